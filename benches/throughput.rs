@@ -0,0 +1,162 @@
+//! Throughput of the bridge at 100% load on a 1 Mbit/s bus, with and without write coalescing.
+//!
+//! A simulated CANET emits standard 8-byte frames back to back for a 1 Mbit/s bus while a GVRET
+//! client reads them, then the client transmits at the same rate. For each direction the frames
+//! arriving per second are printed, and for RX the number of socket reads the client needed, a
+//! stand-in for the TCP segments on the wire.
+//!
+//!     cargo bench --bench throughput
+
+use std::{
+    net::SocketAddr,
+    process::Stdio,
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    process::{Child, Command},
+    time::{interval, sleep, timeout},
+};
+use usr_canet_gvret::{mock_canet::MockCanet, usr_canet::Message};
+
+/// Bits of a standard data frame with 8 bytes, from SOF to the end of interframe space, without
+/// stuff bits
+const FRAME_BITS: u32 = 111;
+const BITRATE: u32 = 1_000_000;
+/// Frames per second at 100% load
+const FRAME_RATE: u32 = BITRATE / FRAME_BITS;
+/// How long each direction is measured
+const RUN: Duration = Duration::from_secs(2);
+/// Extra time for frames still in flight when the load stops
+const DRAIN: Duration = Duration::from_millis(500);
+/// A GVRET frame with 8 data bytes: header, id, bus and length, data, checksum
+const GVRET_FRAME_LEN: usize = 11 + 8 + 1;
+
+struct Setup {
+    name: &'static str,
+    args: &'static [&'static str],
+}
+
+const SETUPS: [Setup; 3] = [
+    Setup {
+        name: "one write per frame",
+        args: &["--batch-window", "0", "--batch-bytes", "1"],
+    },
+    Setup {
+        name: "queued frames only",
+        args: &["--batch-window", "0"],
+    },
+    Setup {
+        name: "1 ms window",
+        args: &["--batch-window", "1000"],
+    },
+];
+
+#[tokio::main]
+async fn main() {
+    println!("{FRAME_RATE} frames/s offered for {RUN:?} in each direction");
+    println!(
+        "{:<22} {:>12} {:>12} {:>12}",
+        "setup", "RX frames/s", "RX reads/s", "TX frames/s"
+    );
+    for setup in &SETUPS {
+        let (canet, _bridge, mut client) = start(setup.args).await;
+        let (rx_frames, rx_reads) = receive(&canet, &mut client).await;
+        let tx_frames = transmit(&canet, &mut client).await;
+        println!(
+            "{:<22} {:>12.0} {:>12.0} {:>12.0}",
+            setup.name,
+            rx_frames as f64 / RUN.as_secs_f64(),
+            rx_reads as f64 / RUN.as_secs_f64(),
+            tx_frames as f64 / RUN.as_secs_f64(),
+        );
+    }
+}
+
+/// Run `action` `FRAME_RATE` times per second for `RUN`, in 1 ms steps
+async fn paced(mut action: impl AsyncFnMut()) {
+    let mut ticks = interval(Duration::from_millis(1));
+    let start = Instant::now();
+    let mut sent = 0_u64;
+    while start.elapsed() < RUN {
+        ticks.tick().await;
+        let due = start.elapsed().as_micros() as u64 * u64::from(FRAME_RATE) / 1_000_000;
+        while sent < due {
+            action().await;
+            sent += 1;
+        }
+    }
+}
+
+/// Emit frames on the CANET and count the frames and reads on the client
+async fn receive(canet: &MockCanet, client: &mut TcpStream) -> (usize, usize) {
+    let frame = Message::new_data(0, 0x123, false, &[0x55; 8]).unwrap();
+    let emit = paced(async || canet.emit(0, &frame));
+    let count = async {
+        let mut buf = vec![0; 64 * 1024];
+        let (mut bytes, mut reads) = (0, 0);
+        while let Ok(Ok(n @ 1..)) = timeout(DRAIN, client.read(&mut buf)).await {
+            bytes += n;
+            reads += 1;
+        }
+        (bytes / GVRET_FRAME_LEN, reads)
+    };
+    let ((), counted) = tokio::join!(emit, count);
+    counted
+}
+
+/// Transmit frames from the client and count the records the CANET gets
+async fn transmit(canet: &MockCanet, client: &mut TcpStream) -> usize {
+    let mut command = vec![0xf1, 0x00];
+    command.extend(0x321_u32.to_le_bytes());
+    command.extend([0, 8]);
+    command.extend([0xaa; 8]);
+    let send = paced(async || client.write_all(&command).await.unwrap());
+    let count = async {
+        let mut frames = 0;
+        while let Ok(Some(_)) = timeout(DRAIN, canet.recv(0)).await {
+            frames += 1;
+        }
+        frames
+    };
+    let ((), frames) = tokio::join!(send, count);
+    frames
+}
+
+/// A one-port CANET, the bridge connected to it with `args` and a GVRET client in binary mode
+async fn start(args: &[&str]) -> (MockCanet, Child, TcpStream) {
+    let canet = MockCanet::start(1).await.unwrap();
+    let listen = free_port().await;
+    let bridge = Command::new(env!("CARGO_BIN_EXE_usr-canet-gvret"))
+        .args(["--listen", &listen.to_string(), "--debug", "error"])
+        .args(args)
+        .arg("127.0.0.1")
+        .arg(canet.addr(0).port().to_string())
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    canet.connected(0).await;
+    let mut client = loop {
+        match TcpStream::connect(listen).await {
+            Ok(stream) => break stream,
+            Err(_) => sleep(Duration::from_millis(20)).await,
+        }
+    };
+    client.write_all(&[0xe7, 0xe7]).await.unwrap();
+    // The keep-alive reply shows the session is registered before frames are emitted
+    client.write_all(&[0xf1, 0x09]).await.unwrap();
+    client.read_exact(&mut [0; 4]).await.unwrap();
+    (canet, bridge, client)
+}
+
+/// A localhost address nobody listens on right now
+async fn free_port() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
@@ -0,0 +1,292 @@
+//! Vector ASCII log files (`.asc`), as written and read by CANalyzer and CANoe.
+//!
+//! A header gives the start date, then each CAN frame is one line with the time since the start,
+//! the channel (bus + 1), the id with an `x` suffix for extended ids, the direction, `d` or `r`
+//! for data and remote frames, the length and the data bytes:
+//!
+//! ```text
+//! date Fri Oct 16 20:14:49.031 2026
+//! base hex  timestamps absolute
+//! internal events logged
+//! // version 9.0.0
+//! Begin Triggerblock Fri Oct 16 20:14:49.031 2026
+//!    0.000000 Start of measurement
+//!    0.001234 1  123             Rx   d 3 01 02 03
+//!    0.002345 2  18FF50E5x       Tx   r 8
+//! End TriggerBlock
+//! ```
+//!
+//! Dates are written in UTC. Lines for other events, such as error frames or CAN FD frames, are
+//! skipped when reading.
+
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, Write},
+    time::Duration,
+};
+
+use crate::{
+    trace::{Direction, MONTHS, Record, TraceWriter, UtcTime, WEEKDAYS},
+    usr_canet::Message,
+};
+
+/// Format `time` since the Unix epoch as an ASC date, `Fri Oct 16 20:14:49.031 2026`
+pub fn format_date(time: Duration) -> String {
+    let date = UtcTime::from_unix(time.as_secs());
+    format!(
+        "{} {} {:02} {:02}:{:02}:{:02}.{:03} {}",
+        WEEKDAYS[date.weekday() as usize],
+        MONTHS[date.month as usize - 1],
+        date.day,
+        date.hour,
+        date.minute,
+        date.second,
+        time.subsec_millis(),
+        date.year
+    )
+}
+
+/// Parse an ASC date in 24 hour or am/pm notation, the weekday is ignored
+pub fn parse_date(date: &str) -> Option<Duration> {
+    let fields: Vec<&str> = date.split_whitespace().collect();
+    let (month, day, time, rest) = match fields.as_slice() {
+        [_, month, day, time, rest @ ..] => (month, day, time, rest),
+        _ => return None,
+    };
+    let (pm, year) = match rest {
+        [year] => (None, year),
+        [ampm, year] => (Some(ampm.eq_ignore_ascii_case("pm")), year),
+        _ => return None,
+    };
+    let month = MONTHS.iter().position(|m| m.eq_ignore_ascii_case(month))? as u32 + 1;
+    let (hms, millis) = time.split_once('.').unwrap_or((time, "0"));
+    let mut hms = hms.split(':').map(|f| f.parse::<u32>().ok());
+    let (mut hour, minute, second) = (hms.next()??, hms.next()??, hms.next()??);
+    match pm {
+        Some(true) if hour < 12 => hour += 12,
+        Some(false) if hour == 12 => hour = 0,
+        _ => {}
+    }
+    let secs = UtcTime {
+        year: year.parse().ok()?,
+        month,
+        day: day.parse().ok()?,
+        hour,
+        minute,
+        second,
+    }
+    .to_unix();
+    let millis: u64 = format!("{millis:0<3}").get(..3)?.parse().ok()?;
+    Some(Duration::from_secs(secs) + Duration::from_millis(millis))
+}
+
+/// Writes an ASC file with timestamps relative to `start`, completed by [`TraceWriter::finish`]
+/// or on drop
+pub struct Writer<W: Write> {
+    out: W,
+    start: Duration,
+    finished: bool,
+}
+
+impl<W: Write> Writer<W> {
+    /// Write the header of a measurement started at `start` since the Unix epoch
+    pub fn new(mut out: W, start: Duration) -> io::Result<Self> {
+        // The header holds milliseconds, frames are timed from there
+        let start = Duration::from_millis(start.as_millis() as u64);
+        let date = format_date(start);
+        write!(
+            out,
+            "date {date}\n\
+             base hex  timestamps absolute\n\
+             internal events logged\n\
+             // version 9.0.0\n\
+             Begin Triggerblock {date}\n\
+             {:>11.6} Start of measurement\n",
+            0.0
+        )?;
+        Ok(Self {
+            out,
+            start,
+            finished: false,
+        })
+    }
+}
+
+impl<W: Write + Send> TraceWriter for Writer<W> {
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        let message = &record.message;
+        let time = record.time.saturating_sub(self.start).as_secs_f64();
+        let id = if message.ext_id() {
+            format!("{:X}x", message.id())
+        } else {
+            format!("{:X}", message.id())
+        };
+        let direction = match record.direction {
+            Some(Direction::Tx) => "Tx",
+            Some(Direction::Rx) | None => "Rx",
+        };
+        let mut line = format!(
+            "{time:>11.6} {:<2} {id:<15} {direction:<4} ",
+            u16::from(message.bus()) + 1
+        );
+        match message.data() {
+            Some(data) => {
+                write!(line, "d {:X}", data.len()).unwrap();
+                data.iter().for_each(|b| write!(line, " {b:02X}").unwrap());
+            }
+            None => write!(line, "r {:X}", message.dlc()).unwrap(),
+        }
+        writeln!(self.out, "{line}")
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if !self.finished {
+            self.finished = true;
+            writeln!(self.out, "End TriggerBlock")?;
+        }
+        self.out.flush()
+    }
+}
+
+impl<W: Write> Drop for Writer<W> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = writeln!(self.out, "End TriggerBlock");
+            let _ = self.out.flush();
+        }
+    }
+}
+
+/// Reads the CAN frames of an ASC file
+pub struct Reader<R> {
+    lines: io::Lines<R>,
+    line: usize,
+    /// Time of the `date` header
+    start: Duration,
+    hex: bool,
+    relative: bool,
+    /// Time of the previous frame, for relative timestamps
+    last: Duration,
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(input: R) -> Self {
+        Self {
+            lines: input.lines(),
+            line: 0,
+            start: Duration::ZERO,
+            hex: true,
+            relative: false,
+            last: Duration::ZERO,
+        }
+    }
+
+    /// Parse one line: `Ok(None)` for headers and events other than CAN frames
+    fn parse(&mut self, line: &str) -> Result<Option<Record>, String> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            ["date", date @ ..] => {
+                self.start = parse_date(&date.join(" "))
+                    .ok_or_else(|| format!("bad date {}", date.join(" ")))?;
+                self.last = self.start;
+                return Ok(None);
+            }
+            ["base", base, "timestamps", timestamps, ..] => {
+                self.hex = *base != "dec";
+                self.relative = *timestamps == "relative";
+                return Ok(None);
+            }
+            _ => {}
+        }
+        // A CAN frame: time, channel, id, direction, d or r, then length and data
+        let (time, channel, id, direction, kind, rest) = match fields.as_slice() {
+            [
+                time,
+                channel,
+                id,
+                direction @ ("Rx" | "Tx"),
+                kind @ ("d" | "r"),
+                rest @ ..,
+            ] => (time, channel, id, direction, kind, rest),
+            _ => return Ok(None),
+        };
+        let (Ok(time), Ok(channel)) = (time.parse::<f64>(), channel.parse::<u8>()) else {
+            return Ok(None);
+        };
+        let offset = Duration::try_from_secs_f64(time).map_err(|_| format!("bad time {time}"))?;
+        let time = if self.relative {
+            self.last + offset
+        } else {
+            self.start + offset
+        };
+        self.last = time;
+
+        let radix = if self.hex { 16 } else { 10 };
+        let (digits, ext_id) = match id.strip_suffix(['x', 'X']) {
+            Some(digits) => (digits, true),
+            None => (*id, false),
+        };
+        let id = u32::from_str_radix(digits, radix).map_err(|_| format!("bad id {id}"))?;
+        let bus = channel.checked_sub(1).ok_or("channel 0")?;
+        let message = if *kind == "r" {
+            // Older versions write no length for remote frames
+            let dlc = rest
+                .first()
+                .and_then(|dlc| u8::from_str_radix(dlc, 16).ok())
+                .unwrap_or(0);
+            Message::new_remote(bus, id, ext_id, dlc)
+        } else {
+            let dlc = rest.first().ok_or("missing length")?;
+            let dlc = u8::from_str_radix(dlc, 16).map_err(|_| format!("bad length {dlc}"))?;
+            let data = rest
+                .iter()
+                .skip(1)
+                .take(dlc as usize)
+                .map(|b| u8::from_str_radix(b, 16).ok())
+                .collect::<Option<Vec<u8>>>()
+                .filter(|data| data.len() == dlc as usize)
+                .ok_or_else(|| format!("bad data {}", rest.join(" ")))?;
+            Message::new_data(bus, id, ext_id, &data)
+        }
+        .map_err(|e| format!("invalid frame: {e:?}"))?;
+        let direction = if *direction == "Tx" {
+            Direction::Tx
+        } else {
+            Direction::Rx
+        };
+        Ok(Some(Record {
+            time,
+            message,
+            direction: Some(direction),
+        }))
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    /// Malformed frames are reported as [`io::ErrorKind::InvalidData`] and can be skipped
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<io::Result<Record>> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e)),
+            };
+            self.line += 1;
+            match self.parse(&line) {
+                Ok(Some(record)) => return Some(Ok(record)),
+                Ok(None) => {}
+                Err(e) => {
+                    return Some(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("line {}: {e}", self.line),
+                    )));
+                }
+            }
+        }
+    }
+}
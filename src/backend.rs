@@ -0,0 +1,221 @@
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use bytes::BytesMut;
+use clap::ValueEnum;
+use log::{trace, warn};
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        TcpStream, UdpSocket, lookup_host,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
+};
+
+use tokio_util::codec::Decoder;
+
+use crate::usr_canet::{CANET_RECORD_LEN, CanetCodec, Message, UsrError, convert_to_canet};
+
+/// Receive buffer for UDP mode, as many whole records as fit in an Ethernet frame
+const MAX_DATAGRAM_LEN: usize = 1500 / CANET_RECORD_LEN * CANET_RECORD_LEN;
+
+/// Receiving half of an open backend connection
+#[async_trait]
+pub(crate) trait BackendReader: Send {
+    /// Wait for the next frame and tag it with `bus`. IO errors mean the connection is gone, other
+    /// errors flag a malformed frame that can be skipped.
+    async fn read_message(&mut self, bus: u8) -> Result<Message, UsrError>;
+}
+
+/// Transmitting half of an open backend connection
+#[async_trait]
+pub(crate) trait BackendWriter: Send {
+    async fn write_message(&mut self, message: &Message) -> io::Result<()>;
+
+    /// Write several frames, in as few writes as the backend allows
+    async fn write_messages(&mut self, messages: &[Message]) -> io::Result<()> {
+        for message in messages {
+            self.write_message(message).await?;
+        }
+        Ok(())
+    }
+}
+
+/// A CAN interface the bridge can open, and open again after the connection failed
+#[async_trait]
+pub(crate) trait Backend: Send + Sync {
+    /// Where the backend connects to, for log messages
+    fn describe(&self) -> String;
+
+    async fn open(&self) -> io::Result<(Box<dyn BackendReader>, Box<dyn BackendWriter>)>;
+}
+
+/// How a CANET port is reached, as set in the device's work mode
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Transport {
+    /// TCP server mode, the bridge connects to the port
+    #[default]
+    Tcp,
+    /// UDP mode, datagrams of one or more 13-byte records in both directions
+    Udp,
+}
+
+/// One CAN port of a USR-CANET in TCP server mode
+pub(crate) struct CanetTcp {
+    addr: String,
+    /// Disable Nagle's algorithm, writes are already coalesced by the port
+    nodelay: bool,
+}
+
+impl CanetTcp {
+    pub(crate) fn new(addr: String, nodelay: bool) -> Self {
+        Self { addr, nodelay }
+    }
+}
+
+#[async_trait]
+impl Backend for CanetTcp {
+    fn describe(&self) -> String {
+        self.addr.clone()
+    }
+
+    async fn open(&self) -> io::Result<(Box<dyn BackendReader>, Box<dyn BackendWriter>)> {
+        let stream = TcpStream::connect(&self.addr).await?;
+        stream.set_nodelay(self.nodelay)?;
+        let (r, w) = stream.into_split();
+        let reader = TcpReader {
+            stream: r,
+            buffer: BytesMut::with_capacity(MAX_DATAGRAM_LEN),
+        };
+        Ok((Box::new(reader), Box::new(w)))
+    }
+}
+
+/// Reads CANET records from a TCP stream through a buffer, so a read cancelled halfway through a
+/// record keeps what it got and the stream stays aligned on record boundaries
+struct TcpReader {
+    stream: OwnedReadHalf,
+    /// Bytes received but not decoded yet
+    buffer: BytesMut,
+}
+
+#[async_trait]
+impl BackendReader for TcpReader {
+    async fn read_message(&mut self, bus: u8) -> Result<Message, UsrError> {
+        loop {
+            if let Some(message) = CanetCodec::new(bus).decode(&mut self.buffer)? {
+                trace!("Recv {message}");
+                return Ok(message);
+            }
+            if self.stream.read_buf(&mut self.buffer).await? == 0 {
+                return Err(UsrError::Io(io::ErrorKind::UnexpectedEof.into()));
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl BackendWriter for OwnedWriteHalf {
+    async fn write_message(&mut self, message: &Message) -> io::Result<()> {
+        self.write_all(&convert_to_canet(message)).await
+    }
+
+    async fn write_messages(&mut self, messages: &[Message]) -> io::Result<()> {
+        let records: Vec<u8> = messages.iter().flat_map(convert_to_canet).collect();
+        self.write_all(&records).await
+    }
+}
+
+/// One CAN port of a USR-CANET in UDP mode. Records are sent to `remote` and received on
+/// `local_port`, which must match the remote port configured on the device.
+pub(crate) struct CanetUdp {
+    remote: String,
+    local_port: u16,
+}
+
+impl CanetUdp {
+    pub(crate) fn new(remote: String, local_port: u16) -> Self {
+        Self { remote, local_port }
+    }
+}
+
+#[async_trait]
+impl Backend for CanetUdp {
+    fn describe(&self) -> String {
+        format!("udp {} from local port {}", self.remote, self.local_port)
+    }
+
+    async fn open(&self) -> io::Result<(Box<dyn BackendReader>, Box<dyn BackendWriter>)> {
+        let remote = lookup_host(&self.remote)
+            .await?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "CANET address not found"))?;
+        let local = match remote {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, self.local_port)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, self.local_port)),
+        };
+        let socket = UdpSocket::bind(local).await?;
+        // Only accept datagrams from the device
+        socket.connect(remote).await?;
+        let socket = Arc::new(socket);
+        Ok((
+            Box::new(UdpReader {
+                socket: socket.clone(),
+                datagram: BytesMut::with_capacity(MAX_DATAGRAM_LEN),
+            }),
+            Box::new(UdpWriter(socket)),
+        ))
+    }
+}
+
+struct UdpReader {
+    socket: Arc<UdpSocket>,
+    /// Records of the last datagram not read yet
+    datagram: BytesMut,
+}
+
+#[async_trait]
+impl BackendReader for UdpReader {
+    async fn read_message(&mut self, bus: u8) -> Result<Message, UsrError> {
+        loop {
+            if let Some(message) = CanetCodec::new(bus).decode(&mut self.datagram)? {
+                return Ok(message);
+            }
+            if !self.datagram.is_empty() {
+                warn!(
+                    "CANET datagram is not a multiple of {CANET_RECORD_LEN} bytes, ignoring {} trailing bytes",
+                    self.datagram.len()
+                );
+                self.datagram.clear();
+            }
+            self.datagram.reserve(MAX_DATAGRAM_LEN);
+            let n = self.socket.recv_buf(&mut self.datagram).await?;
+            trace!("Recv datagram {n} bus={bus}");
+        }
+    }
+}
+
+struct UdpWriter(Arc<UdpSocket>);
+
+#[async_trait]
+impl BackendWriter for UdpWriter {
+    async fn write_message(&mut self, message: &Message) -> io::Result<()> {
+        self.0.send(&convert_to_canet(message)).await?;
+        Ok(())
+    }
+
+    /// Send the records in as few datagrams as possible, each no larger than a received one
+    async fn write_messages(&mut self, messages: &[Message]) -> io::Result<()> {
+        for chunk in messages.chunks(MAX_DATAGRAM_LEN / CANET_RECORD_LEN) {
+            let records: Vec<u8> = chunk.iter().flat_map(convert_to_canet).collect();
+            self.0.send(&records).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,71 @@
+//! Coalescing of outgoing frames into fewer socket writes.
+//!
+//! Writing and flushing every frame on its own sends one TCP segment per 13-byte CANET record or
+//! GVRET frame, which at high bus load costs more in headers and interrupts than in payload and
+//! overwhelms the small MCU of a CANET. Writers instead take everything queued within a short
+//! latency window, up to a size threshold, and send it with a single write.
+
+use std::time::Duration;
+
+use tokio::{
+    sync::mpsc::{self, error::TryRecvError},
+    time::{Instant, timeout_at},
+};
+
+/// Default latency window, short enough to go unnoticed next to CAN and TCP latencies
+pub const DEFAULT_WINDOW: Duration = Duration::from_millis(1);
+/// Default size threshold, the payload of one TCP segment on Ethernet
+pub const DEFAULT_MAX_BYTES: usize = 1460;
+
+/// How long and how much a writer collects before writing
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Batching {
+    /// How long to wait for more items after the first one. Zero only takes what is already
+    /// queued, without delaying anything.
+    pub window: Duration,
+    /// Write as soon as this many bytes are collected
+    pub max_bytes: usize,
+}
+
+impl Default for Batching {
+    fn default() -> Self {
+        Self {
+            window: DEFAULT_WINDOW,
+            max_bytes: DEFAULT_MAX_BYTES,
+        }
+    }
+}
+
+impl Batching {
+    /// Wait for the next item on `queue`, then move it and whatever follows within the window to
+    /// `batch`, until `size` of the items adds up to `max_bytes`.
+    ///
+    /// Returns `false` once `queue` is closed and empty. Cancel safe: items are only taken from
+    /// `queue` when they are added to `batch`.
+    pub async fn recv<T>(
+        &self,
+        queue: &mut mpsc::Receiver<T>,
+        size: impl Fn(&T) -> usize,
+        batch: &mut Vec<T>,
+    ) -> bool {
+        let Some(first) = queue.recv().await else {
+            return false;
+        };
+        let deadline = Instant::now() + self.window;
+        let mut bytes = size(&first);
+        batch.push(first);
+        while bytes < self.max_bytes {
+            let next = match queue.try_recv() {
+                Ok(item) => item,
+                Err(TryRecvError::Disconnected) => break,
+                Err(TryRecvError::Empty) => match timeout_at(deadline, queue.recv()).await {
+                    Ok(Some(item)) => item,
+                    Ok(None) | Err(_) => break,
+                },
+            };
+            bytes += size(&next);
+            batch.push(next);
+        }
+        true
+    }
+}
@@ -0,0 +1,425 @@
+//! Vector Binary Logging Format (`.blf`), as written and read by CANalyzer and CANoe.
+//!
+//! A file is a 144 byte `LOGG` header followed by `LOBJ` objects. Frames are `CAN_MESSAGE`
+//! objects packed into zlib compressed `LOG_CONTAINER` objects, which readers concatenate
+//! before parsing, so an object may span two containers. All integers are little endian.
+//!
+//! The header holds the object count and the start and stop times, so the writer rewrites it
+//! whenever a container is written and the file stays readable if the process dies. Times in the
+//! header are in UTC, object timestamps are nanoseconds since the start.
+
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    time::Duration,
+};
+
+use flate2::{Compression, read::ZlibDecoder, write::ZlibEncoder};
+
+use crate::{
+    trace::{Direction, Record, TraceWriter, UtcTime},
+    usr_canet::Message,
+};
+
+const FILE_SIGNATURE: &[u8; 4] = b"LOGG";
+const OBJECT_SIGNATURE: &[u8; 4] = b"LOBJ";
+pub const FILE_HEADER_LEN: usize = 144;
+/// Signature, header size, header version, object size and object type
+const BASE_HEADER_LEN: usize = 16;
+/// Flags, client index, object version and timestamp following the base header
+const HEADER_V1_LEN: usize = 16;
+/// Compression method and uncompressed size following the base header
+const CONTAINER_HEADER_LEN: usize = 16;
+/// Channel, flags, DLC, id and 8 data bytes
+const CAN_MESSAGE_LEN: usize = 16;
+
+const CAN_MESSAGE: u32 = 1;
+const LOG_CONTAINER: u32 = 10;
+const CAN_MESSAGE2: u32 = 86;
+
+const NO_COMPRESSION: u16 = 0;
+const ZLIB_DEFLATE: u16 = 2;
+
+/// Object timestamps in 10 us units
+const TIME_TEN_MICS: u32 = 1;
+/// Object timestamps in nanoseconds
+const TIME_ONE_NANS: u32 = 2;
+
+const FLAG_TX: u8 = 0x01;
+const FLAG_REMOTE: u8 = 0x80;
+const ID_EXTENDED: u32 = 0x8000_0000;
+
+/// Uncompressed data collected before a container is written
+const MAX_CONTAINER_LEN: usize = 128 * 1024;
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// A `SYSTEMTIME`: year, month, weekday, day, hour, minute, second and milliseconds
+fn system_time(time: Duration) -> [u16; 8] {
+    let date = UtcTime::from_unix(time.as_secs());
+    [
+        date.year as u16,
+        date.month as u16,
+        date.weekday() as u16,
+        date.day as u16,
+        date.hour as u16,
+        date.minute as u16,
+        date.second as u16,
+        time.subsec_millis() as u16,
+    ]
+}
+
+fn from_system_time(fields: [u16; 8]) -> Option<Duration> {
+    let [year, month, _, day, hour, minute, second, millis] = fields.map(u32::from);
+    if !(1..=12).contains(&month) || day == 0 {
+        return None;
+    }
+    let secs = UtcTime {
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    }
+    .to_unix();
+    Some(Duration::from_secs(secs) + Duration::from_millis(millis.into()))
+}
+
+fn base_header(header_len: usize, object_len: usize, object_type: u32) -> [u8; BASE_HEADER_LEN] {
+    let mut header = [0; BASE_HEADER_LEN];
+    header[0..4].copy_from_slice(OBJECT_SIGNATURE);
+    header[4..6].copy_from_slice(&(header_len as u16).to_le_bytes());
+    header[6..8].copy_from_slice(&1_u16.to_le_bytes());
+    header[8..12].copy_from_slice(&(object_len as u32).to_le_bytes());
+    header[12..16].copy_from_slice(&object_type.to_le_bytes());
+    header
+}
+
+/// Writes a BLF file, completed by [`TraceWriter::finish`] or on drop
+pub struct Writer<W: Write + Seek> {
+    out: W,
+    start: Duration,
+    stop: Duration,
+    /// Objects not written to a container yet
+    container: Vec<u8>,
+    objects: u32,
+    /// Size of the file if all containers were uncompressed
+    uncompressed_len: u64,
+    finished: bool,
+}
+
+impl<W: Write + Seek> Writer<W> {
+    /// Start a file for a measurement started at `start` since the Unix epoch
+    pub fn new(out: W, start: Duration) -> io::Result<Self> {
+        // The header holds milliseconds, frames are timed from there
+        let start = Duration::from_millis(start.as_millis() as u64);
+        let mut writer = Self {
+            out,
+            start,
+            stop: start,
+            container: Vec::with_capacity(MAX_CONTAINER_LEN),
+            objects: 0,
+            uncompressed_len: FILE_HEADER_LEN as u64,
+            finished: false,
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let file_len = self.out.seek(SeekFrom::End(0))?.max(FILE_HEADER_LEN as u64);
+        let mut header = Vec::with_capacity(FILE_HEADER_LEN);
+        header.extend(FILE_SIGNATURE);
+        header.extend((FILE_HEADER_LEN as u32).to_le_bytes());
+        // Application id, application version, then the BLF version the layout follows
+        header.extend([0, 0, 0, 0, 2, 6, 8, 1]);
+        header.extend(file_len.to_le_bytes());
+        header.extend(self.uncompressed_len.to_le_bytes());
+        header.extend(self.objects.to_le_bytes());
+        header.extend(0_u32.to_le_bytes());
+        for time in [self.start, self.stop] {
+            system_time(time)
+                .iter()
+                .for_each(|f| header.extend(f.to_le_bytes()));
+        }
+        header.resize(FILE_HEADER_LEN, 0);
+        self.out.seek(SeekFrom::Start(0))?;
+        self.out.write_all(&header)?;
+        self.out.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    /// Compress the collected objects into a container
+    fn write_container(&mut self) -> io::Result<()> {
+        if self.container.is_empty() {
+            return Ok(());
+        }
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(&self.container)?;
+        let data = zlib.finish()?;
+        let object_len = BASE_HEADER_LEN + CONTAINER_HEADER_LEN + data.len();
+        let mut container_header = [0; CONTAINER_HEADER_LEN];
+        container_header[0..2].copy_from_slice(&ZLIB_DEFLATE.to_le_bytes());
+        container_header[8..12].copy_from_slice(&(self.container.len() as u32).to_le_bytes());
+        self.out
+            .write_all(&base_header(BASE_HEADER_LEN, object_len, LOG_CONTAINER))?;
+        self.out.write_all(&container_header)?;
+        self.out.write_all(&data)?;
+        self.out.write_all(&[0; 3][..object_len % 4])?;
+        self.uncompressed_len +=
+            (BASE_HEADER_LEN + CONTAINER_HEADER_LEN + self.container.len()) as u64;
+        self.container.clear();
+        self.write_header()?;
+        self.out.flush()
+    }
+}
+
+impl<W: Write + Seek + Send> TraceWriter for Writer<W> {
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        let message = &record.message;
+        let mut flags = 0;
+        if record.direction == Some(Direction::Tx) {
+            flags |= FLAG_TX;
+        }
+        let mut data = [0; 8];
+        match message.data() {
+            Some(bytes) => data[..bytes.len()].copy_from_slice(bytes),
+            None => flags |= FLAG_REMOTE,
+        }
+        let mut id = message.id();
+        if message.ext_id() {
+            id |= ID_EXTENDED;
+        }
+        self.stop = self.stop.max(record.time);
+        let timestamp = record.time.saturating_sub(self.start).as_nanos() as u64;
+
+        let object_len = BASE_HEADER_LEN + HEADER_V1_LEN + CAN_MESSAGE_LEN;
+        self.container.extend(base_header(
+            BASE_HEADER_LEN + HEADER_V1_LEN,
+            object_len,
+            CAN_MESSAGE,
+        ));
+        self.container.extend(TIME_ONE_NANS.to_le_bytes());
+        // Client index and object version
+        self.container.extend([0; 4]);
+        self.container.extend(timestamp.to_le_bytes());
+        self.container
+            .extend((u16::from(message.bus()) + 1).to_le_bytes());
+        self.container.extend([flags, message.dlc()]);
+        self.container.extend(id.to_le_bytes());
+        self.container.extend(data);
+        self.objects += 1;
+
+        if self.container.len() >= MAX_CONTAINER_LEN {
+            self.write_container()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_container()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.finished = true;
+        self.write_container()?;
+        self.write_header()?;
+        self.out.flush()
+    }
+}
+
+impl<W: Write + Seek> Drop for Writer<W> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.write_container();
+        }
+    }
+}
+
+/// Reads the CAN frames of a BLF file, skipping other objects
+pub struct Reader<R> {
+    input: R,
+    start: Duration,
+    /// Uncompressed object data not parsed yet
+    objects: Vec<u8>,
+    done: bool,
+}
+
+impl<R: Read> Reader<R> {
+    /// Read the file header
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut header = vec![0; BASE_HEADER_LEN];
+        input.read_exact(&mut header)?;
+        if &header[0..4] != FILE_SIGNATURE {
+            return Err(invalid("not a BLF file"));
+        }
+        let header_len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+        // Up to the start time
+        if header_len < 56 {
+            return Err(invalid(format!("BLF header of {header_len} bytes")));
+        }
+        header.resize(header_len, 0);
+        input.read_exact(&mut header[BASE_HEADER_LEN..])?;
+        let start: [u16; 8] =
+            std::array::from_fn(|i| u16::from_le_bytes([header[40 + i * 2], header[41 + i * 2]]));
+        Ok(Self {
+            input,
+            start: from_system_time(start).unwrap_or_default(),
+            objects: vec![],
+            done: false,
+        })
+    }
+
+    /// Start of the measurement from the file header
+    pub fn start(&self) -> Duration {
+        self.start
+    }
+
+    /// Read the next top level object into `objects`, or return `false` at the end of the file
+    fn read_object(&mut self) -> io::Result<bool> {
+        let mut header = [0; BASE_HEADER_LEN];
+        match self.input.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e),
+        }
+        if &header[0..4] != OBJECT_SIGNATURE {
+            return Err(invalid("missing LOBJ signature"));
+        }
+        let object_len = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+        let object_type = u32::from_le_bytes(header[12..16].try_into().unwrap());
+        let mut body = vec![0; object_len.saturating_sub(BASE_HEADER_LEN)];
+        self.input.read_exact(&mut body)?;
+        // Padding is lost at the end of a file cut short
+        let mut padding = [0; 3];
+        let _ = self.input.read_exact(&mut padding[..object_len % 4]);
+        if object_type != LOG_CONTAINER {
+            // Objects outside containers, put back together for the parser
+            self.objects.extend(header);
+            self.objects.extend(body);
+            self.objects.extend(std::iter::repeat_n(0, object_len % 4));
+            return Ok(true);
+        }
+        if body.len() < CONTAINER_HEADER_LEN {
+            return Err(invalid("short container"));
+        }
+        let method = u16::from_le_bytes([body[0], body[1]]);
+        let data = &body[CONTAINER_HEADER_LEN..];
+        match method {
+            NO_COMPRESSION => self.objects.extend(data),
+            ZLIB_DEFLATE => {
+                ZlibDecoder::new(data).read_to_end(&mut self.objects)?;
+            }
+            method => return Err(invalid(format!("unknown compression {method}"))),
+        }
+        Ok(true)
+    }
+
+    /// Give up on a file whose objects cannot be found any more
+    fn corrupt(&mut self, message: impl Into<String>) -> io::Error {
+        self.objects.clear();
+        self.done = true;
+        invalid(message)
+    }
+
+    /// Parse the first complete object in `objects`: `Ok(None)` if more data is needed
+    fn parse_object(&mut self) -> io::Result<Option<Option<Record>>> {
+        if self.objects.len() < BASE_HEADER_LEN {
+            return Ok(None);
+        }
+        if &self.objects[0..4] != OBJECT_SIGNATURE {
+            return Err(self.corrupt("missing LOBJ signature in container"));
+        }
+        let field = |at: usize| u32::from_le_bytes(self.objects[at..at + 4].try_into().unwrap());
+        let header_len = u16::from_le_bytes([self.objects[4], self.objects[5]]) as usize;
+        let object_len = field(8) as usize;
+        let object_type = field(12);
+        if object_len < header_len.max(BASE_HEADER_LEN) {
+            return Err(self.corrupt(format!("object of {object_len} bytes")));
+        }
+        let padded = object_len + object_len % 4;
+        if self.objects.len() < object_len {
+            return Ok(None);
+        }
+
+        let record = match object_type {
+            CAN_MESSAGE | CAN_MESSAGE2 if object_len >= header_len + CAN_MESSAGE_LEN => {
+                // Version 1 and 2 headers both start with the time flags and have the timestamp
+                // at the same offset
+                let timestamp = u64::from_le_bytes(self.objects[24..32].try_into().unwrap());
+                let unit = if field(16) == TIME_TEN_MICS {
+                    10_000
+                } else {
+                    1
+                };
+                let body = &self.objects[header_len..header_len + CAN_MESSAGE_LEN];
+                let channel = u16::from_le_bytes([body[0], body[1]]);
+                let (flags, dlc) = (body[2], body[3].min(8));
+                let id = u32::from_le_bytes(body[4..8].try_into().unwrap());
+                let bus = channel.saturating_sub(1) as u8;
+                let ext_id = id & ID_EXTENDED != 0;
+                let id = id & !ID_EXTENDED;
+                let message = if flags & FLAG_REMOTE != 0 {
+                    Message::new_remote(bus, id, ext_id, dlc)
+                } else {
+                    Message::new_data(bus, id, ext_id, &body[8..8 + dlc as usize])
+                }
+                .map_err(|e| invalid(format!("invalid frame: {e:?}")));
+                let direction = if flags & FLAG_TX != 0 {
+                    Direction::Tx
+                } else {
+                    Direction::Rx
+                };
+                let time = self.start + Duration::from_nanos(timestamp.saturating_mul(unit));
+                Some(message.map(|message| Record {
+                    time,
+                    message,
+                    direction: Some(direction),
+                }))
+            }
+            _ => None,
+        };
+        self.objects.drain(..padded.min(self.objects.len()));
+        match record {
+            Some(Ok(record)) => Ok(Some(Some(record))),
+            Some(Err(e)) => Err(e),
+            None => Ok(Some(None)),
+        }
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    /// Malformed frames are reported as [`io::ErrorKind::InvalidData`] and can be skipped, other
+    /// errors end the file
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<io::Result<Record>> {
+        loop {
+            match self.parse_object() {
+                Ok(Some(Some(record))) => return Some(Ok(record)),
+                Ok(Some(None)) => continue,
+                Ok(None) => {}
+                Err(e) => return Some(Err(e)),
+            }
+            if self.done {
+                return None;
+            }
+            match self.read_object() {
+                Ok(true) => {}
+                Ok(false) => {
+                    self.done = true;
+                    if !self.objects.is_empty() {
+                        self.objects.clear();
+                        return Some(Err(invalid("file ends within an object")));
+                    }
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,255 @@
+//! The log file format of Linux can-utils, as written by `candump -l` and played back by
+//! `canplayer`.
+//!
+//! Each frame is one line with the wall-clock time, the interface and the frame in `cansend`
+//! notation, optionally followed by `R` or `T` for received and transmitted frames as `candump -l
+//! -x` marks them:
+//!
+//! ```text
+//! (1760644036.249713) can0 123#DEADBEEF R
+//! (1760644036.250102) can1 18FF50E5#R T
+//! ```
+//!
+//! Buses are numbered after the interface names as [`interface_bus`] reads them, so a log keeps
+//! its buses whichever appears first.
+
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, Write},
+    time::Duration,
+};
+
+use crate::{
+    trace::{Direction, Record, TraceWriter},
+    usr_canet::Message,
+};
+
+/// Format `message` as a log line without the trailing newline. `time` is the time since the Unix
+/// epoch.
+pub fn format_line(
+    time: Duration,
+    interface: &str,
+    message: &Message,
+    direction: Option<Direction>,
+) -> String {
+    let mut line = format!(
+        "({}.{:06}) {interface} ",
+        time.as_secs(),
+        time.subsec_micros()
+    );
+    if message.ext_id() {
+        write!(line, "{:08X}#", message.id()).unwrap();
+    } else {
+        write!(line, "{:03X}#", message.id()).unwrap();
+    }
+    match message.data() {
+        Some(data) => data.iter().for_each(|b| write!(line, "{b:02X}").unwrap()),
+        None => {
+            line.push('R');
+            if message.dlc() > 0 {
+                write!(line, "{}", message.dlc()).unwrap();
+            }
+        }
+    }
+    match direction {
+        Some(Direction::Rx) => line.push_str(" R"),
+        Some(Direction::Tx) => line.push_str(" T"),
+        None => {}
+    }
+    line
+}
+
+/// Parse a log line. `bus` gives the bus number of an interface name.
+pub fn parse_line(line: &str, bus: impl FnOnce(&str) -> u8) -> Result<Record, String> {
+    let mut fields = line.split_whitespace();
+    let (time, interface, frame) = match (fields.next(), fields.next(), fields.next()) {
+        (Some(time), Some(interface), Some(frame)) => (time, interface, frame),
+        _ => return Err("expected (time) interface frame".into()),
+    };
+    let direction = match fields.next() {
+        Some("R") => Some(Direction::Rx),
+        Some("T") => Some(Direction::Tx),
+        _ => None,
+    };
+
+    let time = time
+        .strip_prefix('(')
+        .and_then(|t| t.strip_suffix(')'))
+        .and_then(|t| t.split_once('.'))
+        .and_then(|(secs, frac)| {
+            let micros = format!("{frac:0<6}");
+            Some(
+                Duration::from_secs(secs.parse().ok()?)
+                    + Duration::from_micros(micros.get(..6)?.parse().ok()?),
+            )
+        })
+        .ok_or_else(|| format!("bad time {time}"))?;
+
+    let (id, payload) = frame
+        .split_once('#')
+        .ok_or_else(|| format!("bad frame {frame}"))?;
+    if payload.starts_with('#') {
+        return Err("CAN FD frames are not supported".into());
+    }
+    let ext_id = id.len() == 8;
+    let id = u32::from_str_radix(id, 16).map_err(|_| format!("bad id {id}"))?;
+    let bus = bus(interface);
+    let message = match payload.strip_prefix('R') {
+        Some(dlc) => {
+            let dlc = if dlc.is_empty() {
+                0
+            } else {
+                dlc.parse().map_err(|_| format!("bad length {dlc}"))?
+            };
+            Message::new_remote(bus, id, ext_id, dlc)
+        }
+        None => {
+            let data = (0..payload.len())
+                .step_by(2)
+                .map(|i| {
+                    payload
+                        .get(i..i + 2)
+                        .and_then(|b| u8::from_str_radix(b, 16).ok())
+                })
+                .collect::<Option<Vec<u8>>>()
+                .ok_or_else(|| format!("bad data {payload}"))?;
+            Message::new_data(bus, id, ext_id, &data)
+        }
+    }
+    .map_err(|e| format!("invalid frame {frame}: {e:?}"))?;
+
+    Ok(Record {
+        time,
+        message,
+        direction,
+    })
+}
+
+/// Bus an interface name stands for: `N` for `canN`, `vcanN` and other names Linux numbers from
+/// 0, `n - 1` for the `CANn` the bridge names its buses by default. `None` for unnumbered names.
+pub fn interface_bus(name: &str) -> Option<u8> {
+    let prefix = name.trim_end_matches(|c: char| c.is_ascii_digit());
+    let n: u8 = name[prefix.len()..].parse().ok()?;
+    match prefix {
+        "" => None,
+        "CAN" => n.checked_sub(1),
+        _ => Some(n),
+    }
+}
+
+/// Writes `candump -l` lines, naming each bus after its entry in `interfaces` or `canN` beyond
+pub struct Writer<W> {
+    out: W,
+    interfaces: Vec<String>,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(out: W, interfaces: Vec<String>) -> Self {
+        Self { out, interfaces }
+    }
+
+    /// Name the buses beyond the known ones, as a [`Reader`] finds more interfaces
+    pub fn extend_interfaces(&mut self, interfaces: &[String]) {
+        if let Some(new) = interfaces.get(self.interfaces.len()..) {
+            self.interfaces.extend_from_slice(new);
+        }
+    }
+}
+
+impl<W: Write + Send> TraceWriter for Writer<W> {
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        let bus = record.message.bus() as usize;
+        let line = match self.interfaces.get(bus) {
+            Some(interface) => {
+                format_line(record.time, interface, &record.message, record.direction)
+            }
+            None => format_line(
+                record.time,
+                &format!("can{bus}"),
+                &record.message,
+                record.direction,
+            ),
+        };
+        writeln!(self.out, "{line}")
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Reads `candump -l` lines, numbering the buses after their interface names as
+/// [`interface_bus`] does. Unnumbered names get the lowest bus no interface seen before has.
+pub struct Reader<R> {
+    lines: io::Lines<R>,
+    line: usize,
+    /// Names of the buses up to the highest seen, `canN` for those not seen
+    interfaces: Vec<String>,
+    /// Interfaces seen so far and their buses
+    buses: Vec<(String, u8)>,
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(input: R) -> Self {
+        Self {
+            lines: input.lines(),
+            line: 0,
+            interfaces: vec![],
+            buses: vec![],
+        }
+    }
+
+    /// Interface names seen so far, indexed by the bus they were given
+    pub fn interfaces(&self) -> &[String] {
+        &self.interfaces
+    }
+
+    /// Bus of the interface `name`, numbering it if it is new
+    fn bus(&mut self, name: &str) -> u8 {
+        if let Some((_, bus)) = self.buses.iter().find(|(n, _)| n == name) {
+            return *bus;
+        }
+        let taken = |bus: &u8| self.buses.iter().any(|(_, b)| b == bus);
+        let bus = interface_bus(name)
+            .or_else(|| (0..=u8::MAX).find(|bus| !taken(bus)))
+            .unwrap_or(u8::MAX);
+        if !taken(&bus) {
+            let index = bus as usize;
+            while self.interfaces.len() <= index {
+                self.interfaces
+                    .push(format!("can{}", self.interfaces.len()));
+            }
+            self.interfaces[index] = name.to_string();
+        }
+        self.buses.push((name.to_string(), bus));
+        bus
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    /// Malformed lines are reported as [`io::ErrorKind::InvalidData`] and can be skipped
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<io::Result<Record>> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e)),
+            };
+            self.line += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            return Some(parse_line(&line, |name| self.bus(name)).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {e}", self.line),
+                )
+            }));
+        }
+    }
+}
@@ -0,0 +1,301 @@
+//! Capture of all traffic crossing the bridge to `candump -l`, Vector ASC or BLF, SavvyCAN CSV log
+//! files or pcapng captures.
+//!
+//! Frames are handed to a writer thread through a bounded queue, so slow storage never holds back
+//! the bridge: when the queue is full frames are dropped from the capture and counted.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError, TrySendError},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use flate2::{Compression, write::GzEncoder};
+use log::{error, info, warn};
+
+use crate::{
+    asc, blf, candump,
+    clock::Clock,
+    config::CaptureConfig,
+    gvret_csv, pcapng,
+    port::Received,
+    trace::{TraceFormat, TraceWriter, UtcTime},
+};
+
+/// Frames buffered for the writer thread before frames are dropped from the capture
+const CAPTURE_QUEUE_DEPTH: usize = 4096;
+/// Longest time frames stay buffered in the writer before they reach the file
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Handle to the capture writer thread
+pub(crate) struct Capture {
+    tx: mpsc::SyncSender<Received>,
+    writer: thread::JoinHandle<()>,
+    dropped: AtomicU64,
+}
+
+impl Capture {
+    /// Open the first capture file and start the writer. `interfaces` names the buses in candump
+    /// and pcapng files, indexed by bus number.
+    pub(crate) fn start(
+        config: &CaptureConfig,
+        interfaces: Vec<String>,
+        clock: Clock,
+    ) -> io::Result<Self> {
+        let mut writer = Writer {
+            path: config.file.clone(),
+            format: config.format(),
+            rotate_bytes: config.rotate_bytes,
+            rotate_after: config.rotate_secs.map(Duration::from_secs),
+            gzip: config.gzip,
+            interfaces: interfaces
+                .into_iter()
+                .map(|name| name.split_whitespace().collect::<Vec<_>>().join("_"))
+                .collect(),
+            clock,
+            out: None,
+            written: Arc::new(AtomicU64::new(0)),
+            opened: Instant::now(),
+        };
+        writer.open()?;
+        let (tx, rx) = mpsc::sync_channel(CAPTURE_QUEUE_DEPTH);
+        let writer = thread::Builder::new()
+            .name("capture".into())
+            .spawn(move || writer.run(rx))?;
+        Ok(Self {
+            tx,
+            writer,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Queue a frame for the capture file
+    pub(crate) fn record(&self, frame: &Received) {
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(frame.clone()) {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped.is_power_of_two() {
+                warn!("Capture queue full, {dropped} frames missing from the capture so far");
+            }
+        }
+    }
+
+    /// Write the queued frames and complete the file, blocking until the writer is done
+    pub(crate) fn finish(self) {
+        drop(self.tx);
+        if self.writer.join().is_err() {
+            error!("Capture writer panicked");
+        }
+    }
+}
+
+struct Writer {
+    path: PathBuf,
+    format: TraceFormat,
+    rotate_bytes: Option<u64>,
+    rotate_after: Option<Duration>,
+    gzip: bool,
+    interfaces: Vec<String>,
+    clock: Clock,
+    out: Option<Box<dyn TraceWriter>>,
+    /// Bytes of frames written to the current file before gzip compression, shared with the
+    /// [`Counting`] sink of the file
+    written: Arc<AtomicU64>,
+    opened: Instant,
+}
+
+impl Writer {
+    fn rotates(&self) -> bool {
+        self.rotate_bytes.is_some() || self.rotate_after.is_some()
+    }
+
+    /// Finish the current file and start the next one. Without rotation a candump or pcapng file
+    /// is appended to. Rotated files, and ASC, BLF and CSV files which hold a single measurement each,
+    /// get the time they were started in their name.
+    fn open(&mut self) -> io::Result<()> {
+        if let Some(mut out) = self.out.take() {
+            out.finish()?;
+        }
+        let (file, path) = if self.rotates() || !self.format.appendable() {
+            create_stamped(&self.path)?
+        } else {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            (file, self.path.clone())
+        };
+        info!("Capturing to {}", path.display());
+        let file = BufWriter::new(file);
+        let start = self.clock.unix_time(Instant::now());
+        let out: Box<dyn TraceWriter> = match self.format {
+            TraceFormat::Blf => Box::new(blf::Writer::new(self.counted(file), start)?),
+            format => {
+                let text: Box<dyn Write + Send> = if self.gzip {
+                    Box::new(self.counted(GzEncoder::new(file, Compression::default())))
+                } else {
+                    Box::new(self.counted(file))
+                };
+                match format {
+                    TraceFormat::Asc => Box::new(asc::Writer::new(text, start)?),
+                    TraceFormat::GvretCsv => Box::new(gvret_csv::Writer::new(text)?),
+                    TraceFormat::Pcapng => {
+                        Box::new(pcapng::Writer::new(text, self.interfaces.clone())?)
+                    }
+                    _ => Box::new(candump::Writer::new(text, self.interfaces.clone())),
+                }
+            }
+        };
+        self.out = Some(out);
+        // Headers don't count, so no file is rotated before it holds a frame
+        self.written.store(0, Ordering::Relaxed);
+        self.opened = Instant::now();
+        Ok(())
+    }
+
+    /// `out` counting into [`Writer::written`]
+    fn counted<W>(&self, out: W) -> Counting<W> {
+        Counting {
+            out,
+            written: self.written.clone(),
+            pos: 0,
+            len: 0,
+        }
+    }
+
+    fn due(&self) -> bool {
+        self.rotate_bytes
+            .is_some_and(|max| self.written.load(Ordering::Relaxed) >= max)
+            || self
+                .rotate_after
+                .is_some_and(|age| self.opened.elapsed() >= age)
+    }
+
+    fn write(&mut self, frame: &Received) -> io::Result<()> {
+        if self.due() {
+            self.open()?;
+        }
+        match &mut self.out {
+            Some(out) => out.write(&frame.record()),
+            None => Ok(()),
+        }
+    }
+
+    /// Write frames until the bridge shuts down. Frames are flushed at least every
+    /// [`FLUSH_INTERVAL`], so the file is complete up to then even if the process is killed,
+    /// without giving BLF a container per frame.
+    fn run(mut self, rx: mpsc::Receiver<Received>) {
+        let mut flushed = Instant::now();
+        let mut pending = false;
+        loop {
+            let result = match rx.recv_timeout(FLUSH_INTERVAL) {
+                Ok(frame) => {
+                    pending = true;
+                    self.write(&frame)
+                }
+                Err(RecvTimeoutError::Timeout) => Ok(()),
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            let result = result.and_then(|()| match &mut self.out {
+                Some(out) if pending && flushed.elapsed() >= FLUSH_INTERVAL => {
+                    pending = false;
+                    flushed = Instant::now();
+                    out.flush()
+                }
+                _ => Ok(()),
+            });
+            if let Err(e) = result {
+                error!("Capture to {} stopped: {e}", self.path.display());
+                return;
+            }
+        }
+        if let Some(Err(e)) = self.out.as_mut().map(|out| out.finish()) {
+            error!("Capture to {} not completed: {e}", self.path.display());
+        }
+    }
+}
+
+/// Sink of a capture file, counting the bytes by which writes through it grow the file. BLF
+/// writers compress frames themselves and write them in containers, so their files are counted
+/// as written, while the header they rewrite in place after each container is not.
+struct Counting<W> {
+    out: W,
+    written: Arc<AtomicU64>,
+    /// Offset of the next write
+    pos: u64,
+    /// Length of the file so far
+    len: u64,
+}
+
+impl<W: Write> Write for Counting<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.out.write(buf)?;
+        self.pos += n as u64;
+        if self.pos > self.len {
+            self.written
+                .fetch_add(self.pos - self.len, Ordering::Relaxed);
+            self.len = self.pos;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl<W: Seek> Seek for Counting<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.out.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+/// Create `dir/name-YYYY-MM-DD_HHMMSS.ext` for `dir/name.ext`, like the files of `candump -l`,
+/// with a counter added if a file was already started in the same second
+fn create_stamped(path: &Path) -> io::Result<(File, PathBuf)> {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "bad capture file name"))?;
+    let (stem, ext) = name.split_once('.').unwrap_or((name, ""));
+    let ext = if ext.is_empty() {
+        String::new()
+    } else {
+        format!(".{ext}")
+    };
+    let now = UtcTime::from_unix(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    );
+    let stamp = format!(
+        "{:04}-{:02}-{:02}_{:02}{:02}{:02}",
+        now.year, now.month, now.day, now.hour, now.minute, now.second
+    );
+    let mut n = 0;
+    loop {
+        let suffix = if n == 0 {
+            String::new()
+        } else {
+            format!("-{n}")
+        };
+        let stamped = path.with_file_name(format!("{stem}-{stamp}{suffix}{ext}"));
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&stamped)
+        {
+            Ok(file) => return Ok((file, stamped)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => n += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! Time base of GVRET timestamps.
+//!
+//! GVRET stamps frames and `TimeSync` replies with 32 bit microseconds, which wrap after about
+//! 71.6 minutes (2^32 us). The counter simply rolls over to 0 and keeps counting, as on GVRET
+//! hardware, so clients computing differences with wrapping arithmetic stay correct across the
+//! wrap.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+/// What the 32 bit timestamps count from
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestampMode {
+    /// Microseconds since the bridge started
+    #[default]
+    Monotonic,
+    /// The low 32 bits of microseconds since the Unix epoch, so a frame can be matched with a
+    /// wall-clock log entry by taking its time modulo 2^32 us. Progresses monotonically from the
+    /// wall-clock time at startup, later clock adjustments are not followed.
+    Wallclock,
+}
+
+/// Converts instants into GVRET timestamps relative to one epoch, shared by frames and
+/// `TimeSync` so both are on the same time line
+#[derive(Clone, Copy, Debug)]
+pub struct Clock {
+    epoch: Instant,
+    /// Wall-clock time at `epoch`
+    unix_epoch: Duration,
+    /// Microseconds added to the time since `epoch`
+    offset: u64,
+}
+
+impl Clock {
+    pub fn new(mode: TimestampMode) -> Self {
+        let epoch = Instant::now();
+        let unix_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let offset = match mode {
+            TimestampMode::Monotonic => 0,
+            TimestampMode::Wallclock => unix_epoch.as_micros() as u64,
+        };
+        Self {
+            epoch,
+            unix_epoch,
+            offset,
+        }
+    }
+
+    /// Timestamp of `at`, wrapping every 2^32 us. Instants before the epoch map to the epoch.
+    pub fn timestamp(&self, at: Instant) -> u32 {
+        let micros = at.saturating_duration_since(self.epoch).as_micros() as u64;
+        self.offset.wrapping_add(micros) as u32
+    }
+
+    /// Timestamp of the current time
+    pub fn now(&self) -> u32 {
+        self.timestamp(Instant::now())
+    }
+
+    /// Wall-clock time of `at` since the Unix epoch, whatever the mode, for capture files. Like
+    /// [`TimestampMode::Wallclock`] it progresses from the wall-clock time at startup.
+    pub fn unix_time(&self, at: Instant) -> Duration {
+        self.unix_epoch + at.saturating_duration_since(self.epoch)
+    }
+}
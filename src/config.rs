@@ -0,0 +1,510 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{Context, bail};
+use clap::{ArgMatches, parser::ValueSource};
+use log::LevelFilter;
+use serde::Deserialize;
+
+use crate::{
+    backend::Transport,
+    batch::Batching,
+    clock::TimestampMode,
+    port::TxPolicy,
+    server::{ClientOverflow, TxEcho},
+    trace::TraceFormat,
+    usr_canet::CAN_EXT_ID_MASK,
+};
+
+/// Number of CAN ports on a USR-CANET200
+const MAX_CANET_PORTS: usize = 2;
+/// GVRET carries the bus of a received frame in a nibble
+const MAX_BUSES: usize = 16;
+
+fn default_bitrate() -> u32 {
+    500_000
+}
+
+/// Bridge configuration, read from a TOML file and overridden by command line flags.
+///
+/// See `config.example.toml` for a documented example.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    /// GVRET listen addresses
+    #[serde(default)]
+    pub(crate) listen: Vec<String>,
+    #[serde(default)]
+    pub(crate) tx_policy: Option<TxPolicy>,
+    /// What GVRET timestamps count from
+    #[serde(default)]
+    pub(crate) timestamps: Option<TimestampMode>,
+    /// Which GVRET clients see frames transmitted on a bus
+    #[serde(default)]
+    pub(crate) tx_echo: Option<TxEcho>,
+    /// What to do when a GVRET client's queue is full
+    #[serde(default)]
+    pub(crate) client_overflow: Option<ClientOverflow>,
+    /// Disconnect GVRET clients after this many malformed commands, never if unset
+    #[serde(default)]
+    pub(crate) max_client_errors: Option<u64>,
+    #[serde(default)]
+    pub(crate) canet: Vec<CanetConfig>,
+    /// SocketCAN interfaces, numbered as GVRET buses after all CANET ports
+    #[serde(default)]
+    pub(crate) socketcan: Vec<SocketCanConfig>,
+    /// SocketCAN interfaces mirroring a bus
+    #[serde(default)]
+    pub(crate) vcan: Vec<VcanConfig>,
+    /// Acceptance filters for frames forwarded to GVRET clients, everything passes if empty
+    #[serde(default)]
+    pub(crate) filter: Vec<FilterConfig>,
+    #[serde(default)]
+    pub(crate) batching: BatchingConfig,
+    /// Capture of all bridged traffic, off if unset
+    #[serde(default)]
+    pub(crate) capture: Option<CaptureConfig>,
+    #[serde(default)]
+    pub(crate) pcap_stream: PcapStreamConfig,
+    #[serde(default)]
+    pub(crate) logging: LoggingConfig,
+}
+
+/// A USR-CANET device and its CAN ports
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CanetConfig {
+    pub(crate) host: String,
+    /// TCP or UDP port of CAN1 and optionally CAN2
+    pub(crate) ports: Vec<u16>,
+    /// Bitrate the device is configured for, reported to GVRET clients
+    #[serde(default = "default_bitrate")]
+    pub(crate) bitrate: u32,
+    /// Work mode of the ports, TCP server or UDP
+    #[serde(default)]
+    pub(crate) transport: Transport,
+    /// UDP only: local ports the device sends to, one per port, defaults to `ports`
+    #[serde(default)]
+    pub(crate) local_ports: Vec<u16>,
+    /// Names for the buses behind each port, `CANn` for GVRET bus n - 1 if not given. Ports of all
+    /// devices are numbered as GVRET buses 0..N in the order they are listed.
+    #[serde(default)]
+    pub(crate) buses: Vec<String>,
+}
+
+impl CanetConfig {
+    /// A TCP device with default settings
+    pub(crate) fn new(host: String, ports: Vec<u16>) -> Self {
+        Self {
+            host,
+            ports,
+            bitrate: default_bitrate(),
+            transport: Transport::default(),
+            local_ports: vec![],
+            buses: vec![],
+        }
+    }
+
+    pub(crate) fn local_port(&self, port: usize) -> u16 {
+        self.local_ports
+            .get(port)
+            .copied()
+            .unwrap_or(self.ports[port])
+    }
+}
+
+/// A Linux SocketCAN interface such as `can0` or `vcan0`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SocketCanConfig {
+    pub(crate) interface: String,
+    /// Bitrate the interface is configured for, reported to GVRET clients
+    #[serde(default = "default_bitrate")]
+    pub(crate) bitrate: u32,
+}
+
+/// Mirror a bus onto a SocketCAN interface: frames received on the bus are written to the
+/// interface and frames sent on the interface are transmitted on the bus
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct VcanConfig {
+    pub(crate) bus: u8,
+    pub(crate) interface: String,
+}
+
+/// Parse a `BUS=INTERFACE` mapping given on the command line
+pub(crate) fn parse_vcan(arg: &str) -> Result<VcanConfig, String> {
+    let (bus, interface) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected BUS=INTERFACE, e.g. 0=vcan0, got {arg:?}"))?;
+    Ok(VcanConfig {
+        bus: bus.parse().map_err(|e| format!("bad bus {bus:?}: {e}"))?,
+        interface: interface.to_string(),
+    })
+}
+
+/// Accept frames whose id matches `id` in all bits set in `mask`, optionally only on one bus
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct FilterConfig {
+    #[serde(default)]
+    pub(crate) bus: Option<u8>,
+    pub(crate) id: u32,
+    #[serde(default = "default_mask")]
+    pub(crate) mask: u32,
+}
+
+fn default_mask() -> u32 {
+    CAN_EXT_ID_MASK
+}
+
+impl FilterConfig {
+    pub(crate) fn matches(&self, bus: u8, id: u32) -> bool {
+        self.bus.is_none_or(|b| b == bus) && (id & self.mask) == (self.id & self.mask)
+    }
+}
+
+/// A log of every frame received or transmitted on a bus
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CaptureConfig {
+    /// File to append to, or the name of files with the start time added
+    pub(crate) file: PathBuf,
+    /// Log format, by default after the extension of `file`, else candump
+    #[serde(default)]
+    pub(crate) format: Option<TraceFormat>,
+    /// Start a new file once this many bytes of frames are written, counted before gzip
+    #[serde(default)]
+    pub(crate) rotate_bytes: Option<u64>,
+    /// Start a new file after this many seconds
+    #[serde(default)]
+    pub(crate) rotate_secs: Option<u64>,
+    /// Compress files with gzip
+    #[serde(default)]
+    pub(crate) gzip: bool,
+}
+
+impl CaptureConfig {
+    pub(crate) fn format(&self) -> TraceFormat {
+        self.format
+            .or_else(|| TraceFormat::from_path(&self.file))
+            .unwrap_or(TraceFormat::Candump)
+    }
+}
+
+/// Live pcapng stream of all bridged traffic for Wireshark, off unless a pipe or port is given
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PcapStreamConfig {
+    /// Named pipe to stream to, created if missing
+    #[serde(default)]
+    pub(crate) pipe: Option<PathBuf>,
+    /// TCP address to stream to every connection on
+    #[serde(default)]
+    pub(crate) listen: Option<String>,
+}
+
+/// Coalescing of frames written to CANET ports and GVRET clients
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct BatchingConfig {
+    /// Microseconds to wait for more frames before writing, 0 writes what is queued right away
+    #[serde(default)]
+    pub(crate) window_us: Option<u64>,
+    /// Write once this many bytes are collected
+    #[serde(default)]
+    pub(crate) max_bytes: Option<usize>,
+    /// Disable Nagle's algorithm on TCP connections
+    #[serde(default)]
+    pub(crate) nodelay: Option<bool>,
+}
+
+impl BatchingConfig {
+    pub(crate) fn batching(&self) -> Batching {
+        let default = Batching::default();
+        Batching {
+            window: self.window_us.map_or(default.window, Duration::from_micros),
+            max_bytes: self.max_bytes.unwrap_or(default.max_bytes),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct LoggingConfig {
+    /// One of off, error, warn, info, debug, trace
+    #[serde(default)]
+    pub(crate) level: Option<String>,
+    /// Append log output to this file instead of stderr
+    #[serde(default)]
+    pub(crate) file: Option<String>,
+}
+
+impl Config {
+    pub(crate) fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Cannot read config file {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    /// Let flags given on the command line take precedence over the config file
+    pub(crate) fn apply_cli(&mut self, matches: &ArgMatches) {
+        let from_cli = |id| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+        if let (Some(ip), Some(&port1)) = (
+            matches.get_one::<String>("ip"),
+            matches.get_one::<u16>("port1"),
+        ) {
+            match self.canet.first_mut() {
+                Some(device) => {
+                    device.host = ip.clone();
+                    match device.ports.first_mut() {
+                        Some(port) => *port = port1,
+                        None => device.ports.push(port1),
+                    }
+                }
+                None => self.canet.push(CanetConfig::new(ip.clone(), vec![port1])),
+            }
+        }
+        if let (Some(port2), Some(device)) =
+            (matches.get_one::<u16>("port2"), self.canet.first_mut())
+        {
+            device.ports.truncate(1);
+            device.ports.push(*port2);
+        }
+        if let Some(interfaces) = matches.get_many::<String>("socketcan") {
+            self.socketcan = interfaces
+                .map(|interface| SocketCanConfig {
+                    interface: interface.clone(),
+                    bitrate: default_bitrate(),
+                })
+                .collect();
+        }
+        if from_cli("transport") {
+            let transport = *matches.get_one::<Transport>("transport").unwrap();
+            self.canet.iter_mut().for_each(|d| d.transport = transport);
+        }
+        if let Some(mirrors) = matches.get_many::<VcanConfig>("vcan") {
+            self.vcan = mirrors.cloned().collect();
+        }
+        if from_cli("bitrate") {
+            let bitrate = *matches.get_one::<u32>("bitrate").unwrap();
+            self.canet.iter_mut().for_each(|d| d.bitrate = bitrate);
+            self.socketcan.iter_mut().for_each(|d| d.bitrate = bitrate);
+        }
+        if from_cli("tx-policy") || self.tx_policy.is_none() {
+            self.tx_policy = matches.get_one::<TxPolicy>("tx-policy").copied();
+        }
+        if from_cli("timestamps") || self.timestamps.is_none() {
+            self.timestamps = matches.get_one::<TimestampMode>("timestamps").copied();
+        }
+        if from_cli("tx-echo") || self.tx_echo.is_none() {
+            self.tx_echo = matches.get_one::<TxEcho>("tx-echo").copied();
+        }
+        if from_cli("client-overflow") || self.client_overflow.is_none() {
+            self.client_overflow = matches
+                .get_one::<ClientOverflow>("client-overflow")
+                .copied();
+        }
+        if from_cli("batch-window") || self.batching.window_us.is_none() {
+            self.batching.window_us = matches.get_one::<u64>("batch-window").copied();
+        }
+        if from_cli("batch-bytes") || self.batching.max_bytes.is_none() {
+            self.batching.max_bytes = matches.get_one::<usize>("batch-bytes").copied();
+        }
+        if from_cli("nodelay") || self.batching.nodelay.is_none() {
+            self.batching.nodelay = matches.get_one::<bool>("nodelay").copied();
+        }
+        if let Some(file) = matches.get_one::<PathBuf>("capture") {
+            match &mut self.capture {
+                Some(capture) => capture.file = file.clone(),
+                None => {
+                    self.capture = Some(CaptureConfig {
+                        file: file.clone(),
+                        format: None,
+                        rotate_bytes: None,
+                        rotate_secs: None,
+                        gzip: false,
+                    })
+                }
+            }
+        }
+        if let (Some(capture), Some(&format)) = (
+            &mut self.capture,
+            matches.get_one::<TraceFormat>("capture-format"),
+        ) {
+            capture.format = Some(format);
+        }
+        if let Some(pipe) = matches.get_one::<PathBuf>("pcap-pipe") {
+            self.pcap_stream.pipe = Some(pipe.clone());
+        }
+        if let Some(addr) = matches.get_one::<String>("pcap-listen") {
+            self.pcap_stream.listen = Some(addr.clone());
+        }
+        if let Some(&max) = matches.get_one::<u64>("max-client-errors") {
+            self.max_client_errors = Some(max);
+        }
+        if from_cli("debug") || self.logging.level.is_none() {
+            self.logging.level = matches
+                .get_one::<LevelFilter>("debug")
+                .map(|l| l.to_string());
+        }
+        if let Some(listen) = matches.get_many::<String>("listen") {
+            self.listen = listen.cloned().collect();
+        } else if from_cli("interface") {
+            self.listen.clear();
+        }
+    }
+
+    /// Check values the TOML schema cannot express, naming the offending key
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        if self.canet.is_empty() && self.socketcan.is_empty() {
+            bail!(
+                "No bus configured, give IP and PORT1, --socketcan or add a [[canet]] or [[socketcan]] table"
+            );
+        }
+        if !cfg!(target_os = "linux") && !self.socketcan.is_empty() {
+            bail!("socketcan: SocketCAN is only available on Linux");
+        }
+        if !cfg!(target_os = "linux") && !self.vcan.is_empty() {
+            bail!("vcan: SocketCAN is only available on Linux");
+        }
+        if !cfg!(unix) && self.pcap_stream.pipe.is_some() {
+            bail!("pcap_stream.pipe: named pipes are only available on Unix");
+        }
+        for (i, interface) in self.socketcan.iter().enumerate() {
+            if interface.interface.is_empty() {
+                bail!("socketcan[{i}].interface: must not be empty");
+            }
+        }
+        for (i, device) in self.canet.iter().enumerate() {
+            if device.host.is_empty() {
+                bail!("canet[{i}].host: must not be empty");
+            }
+            if device.ports.is_empty() || device.ports.len() > MAX_CANET_PORTS {
+                bail!(
+                    "canet[{i}].ports: expected 1 to {MAX_CANET_PORTS} ports, found {}",
+                    device.ports.len()
+                );
+            }
+            if device.buses.len() > device.ports.len() {
+                bail!(
+                    "canet[{i}].buses: {} names given for {} ports",
+                    device.buses.len(),
+                    device.ports.len()
+                );
+            }
+            if device.local_ports.len() > device.ports.len() {
+                bail!(
+                    "canet[{i}].local_ports: {} local ports given for {} ports",
+                    device.local_ports.len(),
+                    device.ports.len()
+                );
+            }
+            if !device.local_ports.is_empty() && device.transport != Transport::Udp {
+                bail!("canet[{i}].local_ports: only used with transport = \"udp\"");
+            }
+            if device.bitrate == 0 || device.bitrate > 1_000_000 {
+                bail!(
+                    "canet[{i}].bitrate: {} is not a classic CAN bitrate",
+                    device.bitrate
+                );
+            }
+        }
+        let buses = self.canet.iter().map(|d| d.ports.len()).sum::<usize>() + self.socketcan.len();
+        if buses > MAX_BUSES {
+            bail!("{buses} buses configured, GVRET supports at most {MAX_BUSES}");
+        }
+        // Captures name buses by these, two of a name would merge on replay
+        let names = self.named_buses();
+        for (bus, (key, name)) in names.iter().enumerate() {
+            if let Some(other) = names[..bus].iter().position(|(_, n)| n == name) {
+                bail!("{key}: bus {bus} is named {name:?} like bus {other}, name them apart");
+            }
+        }
+        for (i, mirror) in self.vcan.iter().enumerate() {
+            if mirror.bus as usize >= buses {
+                bail!(
+                    "vcan[{i}].bus: bus {} does not exist, {buses} buses configured",
+                    mirror.bus
+                );
+            }
+            if mirror.interface.is_empty() {
+                bail!("vcan[{i}].interface: must not be empty");
+            }
+        }
+        for (i, filter) in self.filter.iter().enumerate() {
+            if filter.id > CAN_EXT_ID_MASK {
+                bail!("filter[{i}].id: {:#x} is not a valid CAN id", filter.id);
+            }
+        }
+        if self.max_client_errors == Some(0) {
+            bail!("max_client_errors: must be at least 1");
+        }
+        if let Some(capture) = &self.capture {
+            if capture.rotate_bytes == Some(0) {
+                bail!("capture.rotate_bytes: must be at least 1");
+            }
+            if capture.rotate_secs == Some(0) {
+                bail!("capture.rotate_secs: must be at least 1");
+            }
+            if capture.gzip && capture.format() == TraceFormat::Blf {
+                bail!("capture.gzip: BLF files are already compressed");
+            }
+        }
+        if self.batching.max_bytes == Some(0) {
+            bail!("batching.max_bytes: must be at least 1");
+        }
+        if let Some(level) = &self.logging.level {
+            LevelFilter::from_str(level)
+                .map_err(|_| anyhow::anyhow!("logging.level: unknown level {level:?}"))?;
+        }
+        Ok(())
+    }
+
+    /// Names of the buses indexed by GVRET bus number
+    pub(crate) fn bus_names(&self) -> Vec<String> {
+        self.named_buses()
+            .into_iter()
+            .map(|(_, name)| name)
+            .collect()
+    }
+
+    /// Names of the buses and the key each is configured by, indexed by GVRET bus number
+    fn named_buses(&self) -> Vec<(String, String)> {
+        let mut buses = vec![];
+        for (i, device) in self.canet.iter().enumerate() {
+            for port in 0..device.ports.len() {
+                let name = match device.buses.get(port) {
+                    Some(name) => name.clone(),
+                    None => format!("CAN{}", buses.len() + 1),
+                };
+                buses.push((format!("canet[{i}].buses"), name));
+            }
+        }
+        for (i, interface) in self.socketcan.iter().enumerate() {
+            buses.push((
+                format!("socketcan[{i}].interface"),
+                interface.interface.clone(),
+            ));
+        }
+        buses
+    }
+
+    pub(crate) fn log_level(&self) -> LevelFilter {
+        self.logging
+            .level
+            .as_deref()
+            .and_then(|l| LevelFilter::from_str(l).ok())
+            .unwrap_or(LevelFilter::Info)
+    }
+
+    /// Frames pass if no filter is configured or any filter matches
+    pub(crate) fn accepts(&self, bus: u8, id: u32) -> bool {
+        self.filter.is_empty() || self.filter.iter().any(|f| f.matches(bus, id))
+    }
+}
@@ -0,0 +1,183 @@
+//! The `convert` subcommand, between candump, ASC, BLF, pcapng and GVRET CSV log files.
+
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, bail};
+use clap::ArgMatches;
+use flate2::{Compression, read::MultiGzDecoder, write::GzEncoder};
+use log::{info, warn};
+
+use crate::{
+    asc, blf, candump, gvret_csv, pcapng,
+    trace::{Record, TraceFormat, TraceWriter, is_gzip},
+};
+
+/// The format given for `path`, or the one its extension stands for
+pub(crate) fn format_of(path: &Path, format: Option<TraceFormat>) -> anyhow::Result<TraceFormat> {
+    match format.or_else(|| TraceFormat::from_path(path)) {
+        Some(format) => Ok(format),
+        None => bail!(
+            "{}: unknown log format, name it .log, .asc, .blf, .pcapng or .csv or give the format",
+            path.display()
+        ),
+    }
+}
+
+/// A log file being read, decompressed if its name ends in `.gz`
+pub(crate) enum Input {
+    Candump(candump::Reader<Box<dyn BufRead>>),
+    Asc(asc::Reader<Box<dyn BufRead>>),
+    Blf(blf::Reader<Box<dyn BufRead>>),
+    Pcapng(pcapng::Reader<Box<dyn BufRead>>),
+    GvretCsv(gvret_csv::Reader<Box<dyn BufRead>>),
+}
+
+impl Input {
+    pub(crate) fn open(path: &Path, format: TraceFormat) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let input: Box<dyn BufRead> = if is_gzip(path) {
+            Box::new(BufReader::new(MultiGzDecoder::new(file)))
+        } else {
+            Box::new(BufReader::new(file))
+        };
+        Ok(match format {
+            TraceFormat::Candump => Self::Candump(candump::Reader::new(input)),
+            TraceFormat::Asc => Self::Asc(asc::Reader::new(input)),
+            TraceFormat::Blf => Self::Blf(blf::Reader::new(input)?),
+            TraceFormat::Pcapng => Self::Pcapng(pcapng::Reader::new(input)),
+            TraceFormat::GvretCsv => Self::GvretCsv(gvret_csv::Reader::new(input)),
+        })
+    }
+
+    /// Interface names of a candump or pcapng log seen so far, indexed by bus
+    pub(crate) fn interfaces(&self) -> &[String] {
+        match self {
+            Self::Candump(reader) => reader.interfaces(),
+            Self::Pcapng(reader) => reader.interfaces(),
+            _ => &[],
+        }
+    }
+
+    /// Start of the measurement if the file header tells
+    fn start(&self) -> Option<Duration> {
+        match self {
+            Self::Blf(reader) => Some(reader.start()),
+            _ => None,
+        }
+    }
+}
+
+impl Iterator for Input {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<io::Result<Record>> {
+        match self {
+            Self::Candump(reader) => reader.next(),
+            Self::Asc(reader) => reader.next(),
+            Self::Blf(reader) => reader.next(),
+            Self::Pcapng(reader) => reader.next(),
+            Self::GvretCsv(reader) => reader.next(),
+        }
+    }
+}
+
+/// The converted file, with candump and pcapng kept apart to name their buses after the input's
+/// interfaces
+enum Output {
+    Candump(candump::Writer<Box<dyn Write + Send>>),
+    Pcapng(pcapng::Writer<Box<dyn Write + Send>>),
+    Other(Box<dyn TraceWriter>),
+}
+
+impl Output {
+    fn writer(&mut self) -> &mut dyn TraceWriter {
+        match self {
+            Self::Candump(writer) => writer,
+            Self::Pcapng(writer) => writer,
+            Self::Other(writer) => writer.as_mut(),
+        }
+    }
+}
+
+/// The next frame, warning about and skipping malformed ones
+pub(crate) fn next_record(input: &mut Input, path: &Path) -> io::Result<Option<Record>> {
+    loop {
+        match input.next().transpose() {
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                warn!("{}: skipped {e}", path.display())
+            }
+            result => return result,
+        }
+    }
+}
+
+/// `convert INPUT OUTPUT`
+pub(crate) fn run(matches: &ArgMatches) -> anyhow::Result<()> {
+    let input_path = matches.get_one::<PathBuf>("input").unwrap();
+    let output_path = matches.get_one::<PathBuf>("output").unwrap();
+    let from = format_of(input_path, matches.get_one("from").copied())?;
+    let to = format_of(output_path, matches.get_one("to").copied())?;
+    if to == TraceFormat::Blf && is_gzip(output_path) {
+        bail!(
+            "{}: BLF files are already compressed",
+            output_path.display()
+        );
+    }
+    let reading = || format!("cannot read {}", input_path.display());
+    let writing = || format!("cannot write {}", output_path.display());
+
+    let mut input = Input::open(input_path, from).with_context(reading)?;
+    let Some(first) = next_record(&mut input, input_path).with_context(reading)? else {
+        bail!("{}: no CAN frames", input_path.display());
+    };
+    let start = input.start().unwrap_or(first.time).min(first.time);
+
+    let file = BufWriter::new(File::create(output_path).with_context(writing)?);
+    let text = |file| -> Box<dyn Write + Send> {
+        if is_gzip(output_path) {
+            Box::new(GzEncoder::new(file, Compression::default()))
+        } else {
+            Box::new(file)
+        }
+    };
+    let mut output = match to {
+        TraceFormat::Candump => Output::Candump(candump::Writer::new(text(file), vec![])),
+        TraceFormat::Asc => Output::Other(Box::new(
+            asc::Writer::new(text(file), start).with_context(writing)?,
+        )),
+        TraceFormat::Blf => Output::Other(Box::new(
+            blf::Writer::new(file, start).with_context(writing)?,
+        )),
+        TraceFormat::GvretCsv => Output::Other(Box::new(
+            gvret_csv::Writer::new(text(file)).with_context(writing)?,
+        )),
+        TraceFormat::Pcapng => {
+            Output::Pcapng(pcapng::Writer::new(text(file), vec![]).with_context(writing)?)
+        }
+    };
+
+    let mut frames = 0_u64;
+    let mut record = Some(first);
+    while let Some(frame) = record {
+        match &mut output {
+            Output::Candump(writer) => writer.extend_interfaces(input.interfaces()),
+            Output::Pcapng(writer) => writer.extend_interfaces(input.interfaces()),
+            Output::Other(_) => {}
+        }
+        output.writer().write(&frame).with_context(writing)?;
+        frames += 1;
+        record = next_record(&mut input, input_path).with_context(reading)?;
+    }
+    output.writer().finish().with_context(writing)?;
+    info!(
+        "Converted {frames} frames from {} to {}",
+        input_path.display(),
+        output_path.display()
+    );
+    Ok(())
+}
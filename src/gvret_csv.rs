@@ -0,0 +1,164 @@
+//! The GVRET CSV log format of SavvyCAN, as written by its "GVRET Native CSV" save option.
+//!
+//! A header names the columns, then each frame is one line with the timestamp in microseconds, the
+//! id in hex, whether it is extended, the direction, the bus, the length and the data bytes in hex:
+//!
+//! ```text
+//! Time Stamp,ID,Extended,Dir,Bus,LEN,D1,D2,D3,D4,D5,D6,D7,D8
+//! 1760644036249713,00000123,false,Rx,0,3,01,02,03,
+//! 1760644036250102,18FF50E5,true,Tx,1,8,
+//! ```
+//!
+//! Older files have no `Dir` column. The format has no remote frames, they are written with their
+//! length and no data, and read back as such unless their length is 0.
+
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, Write},
+    time::Duration,
+};
+
+use crate::{
+    trace::{Direction, Record, TraceWriter},
+    usr_canet::Message,
+};
+
+const HEADER: &str = "Time Stamp,ID,Extended,Dir,Bus,LEN,D1,D2,D3,D4,D5,D6,D7,D8";
+
+/// Writes a GVRET CSV file with timestamps in microseconds since the Unix epoch
+pub struct Writer<W> {
+    out: W,
+}
+
+impl<W: Write> Writer<W> {
+    /// Write the header
+    pub fn new(mut out: W) -> io::Result<Self> {
+        writeln!(out, "{HEADER}")?;
+        Ok(Self { out })
+    }
+}
+
+impl<W: Write + Send> TraceWriter for Writer<W> {
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        let message = &record.message;
+        let direction = match record.direction {
+            Some(Direction::Tx) => "Tx",
+            Some(Direction::Rx) | None => "Rx",
+        };
+        let mut line = format!(
+            "{},{:08X},{},{direction},{},{},",
+            record.time.as_micros(),
+            message.id(),
+            message.ext_id(),
+            message.bus(),
+            message.dlc()
+        );
+        if let Some(data) = message.data() {
+            data.iter().for_each(|b| write!(line, "{b:02X},").unwrap());
+        }
+        writeln!(self.out, "{line}")
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Reads the frames of a GVRET CSV file
+pub struct Reader<R> {
+    lines: io::Lines<R>,
+    line: usize,
+    /// Whether lines have a `Dir` column, as told by the header
+    direction: bool,
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(input: R) -> Self {
+        Self {
+            lines: input.lines(),
+            line: 0,
+            direction: true,
+        }
+    }
+
+    /// Parse one line: `Ok(None)` for the header and empty lines
+    fn parse(&mut self, line: &str) -> Result<Option<Record>, String> {
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(None);
+        }
+        if line.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            self.direction = line.split(',').any(|column| column.trim() == "Dir");
+            return Ok(None);
+        }
+        let mut fields = line.split(',').map(str::trim);
+        let mut field = |name: &str| fields.next().ok_or_else(|| format!("missing {name}"));
+        let time = field("time stamp")?;
+        let time = time
+            .parse()
+            .map(Duration::from_micros)
+            .map_err(|_| format!("bad time stamp {time}"))?;
+        let id = field("id")?;
+        let id = u32::from_str_radix(id, 16).map_err(|_| format!("bad id {id}"))?;
+        let ext_id = field("extended")?.eq_ignore_ascii_case("true");
+        let direction = if self.direction {
+            match field("direction")? {
+                "Tx" => Some(Direction::Tx),
+                _ => Some(Direction::Rx),
+            }
+        } else {
+            None
+        };
+        let bus = field("bus")?;
+        let bus = bus.parse().map_err(|_| format!("bad bus {bus}"))?;
+        let len = field("length")?;
+        let len: u8 = len.parse().map_err(|_| format!("bad length {len}"))?;
+        let data = fields
+            .filter(|b| !b.is_empty())
+            .map(|b| u8::from_str_radix(b, 16).map_err(|_| format!("bad data {b}")))
+            .take(len as usize)
+            .collect::<Result<Vec<u8>, String>>()?;
+        let message = if data.is_empty() && len > 0 {
+            Message::new_remote(bus, id, ext_id, len)
+        } else if data.len() == len as usize {
+            Message::new_data(bus, id, ext_id, &data)
+        } else {
+            return Err(format!("{} data bytes for length {len}", data.len()));
+        }
+        .map_err(|e| format!("invalid frame: {e:?}"))?;
+        Ok(Some(Record {
+            time,
+            message,
+            direction,
+        }))
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    /// Malformed lines are reported as [`io::ErrorKind::InvalidData`] and can be skipped
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<io::Result<Record>> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e)),
+            };
+            self.line += 1;
+            match self.parse(&line) {
+                Ok(Some(record)) => return Some(Ok(record)),
+                Ok(None) => {}
+                Err(e) => {
+                    return Some(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("line {}: {e}", self.line),
+                    )));
+                }
+            }
+        }
+    }
+}
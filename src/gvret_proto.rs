@@ -0,0 +1,433 @@
+//! Sans-IO GVRET binary protocol as spoken by SavvyCAN: a [`GvretCodec`] framing the client's
+//! byte stream into typed [`Command`]s, whatever chunks it arrives in, and encoding [`Reply`]s.
+
+use std::{io, mem};
+
+use bytes::{Buf, BufMut, BytesMut};
+use log::trace;
+use thiserror::Error;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::usr_canet::{CAN_MAX_DLC, Message};
+
+/// Sent twice by clients to switch the device into binary mode
+pub const GVRET_BINARY_MODE: u8 = 0xe7;
+/// Starts every command and reply in binary mode
+pub const GVRET_COMMAND: u8 = 0xf1;
+
+/// Set in the id word of a GVRET frame for remote (RTR) frames, next to the extended id flag in
+/// bit 31. Remote frames keep the DLC in the length field and are padded with that many zero
+/// bytes, so clients unaware of the flag stay in sync.
+pub const GVRET_RTR_FLAG: u32 = 1 << 30;
+/// Set in the id word of a GVRET frame for extended ids
+pub const GVRET_EXT_FLAG: u32 = 1 << 31;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GVRETProtocol {
+    BuildCanFrame = 0,
+    TimeSync = 1,
+    DigInputs = 2,
+    AnaInputs = 3,
+    SetDigOut = 4,
+    SetupCanBus = 5,
+    GetCanBusParams = 6,
+    GetDevInfo = 7,
+    SetSwMode = 8,
+    KeepAlive = 9,
+    SetSysType = 10,
+    EchoCanFrame = 11,
+    GetNumBuses = 12,
+    GetExtBuses = 13,
+    SetExtBuses = 14,
+    BuildFdFrame = 20,
+    SetupFd = 21,
+    GetFd = 22,
+}
+
+impl GVRETProtocol {
+    /// Length of the fixed payload following a settings command
+    fn payload_len(&self) -> usize {
+        match self {
+            GVRETProtocol::SetDigOut | GVRETProtocol::SetSwMode | GVRETProtocol::SetSysType => 1,
+            GVRETProtocol::SetupCanBus => 8,
+            GVRETProtocol::SetExtBuses => 12,
+            GVRETProtocol::SetupFd => 16,
+            _ => 0,
+        }
+    }
+}
+
+impl TryFrom<u8> for GVRETProtocol {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => GVRETProtocol::BuildCanFrame,
+            1 => GVRETProtocol::TimeSync,
+            2 => GVRETProtocol::DigInputs,
+            3 => GVRETProtocol::AnaInputs,
+            4 => GVRETProtocol::SetDigOut,
+            5 => GVRETProtocol::SetupCanBus,
+            6 => GVRETProtocol::GetCanBusParams,
+            7 => GVRETProtocol::GetDevInfo,
+            8 => GVRETProtocol::SetSwMode,
+            9 => GVRETProtocol::KeepAlive,
+            10 => GVRETProtocol::SetSysType,
+            11 => GVRETProtocol::EchoCanFrame,
+            12 => GVRETProtocol::GetNumBuses,
+            13 => GVRETProtocol::GetExtBuses,
+            14 => GVRETProtocol::SetExtBuses,
+            20 => GVRETProtocol::BuildFdFrame,
+            21 => GVRETProtocol::SetupFd,
+            22 => GVRETProtocol::GetFd,
+            other => return Err(other),
+        })
+    }
+}
+
+/// Enable flags and bitrate of one bus, as requested by `SetupCanBus` or reported by
+/// `GetCanBusParams`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BusParams {
+    pub enabled: bool,
+    pub listen_only: bool,
+    pub speed: u32,
+}
+
+impl BusParams {
+    /// Decode a `SetupCanBus` word. If bit 31 is set, bit 30 carries the enable and bit 29 the
+    /// listen-only flag, otherwise any nonzero value enables the bus. The low 20 bits are the
+    /// requested bitrate, 0 disables the bus.
+    pub fn from_setup_word(value: u32) -> Self {
+        let speed = (value & 0xfffff).min(1_000_000);
+        let (enabled, listen_only) = match value {
+            0 => (false, false),
+            v if v & (1 << 31) != 0 => (v & (1 << 30) != 0, v & (1 << 29) != 0),
+            _ => (true, false),
+        };
+        Self {
+            enabled,
+            listen_only,
+            speed,
+        }
+    }
+
+    /// Flags byte and little endian bitrate, as in `GetCanBusParams` and `GetExtBuses` replies
+    fn encode(&self, dst: &mut BytesMut) {
+        dst.put_u8(u8::from(self.enabled) | (u8::from(self.listen_only) << 4));
+        dst.put_u32_le(self.speed);
+    }
+}
+
+/// A command sent by a GVRET client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// The client switched to binary mode
+    BinaryMode,
+    /// Transmit a frame
+    BuildCanFrame(Message),
+    TimeSync,
+    DigInputs,
+    AnaInputs,
+    SetDigOut(u8),
+    /// Settings for buses 0 and 1
+    SetupCanBus([BusParams; 2]),
+    GetCanBusParams,
+    GetDevInfo,
+    SetSwMode(u8),
+    KeepAlive,
+    SetSysType(u8),
+    /// Report a frame to the clients as if it had been received, without transmitting it
+    EchoCanFrame(Message),
+    GetNumBuses,
+    GetExtBuses,
+    /// Settings for buses 2 to 4
+    SetExtBuses([BusParams; 3]),
+    /// A CAN-FD frame, only its data length is kept
+    BuildFdFrame {
+        len: u8,
+    },
+    SetupFd([u8; 16]),
+    GetFd,
+}
+
+/// A message sent to a GVRET client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    /// A frame received on a bus, stamped with microseconds since the session epoch
+    Frame {
+        timestamp: u32,
+        message: Message,
+    },
+    TimeSync(u32),
+    /// No digital inputs, all low
+    DigInputs,
+    /// Four analogue inputs, all reading zero
+    AnaInputs,
+    /// Buses 0 and 1
+    CanBusParams([BusParams; 2]),
+    DevInfo,
+    KeepAlive,
+    NumBuses(u8),
+    /// Buses 2 to 4, which GVRET clients know as single wire CAN, LIN1 and LIN2
+    ExtBuses([BusParams; 3]),
+    /// CAN-FD disabled on both buses, no bitrates
+    Fd,
+}
+
+/// Why bytes from a GVRET client were rejected. The decoder carries on with the next command
+/// marker, so none of these end a session by themselves.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum GvretError {
+    #[error("{0} bytes outside a command")]
+    Unsynchronised(usize),
+    #[error("unknown command {0:#04x}")]
+    UnknownCommand(u8),
+    #[error("invalid CAN id {0:#010x}")]
+    InvalidId(u32),
+}
+
+/// Data length of a `BuildCanFrame` or `EchoCanFrame`, clamped to 8 like ESP32RET does
+fn classic_len(len: u8) -> u8 {
+    (len & 0xf).min(CAN_MAX_DLC as u8)
+}
+
+/// Data length of a `BuildFdFrame`, clamped to 64
+fn fd_len(len: u8) -> u8 {
+    (len & 0x7f).min(64)
+}
+
+/// Build a frame from the id word, bus, length and data of a `BuildCanFrame` or `EchoCanFrame`
+fn build_can_frame(body: &[u8]) -> Result<Message, GvretError> {
+    let word = u32::from_le_bytes(body[0..4].try_into().unwrap());
+    let bus = body[4];
+    let dlc = classic_len(body[5]);
+    let ext_id = word & GVRET_EXT_FLAG != 0;
+    let id = word & !(GVRET_EXT_FLAG | GVRET_RTR_FLAG);
+    let message = if word & GVRET_RTR_FLAG != 0 {
+        Message::new_remote(bus, id, ext_id, dlc)
+    } else {
+        Message::new_data(bus, id, ext_id, &body[6..6 + dlc as usize])
+    };
+    // The length is clamped, so only the id can be out of range
+    message.map_err(|_| GvretError::InvalidId(word))
+}
+
+fn setup_words<const N: usize>(payload: &[u8]) -> [BusParams; N] {
+    std::array::from_fn(|i| {
+        BusParams::from_setup_word(u32::from_le_bytes(
+            payload[i * 4..i * 4 + 4].try_into().unwrap(),
+        ))
+    })
+}
+
+/// Frames the byte stream of a GVRET client into [`Command`]s and encodes [`Reply`]s.
+///
+/// A command split over several reads stays in the buffer until it is complete. Malformed
+/// commands and stray bytes are reported as [`GvretError`]s, after which decoding resumes at the
+/// next `0xF1` marker.
+#[derive(Debug, Default)]
+pub struct GvretCodec {
+    binary: bool,
+    /// Stray bytes seen since the last command
+    skipped: usize,
+    /// Skipping the rest of a rejected command, which is not reported again
+    resyncing: bool,
+}
+
+impl GvretCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the client has switched to binary mode
+    pub fn is_binary(&self) -> bool {
+        self.binary
+    }
+
+    /// Length of the command at the start of `src`, or `None` if more bytes are needed to tell.
+    /// Frames are the marker, command, id word, bus, length, data and a checksum byte, which
+    /// SavvyCAN sends as 0 and ESP32RET does not check.
+    fn command_len(cmd: GVRETProtocol, src: &[u8]) -> Option<usize> {
+        let data_len = match cmd {
+            GVRETProtocol::BuildCanFrame | GVRETProtocol::EchoCanFrame => classic_len(*src.get(7)?),
+            GVRETProtocol::BuildFdFrame => fd_len(*src.get(7)?),
+            cmd => return Some(2 + cmd.payload_len()),
+        };
+        Some(9 + data_len as usize)
+    }
+
+    /// Decode the command at the start of `src`, consuming it only if it is valid
+    fn decode_command(src: &mut BytesMut) -> Option<Result<Command, GvretError>> {
+        let code = *src.get(1)?;
+        let cmd = match GVRETProtocol::try_from(code) {
+            Ok(cmd) => cmd,
+            Err(unknown) => return Some(Err(GvretError::UnknownCommand(unknown))),
+        };
+        let len = Self::command_len(cmd, src)?;
+        if src.len() < len {
+            src.reserve(len - src.len());
+            return None;
+        }
+        let body = &src[2..len];
+        let command = match cmd {
+            GVRETProtocol::BuildCanFrame => build_can_frame(body).map(Command::BuildCanFrame),
+            GVRETProtocol::EchoCanFrame => build_can_frame(body).map(Command::EchoCanFrame),
+            GVRETProtocol::BuildFdFrame => Ok(Command::BuildFdFrame {
+                len: fd_len(body[5]),
+            }),
+            GVRETProtocol::TimeSync => Ok(Command::TimeSync),
+            GVRETProtocol::DigInputs => Ok(Command::DigInputs),
+            GVRETProtocol::AnaInputs => Ok(Command::AnaInputs),
+            GVRETProtocol::SetDigOut => Ok(Command::SetDigOut(body[0])),
+            GVRETProtocol::SetupCanBus => Ok(Command::SetupCanBus(setup_words(body))),
+            GVRETProtocol::GetCanBusParams => Ok(Command::GetCanBusParams),
+            GVRETProtocol::GetDevInfo => Ok(Command::GetDevInfo),
+            GVRETProtocol::SetSwMode => Ok(Command::SetSwMode(body[0])),
+            GVRETProtocol::KeepAlive => Ok(Command::KeepAlive),
+            GVRETProtocol::SetSysType => Ok(Command::SetSysType(body[0])),
+            GVRETProtocol::GetNumBuses => Ok(Command::GetNumBuses),
+            GVRETProtocol::GetExtBuses => Ok(Command::GetExtBuses),
+            GVRETProtocol::SetExtBuses => Ok(Command::SetExtBuses(setup_words(body))),
+            GVRETProtocol::SetupFd => Ok(Command::SetupFd(body.try_into().unwrap())),
+            GVRETProtocol::GetFd => Ok(Command::GetFd),
+        };
+        if command.is_ok() {
+            src.advance(len);
+        }
+        Some(command)
+    }
+}
+
+impl Decoder for GvretCodec {
+    type Item = Result<Command, GvretError>;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Self::Item>> {
+        loop {
+            let Some(&first) = src.first() else {
+                return Ok(None);
+            };
+            if first != GVRET_COMMAND {
+                src.advance(1);
+                trace!("GVRET byte skipped {first:02x}");
+                if first == GVRET_BINARY_MODE {
+                    if !self.binary {
+                        self.binary = true;
+                        return Ok(Some(Ok(Command::BinaryMode)));
+                    }
+                } else if self.binary && !self.resyncing {
+                    // Text mode input before the handshake is not an error
+                    self.skipped += 1;
+                }
+                continue;
+            }
+            self.resyncing = false;
+            if self.skipped > 0 {
+                return Ok(Some(Err(GvretError::Unsynchronised(mem::take(
+                    &mut self.skipped,
+                )))));
+            }
+            return Ok(match Self::decode_command(src) {
+                None => None,
+                Some(Err(e)) => {
+                    // Drop the marker only, a real command may start inside the rejected bytes
+                    src.advance(1);
+                    self.resyncing = true;
+                    Some(Err(e))
+                }
+                Some(Ok(command)) => Some(Ok(command)),
+            });
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> io::Result<Option<Self::Item>> {
+        let item = self.decode(src)?;
+        if item.is_none() {
+            // A command cut short by the client closing the connection
+            src.clear();
+        }
+        Ok(item)
+    }
+}
+
+impl Encoder<&Reply> for GvretCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: &Reply, dst: &mut BytesMut) -> io::Result<()> {
+        encode_reply(item, dst);
+        Ok(())
+    }
+}
+
+impl Encoder<Reply> for GvretCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Reply, dst: &mut BytesMut) -> io::Result<()> {
+        self.encode(&item, dst)
+    }
+}
+
+/// Append the wire format of `reply` to `dst`
+pub fn encode_reply(reply: &Reply, dst: &mut BytesMut) {
+    dst.put_u8(GVRET_COMMAND);
+    match reply {
+        Reply::Frame { timestamp, message } => {
+            let mut id = message.id();
+            if message.ext_id() {
+                id |= GVRET_EXT_FLAG;
+            }
+            let data: &[u8] = match message.data() {
+                Some(data) => data,
+                None => {
+                    id |= GVRET_RTR_FLAG;
+                    &[0; 8][..message.dlc() as usize]
+                }
+            };
+            dst.put_u8(GVRETProtocol::BuildCanFrame as u8);
+            dst.put_u32_le(*timestamp);
+            dst.put_u32_le(id);
+            dst.put_u8((message.bus() << 4) | (message.dlc() & 0xf));
+            dst.put_slice(data);
+            dst.put_u8(0);
+        }
+        Reply::TimeSync(timestamp) => {
+            dst.put_u8(GVRETProtocol::TimeSync as u8);
+            dst.put_u32_le(*timestamp);
+        }
+        Reply::DigInputs => {
+            dst.put_u8(GVRETProtocol::DigInputs as u8);
+            dst.put_slice(&[0, 0]);
+        }
+        Reply::AnaInputs => {
+            // Four 16 bit readings and the checksum byte
+            dst.put_u8(GVRETProtocol::AnaInputs as u8);
+            dst.put_slice(&[0; 9]);
+        }
+        Reply::CanBusParams(buses) => {
+            dst.put_u8(GVRETProtocol::GetCanBusParams as u8);
+            buses.iter().for_each(|bus| bus.encode(dst));
+        }
+        Reply::DevInfo => {
+            dst.put_u8(GVRETProtocol::GetDevInfo as u8);
+            dst.put_slice(&[0x6a, 0x02, 0x20, 0, 0, 0]);
+        }
+        Reply::KeepAlive => {
+            dst.put_u8(GVRETProtocol::KeepAlive as u8);
+            dst.put_slice(&[0xde, 0xad]);
+        }
+        Reply::NumBuses(buses) => {
+            dst.put_u8(GVRETProtocol::GetNumBuses as u8);
+            dst.put_u8(*buses);
+        }
+        Reply::ExtBuses(buses) => {
+            dst.put_u8(GVRETProtocol::GetExtBuses as u8);
+            buses.iter().for_each(|bus| bus.encode(dst));
+        }
+        Reply::Fd => {
+            dst.put_u8(GVRETProtocol::GetFd as u8);
+            dst.put_slice(&[0; 18]);
+        }
+    }
+}
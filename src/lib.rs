@@ -0,0 +1,30 @@
+//! CANET and GVRET codecs shared by the `usr-canet-gvret` bridge and other tools talking to
+//! USR-CANET200 devices or GVRET clients.
+//!
+//! [`usr_canet`] holds the CAN [`Message`](usr_canet::Message) type, the 13-byte record encoder
+//! and decoder, a sans-IO [`CanetCodec`](usr_canet::CanetCodec) and
+//! [`decode_canet_frame`](usr_canet::decode_canet_frame) for any `AsyncRead`.
+//!
+//! [`gvret_proto`] holds the sans-IO [`GvretCodec`](gvret_proto::GvretCodec) turning a GVRET
+//! client's bytes into typed commands and encoding replies.
+//!
+//! [`batch`] coalesces outgoing frames into fewer socket writes.
+//!
+//! [`trace`] is the common ground of the log file formats [`candump`], [`asc`], [`blf`],
+//! [`pcapng`] and [`gvret_csv`].
+//!
+//! [`clock`] is the time base of GVRET timestamps, [`router`] maps GVRET bus numbers to ports and
+//! [`mock_canet`] simulates a CANET on localhost for tests and demos.
+
+pub mod asc;
+pub mod batch;
+pub mod blf;
+pub mod candump;
+pub mod clock;
+pub mod gvret_csv;
+pub mod gvret_proto;
+pub mod mock_canet;
+pub mod pcapng;
+pub mod router;
+pub mod trace;
+pub mod usr_canet;
@@ -1,17 +1,49 @@
+use std::{fs::OpenOptions, path::PathBuf, sync::Arc};
+
 use crate::{
-    gvret::{Gvret, Mode, convert_to_gvret, decode_gvret_frames},
-    usr_canet::{CanetMsg, Message, convert_to_canet, decode_canet_frame},
+    backend::{Backend, CanetTcp, CanetUdp, Transport},
+    capture::Capture,
+    clock::{Clock, TimestampMode},
+    config::{CanetConfig, Config, parse_vcan},
+    gvret::convert_to_gvret,
+    pcap_stream::PcapStream,
+    port::{Origin, Port, TxOptions, TxPolicy},
+    replay::{parse_bus_map, parse_id_filter, parse_speed},
+    router::Router,
+    server::{ClientOverflow, Hub, Shared, TxEcho, accept_clients, activated_listeners},
+    trace::TraceFormat,
 };
-use clap::{Arg, Command, ValueEnum};
+#[cfg(target_os = "linux")]
+use crate::{socketcan::SocketCan, vcan::VcanMirror};
+use anyhow::Context;
+use clap::{Arg, ArgAction, Command, ValueEnum};
 use env_logger::Env;
 use log::*;
-use tokio::{
-    io::AsyncWriteExt,
-    net::{TcpListener, TcpStream},
-    time::Instant,
+use tokio::{net::TcpListener, sync::mpsc, time::Duration};
+use usr_canet_gvret::{
+    asc, batch, blf, candump, clock, gvret_csv, gvret_proto, mock_canet::MockCanet, pcapng, router,
+    trace, usr_canet,
 };
+mod backend;
+mod capture;
+mod config;
+mod convert;
 mod gvret;
-mod usr_canet;
+mod pcap_stream;
+mod port;
+mod replay;
+mod server;
+#[cfg(target_os = "linux")]
+mod socketcan;
+#[cfg(target_os = "linux")]
+mod vcan;
+
+/// Number of client transmit requests buffered before GVRET readers are held back
+const TX_QUEUE_DEPTH: usize = 256;
+/// Number of received CANET frames buffered before CANET readers are held back
+const RX_QUEUE_DEPTH: usize = 1024;
+/// Interval of the frames sent by `--simulate`
+const DEMO_PERIOD: Duration = Duration::from_millis(100);
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -23,16 +55,33 @@ async fn main() -> anyhow::Result<()> {
                 .short('i')
                 .long("interface")
                 .value_name("INTERFACE")
-                .help("Sets the bind interface (local or any)")
+                .help("Sets the bind interface (local or any) on port 23 when no --listen is given")
                 .value_parser(clap::value_parser!(Interface))
                 .default_value("local"),
         )
+        .arg(
+            Arg::new("listen")
+                .short('l')
+                .long("listen")
+                .value_name("HOST:PORT")
+                .help("GVRET listen address, e.g. 0.0.0.0:2323 or [::1]:23 (repeatable)")
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("config")
+                .short('c')
+                .long("config")
+                .value_name("FILE")
+                .help("TOML config file, command line flags take precedence")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
         .arg(
             Arg::new("ip")
                 .index(1)
                 .value_name("IP")
                 .help("Sets the CANET IP address")
-                .required(true),
+                .requires("port1")
+                .required_unless_present_any(["config", "socketcan", "simulate"]),
         )
         .arg(
             Arg::new("port1")
@@ -40,7 +89,7 @@ async fn main() -> anyhow::Result<()> {
                 .value_name("PORT1")
                 .help("Sets CAN1 CANET TCP port")
                 .value_parser(clap::value_parser!(u16))
-                .required(true),
+                .required_unless_present_any(["config", "socketcan", "simulate"]),
         )
         .arg(
             Arg::new("port2")
@@ -49,6 +98,139 @@ async fn main() -> anyhow::Result<()> {
                 .help("Sets CAN2 CANET port (optional)")
                 .value_parser(clap::value_parser!(u16)),
         )
+        .arg(
+            Arg::new("transport")
+                .short('t')
+                .long("transport")
+                .value_name("TRANSPORT")
+                .help("CANET work mode, tcp (server) or udp")
+                .value_parser(clap::value_parser!(Transport))
+                .default_value("tcp"),
+        )
+        .arg(
+            Arg::new("simulate")
+                .long("simulate")
+                .value_name("PORTS")
+                .help("Bridge a simulated CANET with 1 or 2 ports sending demo traffic")
+                .value_parser(clap::value_parser!(u8).range(1..=2))
+                .num_args(0..=1)
+                .default_missing_value("2")
+                .conflicts_with("ip"),
+        )
+        .arg(
+            Arg::new("socketcan")
+                .long("socketcan")
+                .value_name("INTERFACE")
+                .help("Bridge a Linux SocketCAN interface such as can0 or vcan0 (repeatable)")
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("vcan")
+                .long("vcan")
+                .value_name("BUS=INTERFACE")
+                .help("Mirror a bus onto a SocketCAN interface, e.g. 0=vcan0 (repeatable)")
+                .value_parser(parse_vcan)
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("bitrate")
+                .short('b')
+                .long("bitrate")
+                .value_name("BITRATE")
+                .help("CAN bitrate configured on the CANET, reported to GVRET clients")
+                .value_parser(clap::value_parser!(u32))
+                .default_value("500000"),
+        )
+        .arg(
+            Arg::new("tx-policy")
+                .long("tx-policy")
+                .value_name("POLICY")
+                .help("What to do with frames sent to a disconnected CANET port")
+                .value_parser(clap::value_parser!(TxPolicy))
+                .default_value("drop"),
+        )
+        .arg(
+            Arg::new("timestamps")
+                .long("timestamps")
+                .value_name("MODE")
+                .help("GVRET timestamps count from bridge start (monotonic) or the Unix epoch (wallclock)")
+                .value_parser(clap::value_parser!(TimestampMode))
+                .default_value("monotonic"),
+        )
+        .arg(
+            Arg::new("tx-echo")
+                .long("tx-echo")
+                .value_name("CLIENTS")
+                .help("Report transmitted frames back to no GVRET client, the sender or all clients")
+                .value_parser(clap::value_parser!(TxEcho))
+                .default_value("none"),
+        )
+        .arg(
+            Arg::new("batch-window")
+                .long("batch-window")
+                .value_name("MICROS")
+                .help("Collect frames for up to MICROS before writing them to a socket, 0 to write right away")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("1000"),
+        )
+        .arg(
+            Arg::new("batch-bytes")
+                .long("batch-bytes")
+                .value_name("BYTES")
+                .help("Write collected frames once they add up to BYTES")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("1460"),
+        )
+        .arg(
+            Arg::new("nodelay")
+                .long("nodelay")
+                .value_name("BOOL")
+                .help("Set TCP_NODELAY on CANET and GVRET connections")
+                .value_parser(clap::value_parser!(bool))
+                .default_value("true"),
+        )
+        .arg(
+            Arg::new("client-overflow")
+                .long("client-overflow")
+                .value_name("POLICY")
+                .help("What to do when a GVRET client cannot keep up: drop frames or disconnect it")
+                .value_parser(clap::value_parser!(ClientOverflow))
+                .default_value("drop"),
+        )
+        .arg(
+            Arg::new("max-client-errors")
+                .long("max-client-errors")
+                .value_name("COUNT")
+                .help("Disconnect GVRET clients after COUNT malformed commands")
+                .value_parser(clap::value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("capture")
+                .long("capture")
+                .value_name("FILE")
+                .help("Log every frame received or transmitted on a bus to FILE, a candump, ASC, BLF, pcapng or GVRET CSV log after its extension")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("capture-format")
+                .long("capture-format")
+                .value_name("FORMAT")
+                .help("Format of the capture file whatever its extension")
+                .value_parser(clap::value_parser!(TraceFormat)),
+        )
+        .arg(
+            Arg::new("pcap-pipe")
+                .long("pcap-pipe")
+                .value_name("PATH")
+                .help("Stream pcapng to a named pipe for wireshark -k -i PATH, created if missing")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("pcap-listen")
+                .long("pcap-listen")
+                .value_name("HOST:PORT")
+                .help("Stream pcapng to every connection, for wireshark -k -i TCP@HOST:PORT"),
+        )
         .arg(
             Arg::new("debug")
                 .short('d')
@@ -58,124 +240,388 @@ async fn main() -> anyhow::Result<()> {
                 .value_parser(clap::value_parser!(LevelFilter))
                 .default_value("info"),
         )
+        .subcommand(
+            Command::new("convert")
+                .about("Convert a log file between candump, ASC, BLF, pcapng and GVRET CSV, gzipped if named .gz")
+                .arg(
+                    Arg::new("input")
+                        .value_name("INPUT")
+                        .help("Log file to read")
+                        .required(true)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("output")
+                        .value_name("OUTPUT")
+                        .help("Log file to write, replaced if it exists")
+                        .required(true)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .value_name("FORMAT")
+                        .help("Format of INPUT, by default after its extension")
+                        .value_parser(clap::value_parser!(TraceFormat)),
+                )
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .value_name("FORMAT")
+                        .help("Format of OUTPUT, by default after its extension")
+                        .value_parser(clap::value_parser!(TraceFormat)),
+                ),
+        )
+        .subcommand(
+            Command::new("replay")
+                .about("Send the frames of a candump, ASC, BLF, pcapng or GVRET CSV log to a CANET with their original timing")
+                .arg(
+                    Arg::new("file")
+                        .value_name("FILE")
+                        .help("Log file to replay, gzipped if named .gz")
+                        .required(true)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("ip")
+                        .value_name("IP")
+                        .help("CANET IP address")
+                        .requires("port1")
+                        .required_unless_present("dry-run"),
+                )
+                .arg(
+                    Arg::new("port1")
+                        .value_name("PORT1")
+                        .help("CANET port of bus 0")
+                        .value_parser(clap::value_parser!(u16)),
+                )
+                .arg(
+                    Arg::new("port2")
+                        .long("port2")
+                        .value_name("PORT2")
+                        .help("CANET port of bus 1, frames of buses without a port are skipped")
+                        .value_parser(clap::value_parser!(u16))
+                        .requires("ip"),
+                )
+                .arg(
+                    Arg::new("transport")
+                        .short('t')
+                        .long("transport")
+                        .value_name("TRANSPORT")
+                        .help("CANET work mode, tcp (server) or udp")
+                        .value_parser(clap::value_parser!(Transport))
+                        .default_value("tcp"),
+                )
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .value_name("FORMAT")
+                        .help("Format of FILE, by default after its extension")
+                        .value_parser(clap::value_parser!(TraceFormat)),
+                )
+                .arg(
+                    Arg::new("speed")
+                        .long("speed")
+                        .value_name("FACTOR")
+                        .help("Play faster (2) or slower (0.5) than recorded")
+                        .value_parser(parse_speed)
+                        .default_value("1"),
+                )
+                .arg(
+                    Arg::new("map")
+                        .long("map")
+                        .value_name("FROM=TO")
+                        .help("Send the frames of interface or bus FROM in the log to bus TO, e.g. CAN2=0 or 1=0 (repeatable)")
+                        .value_parser(parse_bus_map)
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("id")
+                        .long("id")
+                        .value_name("ID[/MASK]")
+                        .help("Only send frames whose id matches ID in the bits of MASK, in hex (repeatable)")
+                        .value_parser(parse_id_filter)
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("loop")
+                        .long("loop")
+                        .value_name("COUNT")
+                        .help("Play the log COUNT times, forever without a COUNT")
+                        .value_parser(clap::value_parser!(u32))
+                        .num_args(0..=1)
+                        .default_missing_value("0"),
+                )
+                .arg(
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .help("Print the CANET records with their time and bus instead of sending them, without waiting")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .args_conflicts_with_subcommands(true)
+        .subcommand_negates_reqs(true)
         .get_matches();
 
-    // Initialize logging
-    let log_level = matches
-        .get_one::<LevelFilter>("debug")
-        .copied()
-        .unwrap_or(LevelFilter::Info);
+    if let Some(("convert", matches)) = matches.subcommand() {
+        env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+        return convert::run(matches);
+    }
+    if let Some(("replay", matches)) = matches.subcommand() {
+        env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+        return replay::run(matches).await;
+    }
 
-    env_logger::Builder::from_env(Env::default().default_filter_or(log_level.to_string())).init();
+    let mut config = match matches.get_one::<PathBuf>("config") {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    config.apply_cli(&matches);
+    // Lives for the whole run, the bridge connects to it like to a real device
+    let simulation = match matches.get_one::<u8>("simulate") {
+        Some(&ports) => {
+            let mock = MockCanet::start(ports as usize).await?;
+            let ports = (0..mock.ports()).map(|p| mock.addr(p).port()).collect();
+            config
+                .canet
+                .push(CanetConfig::new("127.0.0.1".to_string(), ports));
+            Some(mock)
+        }
+        None => None,
+    };
+    config.validate()?;
+
+    // Initialize logging
+    let mut logger = env_logger::Builder::from_env(
+        Env::default().default_filter_or(config.log_level().to_string()),
+    );
+    if let Some(path) = &config.logging.file {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("logging.file: cannot open {path}"))?;
+        logger.target(env_logger::Target::Pipe(Box::new(file)));
+    }
+    logger.init();
 
     let bind_addr = match matches.get_one::<Interface>("interface").unwrap() {
         Interface::Local => "127.0.0.1:23",
         Interface::Any => "0.0.0.0:23",
     };
+    let batching = config.batching.batching();
+    let nodelay = config.batching.nodelay.unwrap_or(true);
+    let tx_options = TxOptions {
+        policy: config.tx_policy.unwrap_or(TxPolicy::Drop),
+        batching,
+    };
 
-    let ip = matches
-        .get_one::<String>("ip")
-        .expect("IP address is required")
-        .to_string();
-    let port1 = *matches
-        .get_one::<u16>("port1")
-        .expect("port1 must be provided");
-    let port2 = matches.get_one::<u16>("port2").copied();
+    // Registered before anything starts, so a stop request is never missed
+    let shutdown = shutdown().context("Cannot handle stop signals")?;
 
     info!("Starting local canet-rs server...");
+    if let Some(mock) = &simulation {
+        info!("Simulating a CANET with {} ports", mock.ports());
+        mock.spawn_demo_traffic(DEMO_PERIOD);
+    }
 
-    let gvret_listener = TcpListener::bind(bind_addr).await?;
-    info!("Listening on {:?}", gvret_listener.local_addr().unwrap());
+    // Socket activated listeners first, then configured addresses, the interface default otherwise
+    let mut listeners = activated_listeners()?;
+    for addr in &config.listen {
+        listeners.push(
+            TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("Cannot listen on {addr}"))?,
+        );
+    }
+    if listeners.is_empty() {
+        listeners.push(
+            TcpListener::bind(bind_addr)
+                .await
+                .with_context(|| format!("Cannot listen on {bind_addr}"))?,
+        );
+    }
 
-    let (gvret_stream, addr) = gvret_listener.accept().await?;
-    info!("Accepted gvret client from {addr}");
+    // Time base of everything the ports receive, so it must exist before any of them
+    let clock = Clock::new(config.timestamps.unwrap_or_default());
 
-    // Connect to CANET device
-    let canet_stream1 = TcpStream::connect(format!("{ip}:{port1}")).await?;
-    info!("Connected to CANET CAN1");
+    // Supervised backend ports, numbered as GVRET buses in config order with CANET ports first
+    let (bus_tx, mut bus_rx) = mpsc::channel(RX_QUEUE_DEPTH);
+    let names = config.bus_names();
+    let mut ports = Vec::new();
+    for device in &config.canet {
+        for (i, port) in device.ports.iter().enumerate() {
+            let addr = format!("{}:{port}", device.host);
+            let backend: Box<dyn Backend> = match device.transport {
+                Transport::Tcp => Box::new(CanetTcp::new(addr, nodelay)),
+                Transport::Udp => Box::new(CanetUdp::new(addr, device.local_port(i))),
+            };
+            ports.push(Port::spawn(
+                names[ports.len()].clone(),
+                backend,
+                ports.len() as u8,
+                device.bitrate,
+                tx_options,
+                clock,
+                bus_tx.clone(),
+            ));
+        }
+    }
+    #[cfg(target_os = "linux")]
+    for interface in &config.socketcan {
+        ports.push(Port::spawn(
+            interface.interface.clone(),
+            Box::new(SocketCan::new(interface.interface.clone())),
+            ports.len() as u8,
+            interface.bitrate,
+            tx_options,
+            clock,
+            bus_tx.clone(),
+        ));
+    }
+    drop(bus_tx);
+    let ports = Arc::new(Router::new(ports));
 
-    // Optional second port
-    let canet_stream2 = if let Some(port) = port2 {
-        match TcpStream::connect(format!("{ip}:{port}")).await {
-            Ok(s) => {
-                info!("Connected to CANET CAN1");
-                Some(s)
-            }
-            Err(e) => {
-                error!("Connection to Canet CAN 2 failed {e}");
-                None
-            }
+    let tx_echo = config.tx_echo.unwrap_or_default();
+    let interfaces: Vec<String> = ports.iter().map(|p| p.name().to_string()).collect();
+    let capture = match &config.capture {
+        Some(capture) => {
+            let file = capture.file.display();
+            Some(
+                Capture::start(capture, interfaces.clone(), clock)
+                    .with_context(|| format!("capture.file: cannot write {file}"))?,
+            )
+        }
+        None => None,
+    };
+    let pcap_stream = &config.pcap_stream;
+    let pcap = if pcap_stream.pipe.is_some() || pcap_stream.listen.is_some() {
+        let pcap = PcapStream::new(&interfaces);
+        #[cfg(unix)]
+        if let Some(path) = &pcap_stream.pipe {
+            pcap.serve_pipe(path.clone())
+                .with_context(|| format!("pcap_stream.pipe: cannot use {}", path.display()))?;
+            info!("Streaming pcapng to {}", path.display());
         }
+        if let Some(addr) = &pcap_stream.listen {
+            let listener = TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("pcap_stream.listen: cannot listen on {addr}"))?;
+            info!("Streaming pcapng on {}", listener.local_addr()?);
+            tokio::spawn(pcap.clone().accept(listener));
+        }
+        Some(pcap)
     } else {
         None
     };
+    let hub = Hub::new(config.client_overflow.unwrap_or_default());
+    let (tx_requests, mut tx_rx) = mpsc::channel(TX_QUEUE_DEPTH);
+    let shared = Shared {
+        hub: hub.clone(),
+        tx_requests,
+        ports: ports.clone(),
+        clock,
+        max_client_errors: config.max_client_errors,
+        batching,
+        nodelay,
+    };
+    for listener in listeners {
+        info!("Listening on {}", listener.local_addr()?);
+        tokio::spawn(accept_clients(listener, shared.clone()));
+    }
+    #[cfg(target_os = "linux")]
+    let mirrors: Vec<_> = config
+        .vcan
+        .iter()
+        .map(|m| {
+            let mirror = VcanMirror::spawn(m.interface.clone(), m.bus, shared.tx_requests.clone());
+            (m.bus, mirror)
+        })
+        .collect();
+    drop(shared);
 
-    // Split streams
-    let (mut gvret_r, mut gvret_w) = gvret_stream.into_split();
-    let (mut canet1_r, mut canet1_w) = canet_stream1.into_split();
-    let (mut canet2_r, mut canet2_w, busses) = match canet_stream2 {
-        Some(s) => {
-            let (r, w) = s.into_split();
-            (Some(r), Some(w), 2)
+    // Handle gvret clients to bus ports, in a task of its own so received frames never wait for it
+    let router = ports.clone();
+    tokio::spawn(async move {
+        while let Some(request) = tx_rx.recv().await {
+            if let Some(port) = router.route(&request.message) {
+                port.send(request);
+            }
         }
-        None => (None, None, 1),
-    };
+    });
 
-    let now = Instant::now();
-    let mut mode = Mode::Init;
+    // Handle bus ports to gvret until the bridge is stopped
+    tokio::pin!(shutdown);
     loop {
-        tokio::select! {
-            // Handle gvret to canet
-            result = decode_gvret_frames(&mut gvret_r, &mut mode, busses, now) => {
-                match result {
-                    Gvret::Frame(message) => {
-                        let data = convert_to_canet(message);
-                        match data {
-                            CanetMsg::Can1(data) => {
-                                canet1_w.write_all(&data).await?;
-                                canet1_w.flush().await?;
-                            }
-                            CanetMsg::Can2(data) => {
-                                if let Some(w) = canet2_w.as_mut(){
-                                    w.write_all(&data).await?;
-                                    w.flush().await?;
-                                };
-
-                            }
-                        }
-                    }
-                    Gvret::Init(b) => {
-                        gvret_w.write_all(&b).await?;
-                        gvret_w.flush().await?;
-                    }
-                }
+        let frame = tokio::select! {
+            frame = bus_rx.recv() => match frame {
+                Some(frame) => frame,
+                None => break,
+            },
+            () = &mut shutdown => {
+                info!("Shutting down");
+                break;
             }
-            // Handle canet1 to gvret
-            result = decode_canet_frame(&mut canet1_r, 0) => {
-                if let Some(frame) = result {
-                    if let Some(b) = convert_to_gvret(frame, now) {
-                        gvret_w.write_all(&b).await?;
-                        gvret_w.flush().await?;
-                    }
-                }
+        };
+        if let Some(capture) = &capture {
+            capture.record(&frame);
+        }
+        if let Some(pcap) = &pcap {
+            pcap.record(&frame);
+        }
+        let message = &frame.message;
+        // Frames transmitted by GVRET clients too, but not those read from a mirror's interface,
+        // which already saw them
+        #[cfg(target_os = "linux")]
+        if frame.origin != Origin::Transmitted(None) {
+            for (_, mirror) in mirrors.iter().filter(|(bus, _)| *bus == message.bus()) {
+                mirror.forward(message.clone());
+            }
+        }
+        if frame.origin == Origin::Bus && !ports[message.bus() as usize].settings().enabled {
+            continue;
+        }
+        if !config.accepts(message.bus(), message.id()) {
+            continue;
+        }
+        match (frame.origin, tx_echo) {
+            (Origin::Bus, _) | (Origin::Transmitted(_), TxEcho::All) => {
+                hub.broadcast(&convert_to_gvret(frame))
             }
-            // Handle canet2 to gvret (if connected)
-            result = async {
-                if let Some(r) = canet2_r.as_mut(){
-                    decode_canet_frame(r, 1).await
-                } else {
-                    std::future::pending::<Option<Message>>().await
-                }
-            } => {
-                if let Some(frame) = result {
-                    if let Some(b) = convert_to_gvret(frame, now) {
-                        gvret_w.write_all(&b).await?;
-                        gvret_w.flush().await?;
-                    }
-                }
+            (Origin::Transmitted(Some(client)), TxEcho::Sender) => {
+                hub.send_to(client, &convert_to_gvret(frame))
             }
+            _ => {}
         }
     }
+    // The writer completes the file, e.g. the gzip trailer or the last BLF container
+    if let Some(capture) = capture {
+        capture.finish();
+    }
+    Ok(())
+}
+
+/// Resolves once the bridge is asked to stop, by Ctrl-C or SIGTERM on Unix
+fn shutdown() -> std::io::Result<impl Future<Output = ()>> {
+    #[cfg(unix)]
+    let (mut interrupt, mut terminate) = {
+        use tokio::signal::unix::{SignalKind, signal};
+        (
+            signal(SignalKind::interrupt())?,
+            signal(SignalKind::terminate())?,
+        )
+    };
+    Ok(async move {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = interrupt.recv() => {}
+            _ = terminate.recv() => {}
+        }
+        #[cfg(not(unix))]
+        if tokio::signal::ctrl_c().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    })
 }
 
 #[derive(Clone, Copy, Debug, ValueEnum)]
@@ -0,0 +1,283 @@
+//! A fake USR-CANET in TCP server mode on localhost, for integration tests and for demos without
+//! hardware (`usr-canet-gvret --simulate`).
+//!
+//! Each port listens on its own ephemeral TCP port. Frames passed to [`MockCanet::emit`] are sent
+//! to every connected bridge as if received on that CAN bus, and records written by the bridge
+//! are decoded and kept for [`MockCanet::recv`]. Nobody takes them under `--simulate`, so once
+//! [`RECV_QUEUE_DEPTH`] are kept further ones are dropped and counted.
+//!
+//! A port can be stopped and restarted on the same address, as when a device is power cycled.
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use log::{info, warn};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    sync::{Mutex, broadcast, mpsc, watch},
+    task::{JoinHandle, JoinSet},
+    time::interval,
+};
+
+use crate::usr_canet::{Message, UsrError, convert_to_canet, decode_canet_frame};
+
+/// Frames buffered per connection before a slow bridge misses some
+const EMIT_QUEUE_DEPTH: usize = 1024;
+/// Frames written by the bridge kept per port for [`MockCanet::recv`] before more are dropped
+pub const RECV_QUEUE_DEPTH: usize = 1024;
+
+struct MockPort {
+    addr: SocketAddr,
+    received: Mutex<mpsc::Receiver<Message>>,
+    /// Frames written by the bridge while `received` was full
+    dropped: Arc<AtomicU64>,
+    connections: watch::Receiver<usize>,
+    server: Server,
+    /// The listener and its connections, `None` while the port is stopped
+    task: std::sync::Mutex<Option<JoinHandle<()>>>,
+}
+
+/// Everything the listener of a port serves its connections with
+#[derive(Clone)]
+struct Server {
+    bus: u8,
+    emit: broadcast::Sender<Message>,
+    received: Recorder,
+    connections: Arc<watch::Sender<usize>>,
+    /// Connections accepted since the start
+    accepted: Arc<AtomicUsize>,
+}
+
+/// A simulated CANET with any number of CAN ports, numbered as buses from 0
+pub struct MockCanet {
+    ports: Vec<MockPort>,
+}
+
+impl MockCanet {
+    /// Listen on a free localhost port for each of `ports` CAN ports
+    pub async fn start(ports: usize) -> io::Result<Self> {
+        let mut mock = Self { ports: vec![] };
+        for bus in 0..ports {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = listener.local_addr()?;
+            let (received_tx, received) = mpsc::channel(RECV_QUEUE_DEPTH);
+            let dropped = Arc::new(AtomicU64::new(0));
+            let (connections_tx, connections) = watch::channel(0);
+            let server = Server {
+                bus: bus as u8,
+                emit: broadcast::channel(EMIT_QUEUE_DEPTH).0,
+                received: Recorder {
+                    tx: received_tx,
+                    dropped: dropped.clone(),
+                },
+                connections: Arc::new(connections_tx),
+                accepted: Arc::new(AtomicUsize::new(0)),
+            };
+            let task = tokio::spawn(accept(listener, server.clone()));
+            mock.ports.push(MockPort {
+                addr,
+                received: Mutex::new(received),
+                dropped,
+                connections,
+                server,
+                task: std::sync::Mutex::new(Some(task)),
+            });
+        }
+        Ok(mock)
+    }
+
+    pub fn ports(&self) -> usize {
+        self.ports.len()
+    }
+
+    /// TCP address of a CAN port, to connect the bridge to
+    pub fn addr(&self, port: usize) -> SocketAddr {
+        self.ports[port].addr
+    }
+
+    /// Wait until a bridge is connected to `port`
+    pub async fn connected(&self, port: usize) {
+        let mut connections = self.ports[port].connections.clone();
+        // The sender lives as long as the port
+        let _ = connections.wait_for(|&n| n > 0).await;
+    }
+
+    /// Wait until no bridge is connected to `port`
+    pub async fn disconnected(&self, port: usize) {
+        let mut connections = self.ports[port].connections.clone();
+        let _ = connections.wait_for(|&n| n == 0).await;
+    }
+
+    /// Number of connections `port` accepted so far
+    pub fn accepted(&self, port: usize) -> usize {
+        self.ports[port].server.accepted.load(Ordering::Relaxed)
+    }
+
+    /// Take `port` down as if the device went away: stop listening and close its connections
+    pub fn stop(&self, port: usize) {
+        if let Some(task) = self.ports[port].task.lock().unwrap().take() {
+            task.abort();
+        }
+    }
+
+    /// Listen on a stopped `port` again, at the address it had
+    pub async fn restart(&self, port: usize) -> io::Result<()> {
+        let port = &self.ports[port];
+        let listener = TcpListener::bind(port.addr).await?;
+        let task = tokio::spawn(accept(listener, port.server.clone()));
+        if let Some(running) = port.task.lock().unwrap().replace(task) {
+            running.abort();
+        }
+        Ok(())
+    }
+
+    /// Send a frame to the connected bridges as if it had been received on `port`. Frames emitted
+    /// while no bridge is connected are lost, like on a real bus.
+    pub fn emit(&self, port: usize, message: &Message) {
+        let _ = self.ports[port].server.emit.send(message.clone());
+    }
+
+    /// Next frame the bridge wrote to `port`, tagged with `port` as bus
+    pub async fn recv(&self, port: usize) -> Option<Message> {
+        self.ports[port].received.lock().await.recv().await
+    }
+
+    /// Number of frames the bridge wrote to `port` that were dropped as nobody took them
+    pub fn dropped(&self, port: usize) -> u64 {
+        self.ports[port].dropped.load(Ordering::Relaxed)
+    }
+
+    /// Emit a counter frame on every port each `period`: standard id `0x100 + port` with a little
+    /// endian counter, and every tenth time an extended id `0x18FF0000 + port` frame.
+    pub fn spawn_demo_traffic(&self, period: Duration) {
+        let emitters: Vec<_> = self.ports.iter().map(|p| p.server.emit.clone()).collect();
+        tokio::spawn(async move {
+            let mut ticks = interval(period);
+            for counter in 0_u32.. {
+                ticks.tick().await;
+                for (port, emit) in emitters.iter().enumerate() {
+                    let bus = port as u8;
+                    let frame =
+                        Message::new_data(bus, 0x100 + port as u32, false, &counter.to_le_bytes())
+                            .unwrap();
+                    let _ = emit.send(frame);
+                    if counter % 10 == 0 {
+                        let frame =
+                            Message::new_data(bus, 0x18FF0000 + port as u32, true, &[bus; 8])
+                                .unwrap();
+                        let _ = emit.send(frame);
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Keeps the frames a port's connections write for [`MockCanet::recv`]
+#[derive(Clone)]
+struct Recorder {
+    tx: mpsc::Sender<Message>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Recorder {
+    fn record(&self, bus: u8, message: Message) {
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.tx.try_send(message) {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped.is_power_of_two() {
+                warn!("Simulated CANET port {bus} kept no more frames, {dropped} dropped so far");
+            }
+        }
+    }
+}
+
+/// Counts a connection of a port for as long as it lives
+struct Connection(Arc<watch::Sender<usize>>);
+
+impl Connection {
+    fn open(connections: Arc<watch::Sender<usize>>) -> Self {
+        connections.send_modify(|n| *n += 1);
+        Self(connections)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.send_modify(|n| *n -= 1);
+    }
+}
+
+/// Serve the connections accepted on `listener`. They are owned here, so aborting the task closes
+/// them along with the listener.
+async fn accept(listener: TcpListener, server: Server) {
+    let bus = server.bus;
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, addr)) => {
+                    info!("Simulated CANET port {bus} accepted {addr}");
+                    server.accepted.fetch_add(1, Ordering::Relaxed);
+                    let connection = Connection::open(server.connections.clone());
+                    let emit = server.emit.subscribe();
+                    let received = server.received.clone();
+                    connections.spawn(async move {
+                        serve(stream, bus, emit, received).await;
+                        drop(connection);
+                    });
+                }
+                Err(e) => warn!("Simulated CANET port {bus} accept error {e}"),
+            },
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+        }
+    }
+}
+
+/// Relay emitted frames to one bridge connection and record what it writes until it closes
+async fn serve(
+    stream: TcpStream,
+    bus: u8,
+    mut emit: broadcast::Receiver<Message>,
+    received: Recorder,
+) {
+    let (mut r, mut w) = stream.into_split();
+    let reader = async {
+        loop {
+            match decode_canet_frame(&mut r, bus).await {
+                Ok(message) => {
+                    info!("Simulated CANET port {bus} transmits {message}");
+                    received.record(bus, message);
+                }
+                Err(UsrError::Io(_)) => return,
+                Err(e) => warn!("Simulated CANET port {bus} got a bad record: {e}"),
+            }
+        }
+    };
+    let writer = async {
+        loop {
+            match emit.recv().await {
+                Ok(message) => {
+                    if w.write_all(&convert_to_canet(&message)).await.is_err() {
+                        return;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("Simulated CANET port {bus} dropped {n} frames")
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    };
+    tokio::select! {
+        _ = reader => {}
+        _ = writer => {}
+    }
+}
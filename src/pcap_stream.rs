@@ -0,0 +1,203 @@
+//! Live pcapng stream of all traffic crossing the bridge, so Wireshark can attach to the running
+//! bridge through a named pipe (`wireshark -k -i PIPE`) or a TCP port (`-i TCP@HOST:PORT`).
+//!
+//! Every reader gets a section header and the interfaces of all buses, then each frame as it
+//! crosses the bridge. Readers that fall behind miss frames rather than holding back the bridge.
+
+use std::{io, sync::Arc};
+
+use bytes::Bytes;
+use log::{info, warn};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    sync::broadcast::{self, error::RecvError},
+};
+
+use crate::{pcapng, port::Received};
+
+/// Frames buffered per reader before the reader misses frames
+const STREAM_QUEUE_DEPTH: usize = 4096;
+/// Frames queued for a reader are written together up to this many bytes
+const MAX_WRITE_LEN: usize = 64 * 1024;
+
+/// Handle to the stream, cheap to clone
+#[derive(Clone)]
+pub(crate) struct PcapStream {
+    /// Section header and interface descriptions every reader starts with
+    header: Arc<Vec<u8>>,
+    tx: broadcast::Sender<Bytes>,
+}
+
+impl PcapStream {
+    /// A stream of the buses named by `interfaces`, indexed by bus number
+    pub(crate) fn new(interfaces: &[String]) -> Self {
+        let mut header = pcapng::section_header();
+        for name in interfaces {
+            header.extend(pcapng::interface_description(name));
+        }
+        Self {
+            header: Arc::new(header),
+            tx: broadcast::channel(STREAM_QUEUE_DEPTH).0,
+        }
+    }
+
+    /// Send a frame to the attached readers
+    pub(crate) fn record(&self, frame: &Received) {
+        if self.tx.receiver_count() > 0 {
+            let record = frame.record();
+            let packet = pcapng::enhanced_packet(frame.message.bus().into(), &record);
+            let _ = self.tx.send(packet.into());
+        }
+    }
+
+    /// Stream to every connection accepted on `listener`
+    pub(crate) async fn accept(self, listener: TcpListener) {
+        loop {
+            match listener.accept().await {
+                Ok((mut stream, addr)) => {
+                    info!("pcap reader connected from {addr}");
+                    let _ = stream.set_nodelay(true);
+                    let pcap = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = pcap.write_to(&mut stream, &addr.to_string()).await {
+                            info!("pcap reader {addr} disconnected: {e}");
+                        }
+                    });
+                }
+                Err(e) => warn!("Failed to accept a pcap reader: {e}"),
+            }
+        }
+    }
+
+    /// Write the stream to `out` until it fails or the bridge shuts down
+    async fn write_to(&self, out: &mut (impl AsyncWrite + Unpin), reader: &str) -> io::Result<()> {
+        let mut rx = self.tx.subscribe();
+        out.write_all(&self.header).await?;
+        let mut batch = Vec::with_capacity(MAX_WRITE_LEN);
+        loop {
+            match rx.recv().await {
+                Ok(packet) => batch.extend_from_slice(&packet),
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("pcap reader {reader} fell behind and missed {missed} frames");
+                    continue;
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            }
+            while batch.len() < MAX_WRITE_LEN {
+                match rx.try_recv() {
+                    Ok(packet) => batch.extend_from_slice(&packet),
+                    Err(_) => break,
+                }
+            }
+            out.write_all(&batch).await?;
+            out.flush().await?;
+            batch.clear();
+        }
+    }
+}
+
+#[cfg(unix)]
+mod pipe {
+    use std::{
+        fs::{self, OpenOptions},
+        io,
+        os::unix::fs::{FileTypeExt, OpenOptionsExt},
+        path::{Path, PathBuf},
+        time::Duration,
+    };
+
+    use log::{info, warn};
+    use tokio::{io::Interest, net::unix::pipe::Sender, time::sleep};
+
+    use super::PcapStream;
+
+    /// Interval at which the pipe is tried until a reader opens it
+    const ATTACH_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+    impl PcapStream {
+        /// Stream to whoever opens the named pipe at `path`, one reader after the other. The pipe
+        /// is created if there is nothing at `path`.
+        pub(crate) fn serve_pipe(&self, path: PathBuf) -> io::Result<()> {
+            match fs::metadata(&path) {
+                Ok(metadata) if metadata.file_type().is_fifo() => {}
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        "not a named pipe",
+                    ));
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => make_pipe(&path)?,
+                Err(e) => return Err(e),
+            }
+            let pcap = self.clone();
+            tokio::spawn(async move {
+                let name = path.display().to_string();
+                loop {
+                    let (mut pipe, hangup) = match open(path.clone()).await {
+                        Ok(pipe) => pipe,
+                        Err(e) => {
+                            warn!("pcap pipe {name} closed: {e}");
+                            return;
+                        }
+                    };
+                    info!("pcap reader attached to {name}");
+                    // A reader opening the pipe right after the last one left would otherwise get
+                    // the rest of the last reader's stream, without its header
+                    tokio::select! {
+                        result = pcap.write_to(&mut pipe, &name) => match result {
+                            Ok(()) => return,
+                            Err(e) => info!("pcap reader left {name}: {e}"),
+                        },
+                        _ = hangup.ready(Interest::ERROR) => info!("pcap reader left {name}"),
+                    }
+                }
+            });
+            Ok(())
+        }
+    }
+
+    /// Open the pipe once a reader opens it, with a second handle to watch for the reader leaving.
+    ///
+    /// A blocking open would only return once a reader comes, in a thread the bridge could not
+    /// stop, so the pipe is opened without blocking until it has a reader.
+    async fn open(path: PathBuf) -> io::Result<(Sender, Sender)> {
+        let file = loop {
+            match OpenOptions::new()
+                .write(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(&path)
+            {
+                Ok(file) => break file,
+                Err(e) if e.raw_os_error() == Some(libc::ENXIO) => {
+                    sleep(ATTACH_POLL_INTERVAL).await
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        Ok((
+            Sender::from_file(file.try_clone()?)?,
+            Sender::from_file(file)?,
+        ))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn make_pipe(path: &Path) -> io::Result<()> {
+        use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        // SAFETY: plain libc call on a NUL terminated path
+        if unsafe { libc::mkfifo(path.as_ptr(), 0o644) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn make_pipe(_path: &Path) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no named pipe, create it with mkfifo",
+        ))
+    }
+}
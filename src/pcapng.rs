@@ -0,0 +1,432 @@
+//! The pcapng capture file format of Wireshark, with frames as `LINKTYPE_CAN_SOCKETCAN` packets.
+//!
+//! A file is a section header followed by an interface description per bus, in bus order so the
+//! interface id of a packet is its bus, and an enhanced packet block per frame. Timestamps are in
+//! nanoseconds since the Unix epoch and the direction is given by the `epb_flags` option. Files
+//! may be concatenated, each starting a new section with its own interfaces.
+//!
+//! Packets hold the 16 bytes of a Linux `struct can_frame`: the id with the extended and remote
+//! flags in network byte order, the length, three reserved bytes and the data padded to 8 bytes.
+
+use std::{
+    io::{self, Read, Write},
+    time::Duration,
+};
+
+use crate::{
+    trace::{Direction, Record, TraceWriter},
+    usr_canet::Message,
+};
+
+const SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION: u32 = 1;
+const ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+pub const LINKTYPE_CAN_SOCKETCAN: u16 = 227;
+
+const OPT_END: u16 = 0;
+const SHB_USERAPPL: u16 = 4;
+const IF_NAME: u16 = 2;
+const IF_TSRESOL: u16 = 9;
+const EPB_FLAGS: u16 = 2;
+
+const EPB_INBOUND: u32 = 1;
+const EPB_OUTBOUND: u32 = 2;
+
+const CAN_EFF_FLAG: u32 = 0x8000_0000;
+const CAN_RTR_FLAG: u32 = 0x4000_0000;
+const CAN_ERR_FLAG: u32 = 0x2000_0000;
+/// Size of a `struct can_frame`
+const CAN_FRAME_LEN: usize = 16;
+/// Largest block read, far beyond any CAN packet
+const MAX_BLOCK_LEN: usize = 1 << 20;
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Append an option, padded to 4 bytes
+fn option(block: &mut Vec<u8>, code: u16, value: &[u8]) {
+    block.extend(code.to_le_bytes());
+    block.extend((value.len() as u16).to_le_bytes());
+    block.extend(value);
+    block.resize(block.len().next_multiple_of(4), 0);
+}
+
+/// Frame `body` as a block of `block_type`, ending its options
+fn block(block_type: u32, mut body: Vec<u8>) -> Vec<u8> {
+    option(&mut body, OPT_END, &[]);
+    let len = (body.len() + 12) as u32;
+    let mut block = Vec::with_capacity(len as usize);
+    block.extend(block_type.to_le_bytes());
+    block.extend(len.to_le_bytes());
+    block.extend(body);
+    block.extend(len.to_le_bytes());
+    block
+}
+
+/// A section header block, starting a file or a new section of it
+pub fn section_header() -> Vec<u8> {
+    let mut body = vec![];
+    body.extend(BYTE_ORDER_MAGIC.to_le_bytes());
+    // Version 1.0, section length unknown
+    body.extend(1_u16.to_le_bytes());
+    body.extend(0_u16.to_le_bytes());
+    body.extend((-1_i64).to_le_bytes());
+    option(
+        &mut body,
+        SHB_USERAPPL,
+        concat!("usr-canet-gvret ", env!("CARGO_PKG_VERSION")).as_bytes(),
+    );
+    block(SECTION_HEADER, body)
+}
+
+/// An interface description block for a CAN bus with nanosecond timestamps
+pub fn interface_description(name: &str) -> Vec<u8> {
+    let mut body = vec![];
+    body.extend(LINKTYPE_CAN_SOCKETCAN.to_le_bytes());
+    body.extend(0_u16.to_le_bytes());
+    // No snapshot length limit
+    body.extend(0_u32.to_le_bytes());
+    option(&mut body, IF_NAME, name.as_bytes());
+    option(&mut body, IF_TSRESOL, &[9]);
+    block(INTERFACE_DESCRIPTION, body)
+}
+
+/// An enhanced packet block for `record` on interface `interface`
+pub fn enhanced_packet(interface: u32, record: &Record) -> Vec<u8> {
+    let message = &record.message;
+    let mut id = message.id();
+    if message.ext_id() {
+        id |= CAN_EFF_FLAG;
+    }
+    let mut frame = [0; CAN_FRAME_LEN];
+    match message.data() {
+        Some(data) => frame[8..8 + data.len()].copy_from_slice(data),
+        None => id |= CAN_RTR_FLAG,
+    }
+    frame[0..4].copy_from_slice(&id.to_be_bytes());
+    frame[4] = message.dlc();
+
+    let nanos = record.time.as_nanos() as u64;
+    let mut body = vec![];
+    body.extend(interface.to_le_bytes());
+    body.extend(((nanos >> 32) as u32).to_le_bytes());
+    body.extend((nanos as u32).to_le_bytes());
+    body.extend((CAN_FRAME_LEN as u32).to_le_bytes());
+    body.extend((CAN_FRAME_LEN as u32).to_le_bytes());
+    body.extend(frame);
+    match record.direction {
+        Some(Direction::Rx) => option(&mut body, EPB_FLAGS, &EPB_INBOUND.to_le_bytes()),
+        Some(Direction::Tx) => option(&mut body, EPB_FLAGS, &EPB_OUTBOUND.to_le_bytes()),
+        None => {}
+    }
+    block(ENHANCED_PACKET, body)
+}
+
+/// Writes a pcapng section, naming each bus after its entry in `interfaces` or `canN` beyond
+pub struct Writer<W> {
+    out: W,
+    interfaces: Vec<String>,
+    /// Interfaces described so far, one per bus from 0
+    described: usize,
+}
+
+impl<W: Write> Writer<W> {
+    /// Write the section header and the interfaces known so far
+    pub fn new(mut out: W, interfaces: Vec<String>) -> io::Result<Self> {
+        out.write_all(&section_header())?;
+        let mut writer = Self {
+            out,
+            interfaces,
+            described: 0,
+        };
+        writer.describe(writer.interfaces.len())?;
+        Ok(writer)
+    }
+
+    /// Name the buses beyond the known ones, as a reader finds more interfaces
+    pub fn extend_interfaces(&mut self, interfaces: &[String]) {
+        if let Some(new) = interfaces.get(self.interfaces.len()..) {
+            self.interfaces.extend_from_slice(new);
+        }
+    }
+
+    /// Describe the interfaces of buses up to `buses`
+    fn describe(&mut self, buses: usize) -> io::Result<()> {
+        while self.described < buses {
+            let bus = self.described;
+            let block = match self.interfaces.get(bus) {
+                Some(name) => interface_description(name),
+                None => interface_description(&format!("can{bus}")),
+            };
+            self.out.write_all(&block)?;
+            self.described += 1;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write + Send> TraceWriter for Writer<W> {
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        let bus = record.message.bus();
+        self.describe(usize::from(bus) + 1)?;
+        self.out.write_all(&enhanced_packet(bus.into(), record))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// An interface of the current section
+struct Interface {
+    /// Bus of a CAN interface, counting the CAN interfaces of the section
+    bus: Option<u8>,
+    /// Timestamp units per second
+    resolution: u64,
+}
+
+/// Reads the CAN frames of a pcapng file, numbering the interfaces of each section as buses.
+///
+/// Packets of other link types and error frames are skipped. Both byte orders are read, but
+/// classic pcap files are not.
+pub struct Reader<R> {
+    input: R,
+    big_endian: bool,
+    interfaces: Vec<Interface>,
+    names: Vec<String>,
+    /// Whether a section header was read
+    started: bool,
+    done: bool,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(input: R) -> Self {
+        Self {
+            input,
+            big_endian: false,
+            interfaces: vec![],
+            names: vec![],
+            started: false,
+            done: false,
+        }
+    }
+
+    /// Interface names of the current section, indexed by the bus they were given. Interfaces
+    /// without a name are called `canN`.
+    pub fn interfaces(&self) -> &[String] {
+        &self.names
+    }
+
+    fn u16(&self, bytes: &[u8]) -> u16 {
+        let bytes = [bytes[0], bytes[1]];
+        if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        }
+    }
+
+    fn u32(&self, bytes: &[u8]) -> u32 {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+
+    /// The options in `body` as code and value
+    fn options<'a>(&self, body: &'a [u8]) -> Vec<(u16, &'a [u8])> {
+        let mut options = vec![];
+        let mut at = 0;
+        while at + 4 <= body.len() {
+            let code = self.u16(&body[at..]);
+            let len = self.u16(&body[at + 2..]) as usize;
+            if code == OPT_END || at + 4 + len > body.len() {
+                break;
+            }
+            options.push((code, &body[at + 4..at + 4 + len]));
+            at += 4 + len.next_multiple_of(4);
+        }
+        options
+    }
+
+    /// Read the next block as its type and body, or `None` at the end of the file
+    fn read_block(&mut self) -> io::Result<Option<(u32, Vec<u8>)>> {
+        let mut header = [0; 8];
+        match self.input.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let block_type = self.u32(&header);
+        if block_type != SECTION_HEADER && !self.started {
+            return Err(invalid("not a pcapng file"));
+        }
+        if block_type == SECTION_HEADER {
+            self.started = true;
+            let mut magic = [0; 4];
+            self.input.read_exact(&mut magic)?;
+            self.big_endian = match u32::from_le_bytes(magic) {
+                BYTE_ORDER_MAGIC => false,
+                magic if magic.swap_bytes() == BYTE_ORDER_MAGIC => true,
+                _ => return Err(invalid("not a pcapng file")),
+            };
+            let len = self.u32(&header[4..]) as usize;
+            if !(28..=MAX_BLOCK_LEN).contains(&len) {
+                return Err(invalid(format!("section header of {len} bytes")));
+            }
+            let mut body = vec![0; len - 12];
+            self.input.read_exact(&mut body)?;
+            // Without the magic and the trailing length
+            body.truncate(len - 16);
+            return Ok(Some((block_type, body)));
+        }
+        let len = self.u32(&header[4..]) as usize;
+        if !(12..=MAX_BLOCK_LEN).contains(&len) || !len.is_multiple_of(4) {
+            return Err(invalid(format!("block of {len} bytes")));
+        }
+        let mut body = vec![0; len - 8];
+        self.input.read_exact(&mut body)?;
+        // Without the trailing length
+        body.truncate(len - 12);
+        Ok(Some((block_type, body)))
+    }
+
+    /// Handle a block, returning the frame of a CAN packet
+    fn parse_block(&mut self, block_type: u32, body: &[u8]) -> io::Result<Option<Record>> {
+        match block_type {
+            SECTION_HEADER => {
+                if self.u16(&body[0..]) != 1 {
+                    return Err(invalid("unsupported pcapng version"));
+                }
+                self.interfaces.clear();
+                self.names.clear();
+            }
+            INTERFACE_DESCRIPTION if body.len() >= 8 => {
+                let mut name = None;
+                let mut interface = Interface {
+                    bus: None,
+                    resolution: 1_000_000,
+                };
+                for (code, value) in self.options(&body[8..]) {
+                    match (code, value) {
+                        (IF_NAME, value) => {
+                            name = Some(String::from_utf8_lossy(value).into_owned())
+                        }
+                        (IF_TSRESOL, &[resolution]) => {
+                            let exponent = u32::from(resolution & 0x7f);
+                            interface.resolution = if resolution & 0x80 == 0 {
+                                10_u64.checked_pow(exponent)
+                            } else {
+                                2_u64.checked_pow(exponent)
+                            }
+                            .ok_or_else(|| invalid("timestamp resolution out of range"))?;
+                        }
+                        _ => {}
+                    }
+                }
+                if self.u16(body) == LINKTYPE_CAN_SOCKETCAN {
+                    let bus = self.names.len();
+                    interface.bus = Some(bus as u8);
+                    self.names.push(name.unwrap_or(format!("can{bus}")));
+                }
+                self.interfaces.push(interface);
+            }
+            ENHANCED_PACKET if body.len() >= 20 => {
+                let id = self.u32(body) as usize;
+                let interface = self
+                    .interfaces
+                    .get(id)
+                    .ok_or_else(|| invalid(format!("packet on undescribed interface {id}")))?;
+                let Some(bus) = interface.bus else {
+                    return Ok(None);
+                };
+                let timestamp =
+                    u64::from(self.u32(&body[4..])) << 32 | u64::from(self.u32(&body[8..]));
+                let resolution = interface.resolution;
+                let time = Duration::from_secs(timestamp / resolution)
+                    + Duration::from_nanos(
+                        ((timestamp % resolution) as u128 * 1_000_000_000 / resolution as u128)
+                            as u64,
+                    );
+                let captured = self.u32(&body[12..]) as usize;
+                let packet = body
+                    .get(20..20 + captured)
+                    .filter(|packet| packet.len() >= 8)
+                    .ok_or_else(|| invalid("short CAN packet"))?;
+                // The id is in network byte order whatever the byte order of the section
+                let id = u32::from_be_bytes(packet[0..4].try_into().unwrap());
+                if id & CAN_ERR_FLAG != 0 {
+                    return Ok(None);
+                }
+                let len = packet[4];
+                if len > 8 {
+                    return Err(invalid("CAN FD frames are not supported"));
+                }
+                let ext_id = id & CAN_EFF_FLAG != 0;
+                let can_id = id & if ext_id { 0x1fff_ffff } else { 0x7ff };
+                let message = if id & CAN_RTR_FLAG != 0 {
+                    Message::new_remote(bus, can_id, ext_id, len)
+                } else {
+                    let data = packet
+                        .get(8..8 + len as usize)
+                        .ok_or_else(|| invalid("short CAN packet"))?;
+                    Message::new_data(bus, can_id, ext_id, data)
+                }
+                .map_err(|e| invalid(format!("invalid frame: {e:?}")))?;
+                let options = body
+                    .get((20 + captured).next_multiple_of(4)..)
+                    .unwrap_or(&[]);
+                let direction = self
+                    .options(options)
+                    .into_iter()
+                    .find(|(code, value)| *code == EPB_FLAGS && value.len() == 4)
+                    .and_then(|(_, flags)| match self.u32(flags) & 3 {
+                        EPB_INBOUND => Some(Direction::Rx),
+                        EPB_OUTBOUND => Some(Direction::Tx),
+                        _ => None,
+                    });
+                return Ok(Some(Record {
+                    time,
+                    message,
+                    direction,
+                }));
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    /// Malformed packets are reported as [`io::ErrorKind::InvalidData`] and can be skipped, other
+    /// errors end the file
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<io::Result<Record>> {
+        while !self.done {
+            let (block_type, body) = match self.read_block() {
+                Ok(Some(block)) => block,
+                Ok(None) => return None,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            };
+            match self.parse_block(block_type, &body) {
+                Ok(Some(record)) => return Some(Ok(record)),
+                Ok(None) => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+}
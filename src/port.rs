@@ -0,0 +1,325 @@
+use std::{
+    io,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Instant,
+};
+
+use clap::ValueEnum;
+use log::{debug, info, warn};
+use serde::Deserialize;
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    time::{Duration, sleep},
+};
+
+use crate::{
+    backend::{Backend, BackendReader, BackendWriter},
+    batch::Batching,
+    clock::Clock,
+    server::ClientId,
+    trace::{Direction, Record},
+    usr_canet::{CANET_RECORD_LEN, Message, UsrError},
+};
+
+/// First reconnect delay after a link drops, doubled on every failed attempt
+pub(crate) const MIN_BACKOFF: Duration = Duration::from_millis(500);
+/// Upper bound for the reconnect delay
+pub(crate) const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Frames held for a port with [`TxPolicy::Queue`] while it is disconnected
+const PORT_QUEUE_DEPTH: usize = 256;
+
+/// What to do with frames transmitted by GVRET clients while a port is disconnected
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TxPolicy {
+    /// Discard frames until the link is back
+    Drop,
+    /// Hold up to a fixed number of frames and send them once reconnected
+    Queue,
+}
+
+/// How ports transmit, the same for every port of the bridge
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct TxOptions {
+    pub(crate) policy: TxPolicy,
+    /// Coalescing of queued frames into backend writes
+    pub(crate) batching: Batching,
+}
+
+/// Per-bus settings as configured through GVRET `SetupCanBus`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct BusSettings {
+    pub(crate) enabled: bool,
+    pub(crate) listen_only: bool,
+    /// Bitrate the CANET port is configured for
+    pub(crate) speed: u32,
+}
+
+/// A frame to transmit and the GVRET client that sent it, `None` for other sources
+#[derive(Clone, Debug)]
+pub(crate) struct TxRequest {
+    pub(crate) message: Message,
+    pub(crate) client: Option<ClientId>,
+}
+
+/// How a frame reported by a port got onto the bus
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Origin {
+    /// Received from another node
+    Bus,
+    /// Written to the backend on behalf of a client, or another source if `None`
+    Transmitted(Option<ClientId>),
+}
+
+/// A frame read from or written to a backend, stamped when the read or write completed
+#[derive(Clone, Debug)]
+pub(crate) struct Received {
+    pub(crate) message: Message,
+    /// GVRET timestamp
+    pub(crate) timestamp: u32,
+    /// Wall-clock time since the Unix epoch, for capture files
+    pub(crate) time: Duration,
+    pub(crate) origin: Origin,
+}
+
+impl Received {
+    /// The frame as logged, at its wall-clock time
+    pub(crate) fn record(&self) -> Record {
+        Record {
+            time: self.time,
+            message: self.message.clone(),
+            direction: Some(match self.origin {
+                Origin::Bus => Direction::Rx,
+                Origin::Transmitted(_) => Direction::Tx,
+            }),
+        }
+    }
+}
+
+/// Where a port sends the frames it read or wrote
+#[derive(Clone)]
+struct Reporter {
+    bus: u8,
+    clock: Clock,
+    rx: mpsc::Sender<Received>,
+}
+
+impl Reporter {
+    /// Stamp a frame read or written at `at` and pass it on, failing once the bridge shut down
+    async fn report(&self, message: Message, origin: Origin, at: Instant) -> io::Result<()> {
+        let received = Received {
+            message,
+            timestamp: self.clock.timestamp(at),
+            time: self.clock.unix_time(at),
+            origin,
+        };
+        self.rx
+            .send(received)
+            .await
+            .map_err(|_| io::Error::other("bridge shut down"))
+    }
+}
+
+/// Handle to a supervised backend connection, one per GVRET bus
+pub(crate) struct Port {
+    name: String,
+    tx: mpsc::Sender<TxRequest>,
+    connected: Arc<AtomicBool>,
+    settings: Mutex<BusSettings>,
+    policy: TxPolicy,
+    dropped: AtomicU64,
+}
+
+impl Port {
+    /// Open `backend` in the background, reopening it with exponential backoff for as long as the
+    /// process runs. Received frames are tagged with `bus`, stamped by `clock` and sent to `rx`,
+    /// followed by each transmitted frame once it has been written.
+    pub(crate) fn spawn(
+        name: String,
+        backend: Box<dyn Backend>,
+        bus: u8,
+        bitrate: u32,
+        options: TxOptions,
+        clock: Clock,
+        rx: mpsc::Sender<Received>,
+    ) -> Self {
+        let (tx, tx_queue) = mpsc::channel(PORT_QUEUE_DEPTH);
+        let connected = Arc::new(AtomicBool::new(false));
+        tokio::spawn(supervise(
+            name.clone(),
+            backend,
+            connected.clone(),
+            options,
+            tx_queue,
+            Reporter { bus, clock, rx },
+        ));
+        Self {
+            name,
+            tx,
+            connected,
+            settings: Mutex::new(BusSettings {
+                enabled: true,
+                listen_only: false,
+                speed: bitrate,
+            }),
+            policy: options.policy,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn settings(&self) -> BusSettings {
+        *self.settings.lock().unwrap()
+    }
+
+    /// Apply settings requested by a GVRET client.
+    ///
+    /// Enable and listen-only are enforced by the bridge. The bitrate is set outside the bridge, in
+    /// the CANET configuration tool or with `ip link`, so a different bitrate is refused and the
+    /// configured one kept.
+    pub(crate) fn configure(&self, enabled: bool, listen_only: bool, speed: u32) {
+        let mut settings = self.settings.lock().unwrap();
+        if enabled && speed != settings.speed {
+            warn!(
+                "{} bitrate is fixed at {} by the device configuration, ignoring request for {speed}",
+                self.name, settings.speed
+            );
+        }
+        settings.enabled = enabled;
+        settings.listen_only = listen_only;
+        info!("{} enabled={enabled} listen_only={listen_only}", self.name);
+    }
+
+    /// Queue a frame for transmission, dropping it if the port cannot take it
+    pub(crate) fn send(&self, frame: TxRequest) {
+        let settings = self.settings();
+        if !settings.enabled || settings.listen_only {
+            debug!(
+                "CANET {} is not transmitting, TX frame discarded",
+                self.name
+            );
+            return;
+        }
+        if !self.is_connected() && self.policy == TxPolicy::Drop {
+            self.drop_frame("disconnected");
+            return;
+        }
+        match self.tx.try_send(frame) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => self.drop_frame("queue full"),
+            Err(TrySendError::Closed(_)) => self.drop_frame("closed"),
+        }
+    }
+
+    fn drop_frame(&self, reason: &str) {
+        let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        if dropped.is_power_of_two() {
+            warn!(
+                "{} TX frame dropped ({reason}), {dropped} dropped so far",
+                self.name
+            );
+        }
+    }
+}
+
+async fn supervise(
+    name: String,
+    backend: Box<dyn Backend>,
+    connected: Arc<AtomicBool>,
+    options: TxOptions,
+    mut tx_queue: mpsc::Receiver<TxRequest>,
+    reporter: Reporter,
+) {
+    let target = backend.describe();
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        match backend.open().await {
+            Ok((reader, writer)) => {
+                info!("Connected to {name} at {target}");
+                backoff = MIN_BACKOFF;
+                connected.store(true, Ordering::Relaxed);
+                let e = run_connection(reader, writer, &options.batching, &mut tx_queue, &reporter)
+                    .await;
+                connected.store(false, Ordering::Relaxed);
+                if reporter.rx.is_closed() {
+                    return;
+                }
+                warn!("{name} connection lost: {e}");
+                if options.policy == TxPolicy::Drop {
+                    while tx_queue.try_recv().is_ok() {}
+                }
+            }
+            Err(e) => warn!("Connection to {name} at {target} failed: {e}"),
+        }
+        info!("Reconnecting to {name} in {backoff:?}");
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Pump frames in both directions until the connection fails.
+///
+/// The reader runs in a task of its own, so a stalled write never holds back received frames.
+async fn run_connection(
+    reader: Box<dyn BackendReader>,
+    mut writer: Box<dyn BackendWriter>,
+    batching: &Batching,
+    tx_queue: &mut mpsc::Receiver<TxRequest>,
+    reporter: &Reporter,
+) -> io::Error {
+    let mut reader = tokio::spawn(read_frames(reader, reporter.clone()));
+    let writer = async {
+        let mut batch = Vec::new();
+        while batching
+            .recv(tx_queue, |_| CANET_RECORD_LEN, &mut batch)
+            .await
+        {
+            let (messages, clients): (Vec<_>, Vec<_>) =
+                batch.drain(..).map(|r| (r.message, r.client)).unzip();
+            if let Err(e) = writer.write_messages(&messages).await {
+                return e;
+            }
+            let at = Instant::now();
+            for (message, client) in messages.into_iter().zip(clients) {
+                if let Err(e) = reporter
+                    .report(message, Origin::Transmitted(client), at)
+                    .await
+                {
+                    return e;
+                }
+            }
+        }
+        io::Error::other("bridge shut down")
+    };
+    let e = tokio::select! {
+        e = &mut reader => e.unwrap_or_else(io::Error::other),
+        e = writer => e,
+    };
+    reader.abort();
+    e
+}
+
+/// Stamp and forward received frames until the connection fails
+async fn read_frames(mut reader: Box<dyn BackendReader>, reporter: Reporter) -> io::Error {
+    loop {
+        match reader.read_message(reporter.bus).await {
+            Ok(message) => {
+                if let Err(e) = reporter.report(message, Origin::Bus, Instant::now()).await {
+                    return e;
+                }
+            }
+            Err(UsrError::Io(e)) => return e,
+            Err(e) => warn!("Dropping malformed frame: {e}"),
+        }
+    }
+}
@@ -0,0 +1,261 @@
+//! The `replay` subcommand, sending the frames of a log file to a CANET with their original timing.
+
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, bail};
+use clap::ArgMatches;
+use log::{info, warn};
+use tokio::time::{Instant, sleep_until};
+
+use crate::{
+    backend::{Backend, BackendWriter, CanetTcp, CanetUdp, Transport},
+    candump,
+    config::FilterConfig,
+    convert::{Input, format_of, next_record},
+    trace::{Record, TraceFormat},
+    usr_canet::{CAN_EXT_ID_MASK, Message, UsrError, convert_to_canet},
+};
+
+/// Frames of the log a `--map` sends to another bus
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum MapFrom {
+    /// A bus as the log numbers it
+    Bus(u8),
+    /// An interface of the log, e.g. `CAN2` or `can1`
+    Interface(String),
+}
+
+impl MapFrom {
+    /// Whether the frames of `bus`, named by `interfaces` of the log, are meant
+    fn matches(&self, bus: u8, interfaces: &[String]) -> bool {
+        match self {
+            Self::Bus(from) => *from == bus,
+            Self::Interface(name) => match interfaces.get(bus as usize) {
+                Some(interface) => interface == name,
+                // Logs without names number their buses like the bridge names them
+                None => candump::interface_bus(name) == Some(bus),
+            },
+        }
+    }
+}
+
+/// Parse a `FROM=TO` bus mapping given on the command line, FROM a bus or an interface name
+pub(crate) fn parse_bus_map(arg: &str) -> Result<(MapFrom, u8), String> {
+    let (from, to) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected FROM=TO, e.g. CAN2=0 or 1=0, got {arg:?}"))?;
+    let from = match from.parse() {
+        Ok(bus) => MapFrom::Bus(bus),
+        Err(_) if !from.is_empty() => MapFrom::Interface(from.to_string()),
+        Err(e) => return Err(format!("bad bus {from:?}: {e}")),
+    };
+    let to = to.parse().map_err(|e| format!("bad bus {to:?}: {e}"))?;
+    Ok((from, to))
+}
+
+/// Parse an `ID[/MASK]` filter in hex given on the command line
+pub(crate) fn parse_id_filter(arg: &str) -> Result<FilterConfig, String> {
+    let hex = |value: &str| {
+        let digits = value.trim_start_matches("0x").trim_start_matches("0X");
+        u32::from_str_radix(digits, 16).map_err(|e| format!("bad hex {value:?}: {e}"))
+    };
+    let (id, mask) = match arg.split_once('/') {
+        Some((id, mask)) => (hex(id)?, hex(mask)?),
+        None => (hex(arg)?, CAN_EXT_ID_MASK),
+    };
+    if id > CAN_EXT_ID_MASK {
+        return Err(format!("{id:#x} is not a valid CAN id"));
+    }
+    Ok(FilterConfig {
+        bus: None,
+        id,
+        mask,
+    })
+}
+
+/// Parse a replay speed, a positive factor
+pub(crate) fn parse_speed(arg: &str) -> Result<f64, String> {
+    match arg.parse::<f64>() {
+        Ok(speed) if speed.is_finite() && speed > 0.0 => Ok(speed),
+        _ => Err(format!(
+            "expected a factor above 0, e.g. 2 or 0.5, got {arg:?}"
+        )),
+    }
+}
+
+/// The message moved to another bus
+fn on_bus(message: Message, bus: u8) -> Message {
+    match message {
+        Message::Data(_, frame) => Message::Data(bus, frame),
+        Message::Remote(_, frame) => Message::Remote(bus, frame),
+    }
+}
+
+/// Where the frames go
+enum Sink {
+    /// Print the CANET records, sending nothing
+    DryRun(io::StdoutLock<'static>),
+    /// The CANET ports, indexed by bus
+    Canet(Vec<Box<dyn BackendWriter>>),
+}
+
+impl Sink {
+    /// Connect to the CANET ports of `matches`
+    async fn connect(matches: &ArgMatches) -> anyhow::Result<Self> {
+        let host = matches.get_one::<String>("ip").unwrap();
+        let transport = *matches.get_one::<Transport>("transport").unwrap();
+        let ports = ["port1", "port2"].map(|port| matches.get_one::<u16>(port).copied());
+        let mut writers = Vec::new();
+        for (bus, port) in ports.into_iter().flatten().enumerate() {
+            let addr = format!("{host}:{port}");
+            let backend: Box<dyn Backend> = match transport {
+                Transport::Tcp => Box::new(CanetTcp::new(addr, true)),
+                Transport::Udp => Box::new(CanetUdp::new(addr, port)),
+            };
+            let (mut reader, writer) = backend
+                .open()
+                .await
+                .with_context(|| format!("Cannot connect to {}", backend.describe()))?;
+            info!("Connected to {}", backend.describe());
+            // Nothing is done with frames from the bus, but they must not pile up at the device
+            tokio::spawn(async move {
+                while !matches!(reader.read_message(bus as u8).await, Err(UsrError::Io(_))) {}
+            });
+            writers.push(writer);
+        }
+        Ok(Self::Canet(writers))
+    }
+
+    /// Whether frames of `bus` have somewhere to go
+    fn has_bus(&self, bus: u8) -> bool {
+        match self {
+            Self::DryRun(_) => true,
+            Self::Canet(writers) => (bus as usize) < writers.len(),
+        }
+    }
+
+    /// Send a frame scheduled `offset` seconds into the pass
+    async fn send(&mut self, message: &Message, offset: f64) -> io::Result<()> {
+        match self {
+            Self::DryRun(out) => {
+                let mut line = format!("{offset:12.6} {}", message.bus());
+                for byte in convert_to_canet(message) {
+                    write!(line, " {byte:02X}").unwrap();
+                }
+                writeln!(out, "{line}")
+            }
+            Self::Canet(writers) => writers[message.bus() as usize].write_message(message).await,
+        }
+    }
+}
+
+/// What to replay and how
+struct Replay<'a> {
+    path: &'a Path,
+    speed: f64,
+    map: Vec<(MapFrom, u8)>,
+    filters: Vec<FilterConfig>,
+    /// Buses without a port that were already warned about
+    skipped: [bool; 256],
+}
+
+impl Replay<'_> {
+    /// The frame as it goes out, if it is to go out at all. `interfaces` names the buses of the
+    /// log as far as it tells.
+    fn frame(&mut self, record: Record, interfaces: &[String], sink: &Sink) -> Option<Message> {
+        let message = record.message;
+        if !self.filters.is_empty() && !self.filters.iter().any(|f| f.matches(0, message.id())) {
+            return None;
+        }
+        let message = match self
+            .map
+            .iter()
+            .find(|(from, _)| from.matches(message.bus(), interfaces))
+        {
+            Some(&(_, to)) => on_bus(message, to),
+            None => message,
+        };
+        let bus = message.bus();
+        if !sink.has_bus(bus) {
+            if !std::mem::replace(&mut self.skipped[bus as usize], true) {
+                warn!("No CANET port for bus {bus}, skipping its frames");
+            }
+            return None;
+        }
+        Some(message)
+    }
+
+    /// Replay the file once, returning the number of frames sent
+    async fn pass(&mut self, format: TraceFormat, sink: &mut Sink) -> anyhow::Result<u64> {
+        let reading = || format!("cannot read {}", self.path.display());
+        let mut input = Input::open(self.path, format).with_context(reading)?;
+        let dry_run = matches!(sink, Sink::DryRun(_));
+        let started = Instant::now();
+        let mut first = None;
+        let mut frames = 0;
+        while let Some(record) = next_record(&mut input, self.path).with_context(reading)? {
+            let first = *first.get_or_insert(record.time);
+            let offset = record.time.saturating_sub(first).div_f64(self.speed);
+            let Some(message) = self.frame(record, input.interfaces(), sink) else {
+                continue;
+            };
+            let due = started + offset;
+            if !dry_run && due > Instant::now() {
+                sleep_until(due).await;
+            }
+            sink.send(&message, offset.as_secs_f64())
+                .await
+                .context("Cannot send")?;
+            frames += 1;
+        }
+        if first.is_none() {
+            bail!("{}: no CAN frames", self.path.display());
+        }
+        Ok(frames)
+    }
+}
+
+/// `replay FILE [IP PORT1]`
+pub(crate) async fn run(matches: &ArgMatches) -> anyhow::Result<()> {
+    let path = matches.get_one::<PathBuf>("file").unwrap();
+    let format = format_of(path, matches.get_one("from").copied())?;
+    let mut sink = if matches.get_flag("dry-run") {
+        Sink::DryRun(io::stdout().lock())
+    } else {
+        Sink::connect(matches).await?
+    };
+    let mut replay = Replay {
+        path,
+        speed: *matches.get_one::<f64>("speed").unwrap(),
+        map: matches
+            .get_many::<(MapFrom, u8)>("map")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        filters: matches
+            .get_many::<FilterConfig>("id")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        skipped: [false; 256],
+    };
+    // Once by default, forever for `--loop` without a count
+    let passes = matches.get_one::<u32>("loop").copied().unwrap_or(1);
+    let mut frames = 0;
+    let mut pass = 0;
+    while passes == 0 || pass < passes {
+        frames += replay.pass(format, &mut sink).await?;
+        pass += 1;
+    }
+    let times = if pass > 1 {
+        format!(" {pass} times")
+    } else {
+        String::new()
+    };
+    info!("Replayed {frames} frames from {}{times}", path.display());
+    Ok(())
+}
@@ -0,0 +1,57 @@
+//! Maps the bus number of a GVRET frame to the backend port that transmits it.
+//!
+//! GVRET carries the bus in a full byte, so clients can address any bus from 0 to 255 while the
+//! bridge only has as many as it has ports. Frames for other buses are counted and dropped.
+
+use std::{
+    ops::Deref,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use log::warn;
+
+use crate::usr_canet::Message;
+
+/// Ports indexed by bus number: the first port is bus 0, the next one bus 1 and so on
+#[derive(Debug, Default)]
+pub struct Router<T> {
+    ports: Vec<T>,
+    unroutable: AtomicU64,
+}
+
+impl<T> Router<T> {
+    pub fn new(ports: Vec<T>) -> Self {
+        Self {
+            ports,
+            unroutable: AtomicU64::new(0),
+        }
+    }
+
+    /// The port for `message`'s bus, or `None` if there is no such bus
+    pub fn route(&self, message: &Message) -> Option<&T> {
+        let port = self.ports.get(message.bus() as usize);
+        if port.is_none() {
+            let unroutable = self.unroutable.fetch_add(1, Ordering::Relaxed) + 1;
+            if unroutable.is_power_of_two() {
+                warn!(
+                    "No port for bus {}, {unroutable} frames for missing buses dropped so far",
+                    message.bus()
+                );
+            }
+        }
+        port
+    }
+
+    /// Frames dropped because their bus does not exist
+    pub fn unroutable(&self) -> u64 {
+        self.unroutable.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Deref for Router<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        &self.ports
+    }
+}
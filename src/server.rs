@@ -0,0 +1,335 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use clap::ValueEnum;
+use futures::StreamExt;
+use log::{error, info, warn};
+use serde::Deserialize;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot,
+    },
+    time::Duration,
+};
+use tokio_util::codec::FramedRead;
+
+use crate::{
+    batch::Batching,
+    clock::Clock,
+    gvret::{Gvret, handle_command},
+    gvret_proto::GvretCodec,
+    port::{Port, TxRequest},
+    router::Router,
+};
+
+/// Number of encoded GVRET frames buffered per client before frames are dropped for that client
+pub(crate) const CLIENT_QUEUE_DEPTH: usize = 1024;
+
+pub(crate) type ClientId = u64;
+
+/// Which GVRET clients see frames transmitted on a bus, like GVRET hardware reports its own
+/// transmissions
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TxEcho {
+    /// Transmitted frames are not reported
+    #[default]
+    None,
+    /// Only the client that sent the frame
+    Sender,
+    /// Every client
+    All,
+}
+
+/// What happens when a GVRET client's queue is full
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ClientOverflow {
+    /// Drop the frames the client has no room for and keep the session
+    #[default]
+    Drop,
+    /// End the session, the client reconnects with an empty queue
+    Disconnect,
+}
+
+struct Client {
+    addr: SocketAddr,
+    tx: mpsc::Sender<Vec<u8>>,
+    dropped: u64,
+    /// Ends the session on overflow with [`ClientOverflow::Disconnect`]
+    kick: Option<oneshot::Sender<()>>,
+}
+
+impl Client {
+    fn queue(&mut self, frame: &[u8], overflow: ClientOverflow) {
+        match self.tx.try_send(frame.to_vec()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.dropped += 1;
+                match overflow {
+                    ClientOverflow::Drop if self.dropped.is_power_of_two() => warn!(
+                        "GVRET client {} is too slow, {} frames dropped",
+                        self.addr, self.dropped
+                    ),
+                    ClientOverflow::Drop => {}
+                    ClientOverflow::Disconnect => {
+                        if let Some(kick) = self.kick.take() {
+                            let _ = kick.send(());
+                        }
+                    }
+                }
+            }
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+}
+
+/// The session's ends of a registered client's queues
+struct Registration {
+    id: ClientId,
+    /// Replies to the client's own commands, queued with the frames
+    replies: mpsc::Sender<Vec<u8>>,
+    queue: mpsc::Receiver<Vec<u8>>,
+    /// Resolves when the client is disconnected for falling behind
+    kicked: oneshot::Receiver<()>,
+}
+
+#[derive(Default)]
+struct HubInner {
+    next_id: ClientId,
+    clients: HashMap<ClientId, Client>,
+}
+
+/// Registry of connected GVRET clients, each with its own bounded outbound queue.
+///
+/// A client that cannot keep up loses frames instead of stalling the bus or the other clients.
+#[derive(Clone)]
+pub(crate) struct Hub {
+    inner: Arc<Mutex<HubInner>>,
+    overflow: ClientOverflow,
+}
+
+impl Hub {
+    pub(crate) fn new(overflow: ClientOverflow) -> Self {
+        Self {
+            inner: Arc::default(),
+            overflow,
+        }
+    }
+
+    fn register(&self, addr: SocketAddr) -> Registration {
+        let (tx, queue) = mpsc::channel(CLIENT_QUEUE_DEPTH);
+        let (kick, kicked) = oneshot::channel();
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.clients.insert(
+            id,
+            Client {
+                addr,
+                tx: tx.clone(),
+                dropped: 0,
+                kick: Some(kick),
+            },
+        );
+        Registration {
+            id,
+            replies: tx,
+            queue,
+            kicked,
+        }
+    }
+
+    /// Forget a client, returning the number of frames it missed
+    fn remove(&self, id: ClientId) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        inner.clients.remove(&id).map_or(0, |c| c.dropped)
+    }
+
+    /// Queue an encoded frame for every connected client
+    pub(crate) fn broadcast(&self, frame: &[u8]) {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .clients
+            .values_mut()
+            .for_each(|c| c.queue(frame, self.overflow));
+    }
+
+    /// Queue an encoded frame for one client, if it is still connected
+    pub(crate) fn send_to(&self, id: ClientId, frame: &[u8]) {
+        if let Some(client) = self.inner.lock().unwrap().clients.get_mut(&id) {
+            client.queue(frame, self.overflow);
+        }
+    }
+}
+
+/// State shared by all GVRET client sessions
+#[derive(Clone)]
+pub(crate) struct Shared {
+    pub(crate) hub: Hub,
+    /// Frames requested for transmission by any client
+    pub(crate) tx_requests: mpsc::Sender<TxRequest>,
+    pub(crate) ports: Arc<Router<Port>>,
+    /// Time base of frame timestamps and `TimeSync`
+    pub(crate) clock: Clock,
+    /// Disconnect clients after this many malformed commands
+    pub(crate) max_client_errors: Option<u64>,
+    /// Coalescing of queued frames and replies into socket writes
+    pub(crate) batching: Batching,
+    /// Disable Nagle's algorithm on client connections
+    pub(crate) nodelay: bool,
+}
+
+/// Listeners handed over by systemd socket activation, following the `sd_listen_fds(3)` protocol.
+///
+/// This lets a `.socket` unit bind privileged ports such as 23 while the bridge runs unprivileged.
+#[cfg(unix)]
+pub(crate) fn activated_listeners() -> io::Result<Vec<TcpListener>> {
+    use std::os::fd::FromRawFd;
+
+    /// First file descriptor passed by systemd
+    const SD_LISTEN_FDS_START: i32 = 3;
+
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id());
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<i32>().ok())
+        .unwrap_or(0);
+    if !for_us {
+        return Ok(Vec::new());
+    }
+
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(|fd| {
+            // SAFETY: systemd passes ownership of these descriptors to this process
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener)
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn activated_listeners() -> io::Result<Vec<TcpListener>> {
+    Ok(Vec::new())
+}
+
+pub(crate) async fn accept_clients(listener: TcpListener, shared: Shared) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                info!("Accepted gvret client from {addr}");
+                tokio::spawn(serve_client(stream, addr, shared.clone()));
+            }
+            Err(e) => {
+                // Typically fd exhaustion, back off instead of spinning
+                error!("GVRET accept error {e}");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+/// Run one GVRET session until the client goes away, then release its resources.
+///
+/// Every session starts with a fresh handshake; the CANET side is unaffected by clients coming and going.
+async fn serve_client(stream: TcpStream, addr: SocketAddr, shared: Shared) {
+    if let Err(e) = stream.set_nodelay(shared.nodelay) {
+        warn!("GVRET client {addr} cannot set TCP_NODELAY: {e}");
+    }
+    let (gvret_r, mut gvret_w) = stream.into_split();
+    let Registration {
+        id,
+        replies,
+        mut queue,
+        kicked,
+    } = shared.hub.register(addr);
+
+    let batching = shared.batching;
+    let mut writer = tokio::spawn(async move {
+        let mut batch = Vec::new();
+        let mut bytes = Vec::with_capacity(batching.max_bytes);
+        while batching.recv(&mut queue, Vec::len, &mut batch).await {
+            bytes.clear();
+            batch.drain(..).for_each(|b| bytes.extend(b));
+            gvret_w.write_all(&bytes).await?;
+        }
+        Ok::<_, io::Error>(())
+    });
+
+    let mut errors = 0_u64;
+    let reader = async {
+        let mut commands = FramedRead::new(gvret_r, GvretCodec::new());
+        while let Some(command) = commands.next().await {
+            let command = match command? {
+                Ok(command) => command,
+                Err(e) => {
+                    errors += 1;
+                    if errors.is_power_of_two() {
+                        warn!("GVRET client {addr} sent garbage ({e}), {errors} errors so far");
+                    }
+                    if shared.max_client_errors.is_some_and(|max| errors >= max) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("{errors} malformed commands"),
+                        ));
+                    }
+                    continue;
+                }
+            };
+            let delivered = match handle_command(command, &shared.ports, &shared.clock) {
+                Some(Gvret::Frame(message)) => {
+                    let request = TxRequest {
+                        message,
+                        client: Some(id),
+                    };
+                    shared.tx_requests.send(request).await.is_ok()
+                }
+                Some(Gvret::Reply(b)) => replies.send(b).await.is_ok(),
+                Some(Gvret::Inject(b)) => {
+                    shared.hub.broadcast(&b);
+                    true
+                }
+                None => true,
+            };
+            if !delivered {
+                return Ok(());
+            }
+        }
+        Err(io::ErrorKind::UnexpectedEof.into())
+    };
+
+    let result = tokio::select! {
+        result = reader => result,
+        result = &mut writer => result.unwrap_or(Ok(())),
+        Ok(()) = kicked => Err(io::Error::other(format!(
+            "too slow, more than {CLIENT_QUEUE_DEPTH} frames queued"
+        ))),
+    };
+    let dropped = shared.hub.remove(id);
+    writer.abort();
+
+    if errors > 0 {
+        info!("GVRET client {addr} sent {errors} malformed commands");
+    }
+    if dropped > 0 {
+        info!("GVRET client {addr} missed {dropped} frames");
+    }
+    match result {
+        Ok(()) => info!("GVRET client {addr} session ended"),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            info!("GVRET client {addr} disconnected")
+        }
+        Err(e) => warn!("GVRET client {addr} dropped: {e}"),
+    }
+}
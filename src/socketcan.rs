@@ -0,0 +1,187 @@
+//! Linux SocketCAN backend, e.g. `can0` or a `vcan0` for testing:
+//!
+//! ```sh
+//! sudo ip link add dev vcan0 type vcan && sudo ip link set up vcan0
+//! ```
+
+use std::{
+    ffi::CString,
+    io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use tokio::io::{Interest, unix::AsyncFd};
+
+use crate::{
+    backend::{Backend, BackendReader, BackendWriter},
+    usr_canet::{CAN_MAX_DLC, Message, UsrError},
+};
+
+/// A raw CAN socket bound to one interface
+pub(crate) struct CanSocket {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl CanSocket {
+    pub(crate) fn open(interface: &str) -> io::Result<Self> {
+        let name = CString::new(interface)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "bad interface name"))?;
+        // SAFETY: plain libc calls, the descriptor is owned by `OwnedFd` as soon as it is created
+        unsafe {
+            let ifindex = libc::if_nametoindex(name.as_ptr());
+            if ifindex == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let fd = libc::socket(
+                libc::PF_CAN,
+                libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                libc::CAN_RAW,
+            );
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let fd = OwnedFd::from_raw_fd(fd);
+
+            let mut addr: libc::sockaddr_can = mem::zeroed();
+            addr.can_family = libc::AF_CAN as libc::sa_family_t;
+            addr.can_ifindex = ifindex as libc::c_int;
+            if libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const _ as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_can>() as libc::socklen_t,
+            ) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self {
+                fd: AsyncFd::new(fd)?,
+            })
+        }
+    }
+
+    /// Receive the next data or remote frame, error frames are skipped
+    pub(crate) async fn recv(&self, bus: u8) -> Result<Message, UsrError> {
+        loop {
+            // SAFETY: an all zero can_frame is valid
+            let mut frame: libc::can_frame = unsafe { mem::zeroed() };
+            let n = self
+                .fd
+                .async_io(Interest::READABLE, |fd| {
+                    // SAFETY: reads at most size_of::<can_frame>() bytes into `frame`
+                    let n = unsafe {
+                        libc::read(
+                            fd.as_raw_fd(),
+                            &mut frame as *mut _ as *mut libc::c_void,
+                            mem::size_of::<libc::can_frame>(),
+                        )
+                    };
+                    if n < 0 {
+                        Err(io::Error::last_os_error())
+                    } else {
+                        Ok(n as usize)
+                    }
+                })
+                .await?;
+            if n != mem::size_of::<libc::can_frame>() || frame.can_id & libc::CAN_ERR_FLAG != 0 {
+                continue;
+            }
+            return frame_to_message(&frame, bus);
+        }
+    }
+
+    pub(crate) async fn send(&self, message: &Message) -> io::Result<()> {
+        let frame = message_to_frame(message);
+        self.fd
+            .async_io(Interest::WRITABLE, |fd| {
+                // SAFETY: writes exactly one can_frame
+                let n = unsafe {
+                    libc::write(
+                        fd.as_raw_fd(),
+                        &frame as *const _ as *const libc::c_void,
+                        mem::size_of::<libc::can_frame>(),
+                    )
+                };
+                if n < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(())
+                }
+            })
+            .await
+    }
+}
+
+fn frame_to_message(frame: &libc::can_frame, bus: u8) -> Result<Message, UsrError> {
+    let ext_id = frame.can_id & libc::CAN_EFF_FLAG != 0;
+    let id = if ext_id {
+        frame.can_id & libc::CAN_EFF_MASK
+    } else {
+        frame.can_id & libc::CAN_SFF_MASK
+    };
+    let dlc = frame.can_dlc.min(CAN_MAX_DLC as u8);
+    let message = if frame.can_id & libc::CAN_RTR_FLAG != 0 {
+        Message::new_remote(bus, id, ext_id, dlc)?
+    } else {
+        Message::new_data(bus, id, ext_id, &frame.data[..dlc as usize])?
+    };
+    Ok(message)
+}
+
+fn message_to_frame(message: &Message) -> libc::can_frame {
+    // SAFETY: an all zero can_frame is valid
+    let mut frame: libc::can_frame = unsafe { mem::zeroed() };
+    frame.can_id = message.id();
+    if message.ext_id() {
+        frame.can_id |= libc::CAN_EFF_FLAG;
+    }
+    frame.can_dlc = message.dlc();
+    match message.data() {
+        Some(data) => frame.data[..data.len()].copy_from_slice(data),
+        None => frame.can_id |= libc::CAN_RTR_FLAG,
+    }
+    frame
+}
+
+/// A SocketCAN interface as bridge backend
+pub(crate) struct SocketCan {
+    interface: String,
+}
+
+impl SocketCan {
+    pub(crate) fn new(interface: String) -> Self {
+        Self { interface }
+    }
+}
+
+struct SharedSocket(Arc<CanSocket>);
+
+#[async_trait]
+impl Backend for SocketCan {
+    fn describe(&self) -> String {
+        self.interface.clone()
+    }
+
+    async fn open(&self) -> io::Result<(Box<dyn BackendReader>, Box<dyn BackendWriter>)> {
+        let socket = Arc::new(CanSocket::open(&self.interface)?);
+        Ok((
+            Box::new(SharedSocket(socket.clone())),
+            Box::new(SharedSocket(socket)),
+        ))
+    }
+}
+
+#[async_trait]
+impl BackendReader for SharedSocket {
+    async fn read_message(&mut self, bus: u8) -> Result<Message, UsrError> {
+        self.0.recv(bus).await
+    }
+}
+
+#[async_trait]
+impl BackendWriter for SharedSocket {
+    async fn write_message(&mut self, message: &Message) -> io::Result<()> {
+        self.0.send(message).await
+    }
+}
@@ -0,0 +1,160 @@
+//! Frames recorded to log files and read back from them.
+//!
+//! [`candump`](crate::candump) is the text format of Linux can-utils, [`asc`](crate::asc) and
+//! [`blf`](crate::blf) are the Vector ASCII and binary logging formats of CANalyzer and CANoe,
+//! [`pcapng`](crate::pcapng) is the capture format of Wireshark and [`gvret_csv`](crate::gvret_csv)
+//! the CSV log of SavvyCAN. Each has a writer implementing [`TraceWriter`] and a reader iterating
+//! over [`Record`]s. Buses are numbered from 0 as in [`Message::bus`], which is channel 1 in Vector
+//! logs.
+
+use std::{io, path::Path, time::Duration};
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::usr_canet::Message;
+
+/// Which way a frame crossed the bridge
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Received from the bus
+    Rx,
+    /// Transmitted on the bus
+    Tx,
+}
+
+/// A logged frame
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// Time since the Unix epoch
+    pub time: Duration,
+    pub message: Message,
+    /// Which way the frame went, if the log tells
+    pub direction: Option<Direction>,
+}
+
+/// A log file being written
+pub trait TraceWriter: Send {
+    fn write(&mut self, record: &Record) -> io::Result<()>;
+
+    /// Hand buffered records to the underlying writer, so the file is readable up to here even if
+    /// the process dies
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// Complete the file. Writers also do this when dropped, ignoring errors.
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+/// Log file formats
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceFormat {
+    /// `candump -l` text, `.log`
+    Candump,
+    /// Vector ASCII, `.asc`
+    Asc,
+    /// Vector binary logging format, `.blf`
+    Blf,
+    /// Wireshark capture with SocketCAN packets, `.pcapng`
+    Pcapng,
+    /// SavvyCAN GVRET CSV, `.csv`
+    #[value(name = "gvret-csv")]
+    #[serde(rename = "gvret-csv")]
+    GvretCsv,
+}
+
+impl TraceFormat {
+    /// The format a file name's extension stands for, looking through a trailing `.gz`
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let path = match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("gz") => path.file_stem().map(Path::new)?,
+            _ => path,
+        };
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "log" => Some(Self::Candump),
+            "asc" => Some(Self::Asc),
+            "blf" => Some(Self::Blf),
+            "pcapng" => Some(Self::Pcapng),
+            "csv" => Some(Self::GvretCsv),
+            _ => None,
+        }
+    }
+
+    /// Whether files of the format may be appended to, rather than holding a single measurement
+    pub fn appendable(self) -> bool {
+        matches!(self, Self::Candump | Self::Pcapng)
+    }
+}
+
+/// Whether a file name ends in `.gz`
+pub fn is_gzip(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("gz"))
+}
+
+/// A calendar date and time of day in UTC
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UtcTime {
+    pub year: u32,
+    /// 1 to 12
+    pub month: u32,
+    /// 1 to 31
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+/// English month abbreviations as used by Vector logs
+pub const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+/// English weekday abbreviations, from Sunday
+pub const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+impl UtcTime {
+    /// The date of `secs` since the Unix epoch, after Howard Hinnant's `civil_from_days`
+    pub fn from_unix(secs: u64) -> Self {
+        let (days, rem) = (secs / 86_400, secs % 86_400);
+        let z = days + 719_468;
+        let era = z / 146_097;
+        let doe = z % 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        Self {
+            year: (yoe + era * 400 + u64::from(month <= 2)) as u32,
+            month: month as u32,
+            day: (doy - (153 * mp + 2) / 5 + 1) as u32,
+            hour: (rem / 3600) as u32,
+            minute: (rem % 3600 / 60) as u32,
+            second: (rem % 60) as u32,
+        }
+    }
+
+    /// Seconds since the Unix epoch, after Howard Hinnant's `days_from_civil`. Dates before 1970
+    /// map to the epoch.
+    pub fn to_unix(&self) -> u64 {
+        let year = i64::from(self.year) - i64::from(self.month <= 2);
+        let era = year.div_euclid(400);
+        let yoe = year - era * 400;
+        let month = i64::from(self.month);
+        let mp = if month > 2 { month - 3 } else { month + 9 };
+        let doy = (153 * mp + 2) / 5 + i64::from(self.day) - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146_097 + doe - 719_468;
+        let secs = days * 86_400
+            + i64::from(self.hour) * 3600
+            + i64::from(self.minute) * 60
+            + i64::from(self.second);
+        secs.max(0) as u64
+    }
+
+    /// Day of the week, 0 for Sunday
+    pub fn weekday(&self) -> u32 {
+        // 1970-01-01 was a Thursday
+        ((self.to_unix() / 86_400 + 4) % 7) as u32
+    }
+}
@@ -1,7 +1,8 @@
 // #![allow(dead_code)]
 
 use byteorder::{BigEndian, ByteOrder};
-use log::info;
+use bytes::{Buf, BufMut, BytesMut};
+use log::trace;
 /// Original implentation - https://github.com/raffber/async-can
 /// Added dual CAN control, bus in Message
 use std::{
@@ -10,7 +11,8 @@ use std::{
     result::Result as StdResult,
 };
 use thiserror::Error;
-use tokio::{io::AsyncReadExt, net::tcp::OwnedReadHalf};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::codec::{Decoder, Encoder};
 /// Maximum value for CAN ID if extended 29-bit ID is selected
 pub const CAN_EXT_ID_MASK: u32 = 0x1FFFFFFF;
 
@@ -243,54 +245,104 @@ impl From<io::Error> for UsrError {
     }
 }
 
-pub(crate) async fn decode_canet_frame(
-    canet_socket: &mut OwnedReadHalf,
+/// Size of a CANET record, identical for TCP and UDP transport
+pub const CANET_RECORD_LEN: usize = 13;
+
+/// Read one 13-byte CANET record from any byte stream, e.g. the TCP connection to a CANET port.
+/// IO errors mean the stream is gone, other errors flag a malformed record that can be skipped.
+pub async fn decode_canet_frame<R: AsyncRead + Unpin>(
+    canet_socket: &mut R,
     bus: u8,
-) -> Option<Message> {
-    let mut buf = [0_u8; 13];
-    match canet_socket.read_exact(&mut buf).await {
-        Ok(v) => {
-            info!("Recv {v} bus={bus} data={buf:02x?}");
-            let ext_id = (buf[0] & 0x80) != 0;
-            let id = BigEndian::read_u32(&buf[1..]);
-            let dlc = buf[0] & 0xF;
-            if (buf[0] & 0x40) != 0 {
-                Message::new_remote(bus, id, ext_id, dlc).ok()
-            } else {
-                Message::new_data(bus, id, ext_id, &buf[5..5 + (dlc as usize)]).ok()
-            }
-        }
-        Err(_e) => None,
-    }
+) -> StdResult<Message, UsrError> {
+    let mut buf = [0_u8; CANET_RECORD_LEN];
+    let v = canet_socket.read_exact(&mut buf).await?;
+    trace!("Recv {v} bus={bus} data={buf:02x?}");
+    Ok(decode_canet_record(&buf, bus)?)
 }
 
-#[derive(Debug)]
-pub(crate) enum CanetMsg {
-    Can1([u8; 13]),
-    Can2([u8; 13]),
+/// Decode a 13-byte CANET record: flags and DLC, big endian id, 8 data bytes
+pub fn decode_canet_record(
+    buf: &[u8; CANET_RECORD_LEN],
+    bus: u8,
+) -> StdResult<Message, CanFrameError> {
+    let ext_id = (buf[0] & 0x80) != 0;
+    let id = BigEndian::read_u32(&buf[1..]);
+    // DLC values 9..=15 still carry 8 data bytes on classic CAN
+    let dlc = (buf[0] & 0xF).min(CAN_MAX_DLC as u8);
+    if (buf[0] & 0x40) != 0 {
+        Message::new_remote(bus, id, ext_id, dlc)
+    } else {
+        Message::new_data(bus, id, ext_id, &buf[5..5 + (dlc as usize)])
+    }
 }
 
-pub(crate) fn convert_to_canet(msg: Message) -> CanetMsg {
-    let mut buf = [0_u8; 13];
+/// Encode a message as a 13-byte CANET record. The bus is not part of the record, it selects the
+/// port the record is written to.
+pub fn convert_to_canet(msg: &Message) -> [u8; CANET_RECORD_LEN] {
+    let mut buf = [0_u8; CANET_RECORD_LEN];
     buf[0] = if msg.ext_id() { 0x80_u8 } else { 0x00 };
     buf[0] |= msg.dlc() & 0xF;
     BigEndian::write_u32(&mut buf[1..], msg.id());
-    let bus = match msg {
-        Message::Data(bus, msg) => {
+    match msg {
+        Message::Data(_, msg) => {
             buf[5..5 + msg.dlc() as usize].copy_from_slice(msg.data());
-            bus
         }
-        Message::Remote(bus, msg) => {
+        Message::Remote(_, msg) => {
             buf[0] |= 0x40;
             BigEndian::write_u32(&mut buf[1..], msg.id());
-            bus
         }
     }
-    .max(1);
+    buf
+}
+
+/// Sans-IO codec for the 13-byte records of one CANET port, for use with
+/// [`tokio_util::codec::Framed`] or directly on a [`BytesMut`] such as a UDP datagram.
+///
+/// Decoded messages are tagged with `bus`. A malformed record is consumed before its error is
+/// returned, so decoding can carry on with the next one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CanetCodec {
+    bus: u8,
+}
+
+impl CanetCodec {
+    pub fn new(bus: u8) -> Self {
+        Self { bus }
+    }
+
+    pub fn bus(&self) -> u8 {
+        self.bus
+    }
+}
+
+impl Decoder for CanetCodec {
+    type Item = Message;
+    type Error = UsrError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> StdResult<Option<Message>, UsrError> {
+        if src.len() < CANET_RECORD_LEN {
+            src.reserve(CANET_RECORD_LEN - src.len());
+            return Ok(None);
+        }
+        let mut record = [0_u8; CANET_RECORD_LEN];
+        src.copy_to_slice(&mut record);
+        Ok(Some(decode_canet_record(&record, self.bus)?))
+    }
+}
+
+impl Encoder<&Message> for CanetCodec {
+    type Error = UsrError;
+
+    fn encode(&mut self, item: &Message, dst: &mut BytesMut) -> StdResult<(), UsrError> {
+        dst.put_slice(&convert_to_canet(item));
+        Ok(())
+    }
+}
+
+impl Encoder<Message> for CanetCodec {
+    type Error = UsrError;
 
-    match bus {
-        0 => CanetMsg::Can1(buf),
-        1 => CanetMsg::Can2(buf),
-        _ => unreachable!(), // max(1)
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> StdResult<(), UsrError> {
+        self.encode(&item, dst)
     }
 }
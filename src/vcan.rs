@@ -0,0 +1,111 @@
+//! Mirror a bridge bus onto a Linux (v)CAN interface, so SocketCAN tools such as candump or
+//! python-can see the CANET traffic and can transmit through it.
+//!
+//! The interface sees every frame on the bus: those received from other nodes and those GVRET
+//! clients transmit. Frames transmitted through a mirror are not written to any mirror again, so
+//! two mirrors of one bus do not see each other's frames.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use log::{info, warn};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    time::sleep,
+};
+
+use crate::{
+    port::{MAX_BACKOFF, MIN_BACKOFF, TxRequest},
+    socketcan::CanSocket,
+    usr_canet::{Message, UsrError},
+};
+
+/// Frames buffered for the interface before they are dropped
+const MIRROR_QUEUE_DEPTH: usize = 1024;
+
+/// Handle to a task copying frames between one bus and a SocketCAN interface
+pub(crate) struct VcanMirror {
+    interface: String,
+    tx: mpsc::Sender<Message>,
+    dropped: AtomicU64,
+}
+
+impl VcanMirror {
+    /// Frames read from `interface` are sent to `tx_requests` as transmissions on `bus`
+    pub(crate) fn spawn(interface: String, bus: u8, tx_requests: mpsc::Sender<TxRequest>) -> Self {
+        let (tx, rx) = mpsc::channel(MIRROR_QUEUE_DEPTH);
+        tokio::spawn(run(interface.clone(), bus, rx, tx_requests));
+        Self {
+            interface,
+            tx,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Write a frame received from or transmitted on the bus to the interface
+    pub(crate) fn forward(&self, message: Message) {
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(message) {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped.is_power_of_two() {
+                warn!(
+                    "{} mirror queue full, {dropped} frames dropped so far",
+                    self.interface
+                );
+            }
+        }
+    }
+}
+
+async fn run(
+    interface: String,
+    bus: u8,
+    mut rx: mpsc::Receiver<Message>,
+    tx_requests: mpsc::Sender<TxRequest>,
+) {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        match CanSocket::open(&interface) {
+            Ok(socket) => {
+                info!("Mirroring bus {bus} to {interface}");
+                backoff = MIN_BACKOFF;
+                let reader = async {
+                    loop {
+                        match socket.recv(bus).await {
+                            Ok(message) => {
+                                let request = TxRequest {
+                                    message,
+                                    client: None,
+                                };
+                                if tx_requests.send(request).await.is_err() {
+                                    return None;
+                                }
+                            }
+                            Err(UsrError::Io(e)) => return Some(e),
+                            Err(e) => warn!("Dropping malformed frame from {interface}: {e}"),
+                        }
+                    }
+                };
+                let writer = async {
+                    while let Some(message) = rx.recv().await {
+                        if let Err(e) = socket.send(&message).await {
+                            return Some(e);
+                        }
+                    }
+                    None
+                };
+                let result = tokio::select! {
+                    e = reader => e,
+                    e = writer => e,
+                };
+                match result {
+                    Some(e) => warn!("{interface} mirror failed: {e}"),
+                    None => return,
+                }
+            }
+            Err(e) => warn!("Cannot open {interface} for bus {bus}: {e}"),
+        }
+        // Drop whatever arrived while the interface was unavailable
+        while rx.try_recv().is_ok() {}
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
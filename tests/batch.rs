@@ -0,0 +1,74 @@
+use std::time::Duration;
+
+use tokio::{sync::mpsc, time::Instant};
+use usr_canet_gvret::batch::Batching;
+
+fn batching(window_ms: u64, max_bytes: usize) -> Batching {
+    Batching {
+        window: Duration::from_millis(window_ms),
+        max_bytes,
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn collects_frames_within_the_window() {
+    let (tx, mut rx) = mpsc::channel(16);
+    tx.send(1).await.unwrap();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        tx.send(2).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        tx.send(3).await.unwrap();
+    });
+
+    let start = Instant::now();
+    let mut batch = vec![];
+    assert!(batching(2, 100).recv(&mut rx, |_| 13, &mut batch).await);
+    assert_eq!(batch, [1, 2]);
+    assert_eq!(start.elapsed(), Duration::from_millis(2));
+
+    batch.clear();
+    assert!(batching(2, 100).recv(&mut rx, |_| 13, &mut batch).await);
+    assert_eq!(batch, [3]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn writes_once_the_size_threshold_is_reached() {
+    let (tx, mut rx) = mpsc::channel(16);
+    for i in 0..10 {
+        tx.send(i).await.unwrap();
+    }
+
+    let start = Instant::now();
+    let mut batch = vec![];
+    assert!(batching(1000, 39).recv(&mut rx, |_| 13, &mut batch).await);
+    assert_eq!(batch, [0, 1, 2]);
+    assert_eq!(start.elapsed(), Duration::ZERO);
+}
+
+#[tokio::test(start_paused = true)]
+async fn zero_window_takes_only_queued_frames() {
+    let (tx, mut rx) = mpsc::channel(16);
+    tx.send(1).await.unwrap();
+    tx.send(2).await.unwrap();
+
+    let start = Instant::now();
+    let mut batch = vec![];
+    assert!(batching(0, 100).recv(&mut rx, |_| 13, &mut batch).await);
+    assert_eq!(batch, [1, 2]);
+    assert_eq!(start.elapsed(), Duration::ZERO);
+    drop(tx);
+}
+
+#[tokio::test(start_paused = true)]
+async fn ends_when_the_queue_is_closed() {
+    let (tx, mut rx) = mpsc::channel(16);
+    tx.send(1).await.unwrap();
+    drop(tx);
+
+    let mut batch = vec![];
+    assert!(batching(1000, 100).recv(&mut rx, |_| 13, &mut batch).await);
+    assert_eq!(batch, [1]);
+    assert!(!batching(1000, 100).recv(&mut rx, |_| 13, &mut batch).await);
+    assert_eq!(batch, [1]);
+}
@@ -0,0 +1,531 @@
+//! The bridge binary between a simulated CANET and a fake GVRET client
+
+#[allow(dead_code)]
+mod common;
+
+use std::{io::Read, time::Duration};
+
+use common::{Bridge, DEADLINE, TwoDevices};
+use tokio::{
+    io::AsyncReadExt,
+    net::TcpStream,
+    time::{sleep, timeout},
+};
+use usr_canet_gvret::{
+    asc, blf, pcapng,
+    trace::{Direction, Record},
+    usr_canet::Message,
+};
+
+#[tokio::test]
+async fn answers_handshake_queries() {
+    let bridge = Bridge::start(2).await;
+    let mut client = bridge.client().await;
+
+    assert_eq!(client.request(&[0xf1, 0x0c], 3).await, [0xf1, 0x0c, 2]);
+    assert_eq!(
+        client.request(&[0xf1, 0x07], 8).await,
+        [0xf1, 0x07, 0x6a, 0x02, 0x20, 0, 0, 0]
+    );
+    assert_eq!(
+        client.request(&[0xf1, 0x06], 12).await,
+        [0xf1, 0x06, 1, 0x20, 0xa1, 0x07, 0, 1, 0x20, 0xa1, 0x07, 0]
+    );
+    assert_eq!(
+        client.request(&[0xf1, 0x09], 4).await,
+        [0xf1, 0x09, 0xde, 0xad]
+    );
+}
+
+#[tokio::test]
+async fn forwards_received_frames_to_clients() {
+    let bridge = Bridge::start(2).await;
+    let mut client = bridge.client().await;
+    // A reply proves the session is registered before frames are emitted
+    client.request(&[0xf1, 0x09], 4).await;
+
+    let standard = Message::new_data(0, 0x123, false, &[1, 2, 3]).unwrap();
+    let extended = Message::new_data(1, 0x18FF50E5, true, &[4; 8]).unwrap();
+    bridge.canet.emit(0, &standard);
+    bridge.canet.emit(1, &extended);
+
+    // The ports are separate connections, their frames may arrive in either order
+    let mut frames = [client.frame().await, client.frame().await];
+    frames.sort_by_key(|f| f.bus);
+    let [can1, can2] = frames;
+    assert_eq!(
+        (can1.bus, can1.id, &can1.data[..]),
+        (0, 0x123, &[1, 2, 3][..])
+    );
+    assert_eq!(
+        (can2.bus, can2.id, &can2.data[..]),
+        (1, 0x18FF50E5 | 1 << 31, &[4; 8][..])
+    );
+}
+
+#[tokio::test]
+async fn transmits_client_frames_on_their_bus() {
+    let bridge = Bridge::start(2).await;
+    let mut client = bridge.client().await;
+
+    let on_can1 = Message::new_data(0, 0x7ff, false, &[0xaa]).unwrap();
+    let on_can2 = Message::new_data(1, 0x1abcdef, true, &[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+    client.transmit(&on_can2).await;
+    client.transmit(&on_can1).await;
+
+    let recv = |port| timeout(DEADLINE, bridge.canet.recv(port));
+    assert_eq!(recv(1).await.unwrap(), Some(on_can2));
+    assert_eq!(recv(0).await.unwrap(), Some(on_can1));
+}
+
+#[tokio::test]
+async fn timestamps_follow_the_clock() {
+    let bridge = Bridge::start(1).await;
+    let mut client = bridge.client().await;
+    client.request(&[0xf1, 0x09], 4).await;
+
+    let message = Message::new_data(0, 0x100, false, &[]).unwrap();
+    bridge.canet.emit(0, &message);
+    let first = client.frame().await.timestamp;
+    sleep(Duration::from_millis(200)).await;
+    let sync = client.request(&[0xf1, 0x01], 6).await;
+    let sync = u32::from_le_bytes(sync[2..].try_into().unwrap());
+    bridge.canet.emit(0, &message);
+    let second = client.frame().await.timestamp;
+
+    assert!(first < sync && sync <= second, "{first} {sync} {second}");
+    assert!(second - first >= 200_000, "{first} {second}");
+}
+
+#[tokio::test]
+async fn drops_frames_for_missing_buses() {
+    let bridge = Bridge::start(2).await;
+    let mut client = bridge.client().await;
+
+    // The GVRET header carries the bus in a full byte
+    for bus in (0..=u8::MAX).rev() {
+        let message = Message::new_data(bus, 0x200, false, &[bus]).unwrap();
+        client.transmit(&message).await;
+    }
+
+    let recv = |port| timeout(DEADLINE, bridge.canet.recv(port));
+    assert_eq!(recv(0).await.unwrap().unwrap().data(), Some(&[0][..]));
+    assert_eq!(recv(1).await.unwrap().unwrap().data(), Some(&[1][..]));
+    // Still serving the session
+    assert_eq!(client.request(&[0xf1, 0x0c], 3).await, [0xf1, 0x0c, 2]);
+    let late = timeout(Duration::from_millis(100), bridge.canet.recv(0)).await;
+    assert!(late.is_err(), "unexpected frame {late:?}");
+}
+
+/// A data frame whose standard id does not fit in 11 bits
+const BAD_FRAME: [u8; 10] = [0xf1, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x01, 0x55, 0x00];
+
+#[tokio::test]
+async fn survives_malformed_commands() {
+    let bridge = Bridge::start(1).await;
+    let mut client = bridge.client().await;
+
+    for _ in 0..100 {
+        client.send(&BAD_FRAME).await;
+        client.send(&[0x00, 0xf1, 0xee]).await;
+    }
+    let good = Message::new_data(0, 0x123, false, &[7]).unwrap();
+    client.transmit(&good).await;
+
+    assert_eq!(
+        client.request(&[0xf1, 0x09], 4).await,
+        [0xf1, 0x09, 0xde, 0xad]
+    );
+    let recv = timeout(DEADLINE, bridge.canet.recv(0)).await.unwrap();
+    assert_eq!(recv, Some(good));
+}
+
+#[tokio::test]
+async fn disconnects_clients_sending_garbage() {
+    let bridge = Bridge::start_with(1, &["--max-client-errors", "3"]).await;
+    let mut client = bridge.client().await;
+
+    for _ in 0..3 {
+        client.send(&BAD_FRAME).await;
+    }
+    client.closed().await;
+
+    // Other sessions are not affected
+    let mut other = bridge.client().await;
+    assert_eq!(other.request(&[0xf1, 0x0c], 3).await, [0xf1, 0x0c, 1]);
+}
+
+#[tokio::test]
+async fn keeps_clients_sending_checksummed_frames() {
+    let bridge = Bridge::start_with(1, &["--max-client-errors", "3"]).await;
+    let mut client = bridge.client().await;
+
+    // As SavvyCAN sends them, with a checksum byte after the data
+    for data in 0..10 {
+        client
+            .send(&[0xf1, 0x00, 0x23, 0x01, 0x00, 0x00, 0x00, 0x01, data, 0x00])
+            .await;
+    }
+
+    for data in 0..10 {
+        let recv = timeout(DEADLINE, bridge.canet.recv(0)).await.unwrap();
+        assert_eq!(
+            recv,
+            Some(Message::new_data(0, 0x123, false, &[data]).unwrap())
+        );
+    }
+    assert_eq!(
+        client.request(&[0xf1, 0x09], 4).await,
+        [0xf1, 0x09, 0xde, 0xad]
+    );
+}
+
+/// Send a keep-alive and read 4 bytes, which are its reply only if no frame was queued before it
+async fn next_reply(client: &mut common::GvretClient) -> Vec<u8> {
+    client.request(&[0xf1, 0x09], 4).await
+}
+
+#[tokio::test]
+async fn does_not_echo_by_default() {
+    let bridge = Bridge::start(1).await;
+    let mut client = bridge.client().await;
+
+    let message = Message::new_data(0, 0x321, false, &[1]).unwrap();
+    client.transmit(&message).await;
+    timeout(DEADLINE, bridge.canet.recv(0)).await.unwrap();
+
+    assert_eq!(next_reply(&mut client).await, [0xf1, 0x09, 0xde, 0xad]);
+}
+
+#[tokio::test]
+async fn echoes_transmitted_frames_to_the_sender() {
+    let bridge = Bridge::start_with(2, &["--tx-echo", "sender"]).await;
+    let mut sender = bridge.client().await;
+    let mut other = bridge.client().await;
+    next_reply(&mut other).await;
+
+    let sync = sender.request(&[0xf1, 0x01], 6).await;
+    let sync = u32::from_le_bytes(sync[2..].try_into().unwrap());
+    let message = Message::new_data(1, 0x1abcdef, true, &[9, 8, 7]).unwrap();
+    sender.transmit(&message).await;
+
+    let echo = sender.frame().await;
+    assert_eq!(
+        (echo.bus, echo.id, &echo.data[..]),
+        (1, 0x1abcdef | 1 << 31, &[9, 8, 7][..])
+    );
+    assert!(echo.timestamp >= sync, "{sync} {}", echo.timestamp);
+    assert_eq!(
+        timeout(DEADLINE, bridge.canet.recv(1)).await.unwrap(),
+        Some(message)
+    );
+    assert_eq!(next_reply(&mut other).await, [0xf1, 0x09, 0xde, 0xad]);
+}
+
+#[tokio::test]
+async fn echoes_transmitted_frames_to_all_clients() {
+    let bridge = Bridge::start_with(1, &["--tx-echo", "all"]).await;
+    let mut sender = bridge.client().await;
+    let mut other = bridge.client().await;
+    next_reply(&mut other).await;
+
+    let message = Message::new_data(0, 0x7ff, false, &[0xaa]).unwrap();
+    sender.transmit(&message).await;
+
+    for client in [&mut sender, &mut other] {
+        let echo = client.frame().await;
+        assert_eq!((echo.bus, echo.id, &echo.data[..]), (0, 0x7ff, &[0xaa][..]));
+    }
+}
+
+#[tokio::test]
+async fn injects_echo_commands_without_transmitting() {
+    let bridge = Bridge::start(2).await;
+    let mut sender = bridge.client().await;
+    let mut other = bridge.client().await;
+    next_reply(&mut other).await;
+
+    let message = Message::new_data(1, 0x456, false, &[1, 2]).unwrap();
+    sender.echo(&message).await;
+
+    for client in [&mut sender, &mut other] {
+        let echo = client.frame().await;
+        assert_eq!((echo.bus, echo.id, &echo.data[..]), (1, 0x456, &[1, 2][..]));
+    }
+    let sent = timeout(Duration::from_millis(100), bridge.canet.recv(1)).await;
+    assert!(sent.is_err(), "unexpected frame {sent:?}");
+}
+
+#[tokio::test]
+async fn captures_traffic_in_candump_format() {
+    let path = std::env::temp_dir().join(format!("usr-canet-gvret-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let bridge = Bridge::start_with(2, &["--capture", path.to_str().unwrap()]).await;
+    let mut client = bridge.client().await;
+    client.request(&[0xf1, 0x09], 4).await;
+
+    let received = Message::new_data(1, 0x18FF50E5, true, &[1, 2]).unwrap();
+    bridge.canet.emit(1, &received);
+    client.frame().await;
+    let transmitted = Message::new_data(0, 0x123, false, &[0xab]).unwrap();
+    client.transmit(&transmitted).await;
+    timeout(DEADLINE, bridge.canet.recv(0)).await.unwrap();
+
+    let mut lines = vec![];
+    for _ in 0..100 {
+        lines = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|l| l.split_once(' ').unwrap().1.to_string())
+            .collect();
+        if lines.len() == 2 {
+            break;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(lines, ["CAN2 18FF50E5#0102 R", "CAN1 123#AB T"]);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn completes_the_capture_on_sigterm() {
+    let path = std::env::temp_dir().join(format!("usr-canet-gvret-{}.log.gz", std::process::id()));
+    let config = path.with_extension("toml");
+    let _ = std::fs::remove_file(&path);
+    std::fs::write(
+        &config,
+        format!(
+            "[capture]\nfile = {:?}\ngzip = true\n",
+            path.to_str().unwrap()
+        ),
+    )
+    .unwrap();
+    let bridge = Bridge::start_with(2, &["--config", config.to_str().unwrap()]).await;
+    std::fs::remove_file(config).unwrap();
+    let mut client = bridge.client().await;
+    client.request(&[0xf1, 0x09], 4).await;
+    let received = Message::new_data(1, 0x18FF50E5, true, &[1, 2]).unwrap();
+    bridge.canet.emit(1, &received);
+    client.frame().await;
+
+    // Stopped well within the flush interval, so only finishing the capture writes the frame
+    assert!(bridge.stop().await.success());
+    let mut log = String::new();
+    let file = std::fs::File::open(&path).unwrap();
+    // A missing gzip trailer fails the read
+    flate2::read::GzDecoder::new(file)
+        .read_to_string(&mut log)
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let lines: Vec<_> = log.lines().map(|l| l.split_once(' ').unwrap().1).collect();
+    assert_eq!(lines, ["CAN2 18FF50E5#0102 R"]);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn names_the_buses_of_several_devices_apart() {
+    let path = std::env::temp_dir().join(format!("usr-canet-gvret-{}-two.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let devices =
+        TwoDevices::start(&format!("[capture]\nfile = {:?}\n", path.to_str().unwrap())).await;
+    let mut client = devices.bridge.client().await;
+    client.request(&[0xf1, 0x09], 4).await;
+    devices
+        .bridge
+        .canet
+        .emit(0, &Message::new_data(0, 0x100, false, &[1]).unwrap());
+    client.frame().await;
+    devices
+        .second
+        .emit(0, &Message::new_data(0, 0x300, false, &[3]).unwrap());
+    client.frame().await;
+    assert!(devices.bridge.stop().await.success());
+
+    let log = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let lines: Vec<_> = log.lines().map(|l| l.split_once(' ').unwrap().1).collect();
+    // The first port of the second device is the third bus
+    assert_eq!(lines, ["CAN1 100#01 R", "CAN3 300#03 R"]);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn rotates_asc_captures_into_complete_files() {
+    let dir = std::env::temp_dir().join(format!("usr-canet-gvret-{}-rotate", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir(&dir).unwrap();
+    let config = dir.join("bridge.toml");
+    std::fs::write(
+        &config,
+        format!(
+            "[capture]\nfile = {:?}\nrotate_bytes = 40\n",
+            dir.join("capture.asc").to_str().unwrap()
+        ),
+    )
+    .unwrap();
+    let bridge = Bridge::start_with(2, &["--config", config.to_str().unwrap()]).await;
+    std::fs::remove_file(config).unwrap();
+    let mut client = bridge.client().await;
+    client.request(&[0xf1, 0x09], 4).await;
+    let messages = [
+        Message::new_data(0, 0x100, false, &[1]).unwrap(),
+        Message::new_data(1, 0x18FF50E5, true, &[2, 3]).unwrap(),
+    ];
+    for message in &messages {
+        bridge.canet.emit(message.bus() as usize, message);
+        client.frame().await;
+    }
+    assert!(bridge.stop().await.success());
+
+    // The 43 bytes of an ASC line of one data byte fill a file, so each frame has one of its own
+    let mut records = vec![];
+    for entry in std::fs::read_dir(&dir).unwrap() {
+        let log = std::fs::read_to_string(entry.unwrap().path()).unwrap();
+        assert!(log.ends_with("End TriggerBlock\n"), "unfinished file {log}");
+        let frames: Vec<_> = asc::Reader::new(log.as_bytes())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(frames.len(), 1, "{log}");
+        records.extend(frames);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+    records.sort_by_key(|r| r.time);
+    let read: Vec<_> = records.into_iter().map(|r| r.message).collect();
+    assert_eq!(read, messages);
+}
+
+#[tokio::test]
+async fn rotates_blf_captures_on_container_bytes() {
+    let dir = std::env::temp_dir().join(format!("usr-canet-gvret-{}-blf", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir(&dir).unwrap();
+    let config = dir.join("bridge.toml");
+    std::fs::write(
+        &config,
+        format!(
+            "[capture]\nfile = {:?}\nrotate_bytes = 200\n",
+            dir.join("capture.blf").to_str().unwrap()
+        ),
+    )
+    .unwrap();
+    let bridge = Bridge::start_with(2, &["--config", config.to_str().unwrap()]).await;
+    std::fs::remove_file(config).unwrap();
+    let mut client = bridge.client().await;
+    client.request(&[0xf1, 0x09], 4).await;
+    let messages = [
+        Message::new_data(0, 0x100, false, &[1]).unwrap(),
+        Message::new_data(1, 0x200, false, &[2]).unwrap(),
+    ];
+    for message in &messages {
+        bridge.canet.emit(message.bus() as usize, message);
+        client.frame().await;
+        // Past the flush interval, so each frame goes in a container of its own
+        sleep(Duration::from_millis(1500)).await;
+    }
+    assert!(bridge.stop().await.success());
+
+    // A container of one frame is below 100 bytes. With the 144 byte header rewritten after it
+    // counted, the first container alone would fill a file.
+    let files: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+    assert_eq!(files.len(), 1);
+    let file = std::fs::read(files[0].as_ref().unwrap().path()).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    let read: Vec<_> = blf::Reader::new(&file[..])
+        .unwrap()
+        .map(|r| r.unwrap().message)
+        .collect();
+    assert_eq!(read, messages);
+}
+
+#[tokio::test]
+async fn streams_traffic_as_pcapng() {
+    let addr = common::free_port().await;
+    let bridge = Bridge::start_with(2, &["--pcap-listen", &addr.to_string()]).await;
+    let mut client = bridge.client().await;
+    // The stream listens before GVRET clients are served
+    client.request(&[0xf1, 0x09], 4).await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut header = pcapng::section_header();
+    header.extend(pcapng::interface_description("CAN1"));
+    header.extend(pcapng::interface_description("CAN2"));
+    let mut read = vec![0; header.len()];
+    timeout(DEADLINE, stream.read_exact(&mut read))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(read, header);
+
+    let received = Message::new_data(1, 0x18FF50E5, true, &[1, 2]).unwrap();
+    bridge.canet.emit(1, &received);
+    client.frame().await;
+    let transmitted = Message::new_data(0, 0x123, false, &[0xab]).unwrap();
+    client.transmit(&transmitted).await;
+    timeout(DEADLINE, bridge.canet.recv(0)).await.unwrap();
+
+    let record = Record {
+        time: Duration::ZERO,
+        message: received.clone(),
+        direction: Some(Direction::Rx),
+    };
+    let mut packets = vec![0; 2 * pcapng::enhanced_packet(0, &record).len()];
+    timeout(DEADLINE, stream.read_exact(&mut packets))
+        .await
+        .unwrap()
+        .unwrap();
+    read.extend(packets);
+    let mut reader = pcapng::Reader::new(&read[..]);
+    let records: Vec<_> = reader.by_ref().map(Result::unwrap).collect();
+    assert_eq!(reader.interfaces(), ["CAN1", "CAN2"]);
+    assert_eq!(records[0].message, received);
+    assert_eq!(records[0].direction, Some(Direction::Rx));
+    assert_eq!(records[1].message, transmitted);
+    assert_eq!(records[1].direction, Some(Direction::Tx));
+    assert!(records[0].time <= records[1].time);
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap();
+    assert!(now - records[0].time < DEADLINE, "{:?}", records[0].time);
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn streams_traffic_to_a_pipe() {
+    let path = std::env::temp_dir().join(format!("usr-canet-gvret-{}.pcapng", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let bridge = Bridge::start_with(2, &["--pcap-pipe", path.to_str().unwrap()]).await;
+    bridge.client().await.request(&[0xf1, 0x09], 4).await;
+
+    let mut header = pcapng::section_header();
+    header.extend(pcapng::interface_description("CAN1"));
+    header.extend(pcapng::interface_description("CAN2"));
+    let len = header.len();
+    let pipe = path.clone();
+    let read = tokio::task::spawn_blocking(move || {
+        let mut read = vec![0; len];
+        std::fs::File::open(pipe)?.read_exact(&mut read)?;
+        std::io::Result::Ok(read)
+    });
+    assert_eq!(
+        timeout(DEADLINE, read).await.unwrap().unwrap().unwrap(),
+        header
+    );
+    assert!(bridge.stop().await.success());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn stops_with_no_reader_on_the_pipe() {
+    let path = std::env::temp_dir().join(format!(
+        "usr-canet-gvret-{}-idle.pcapng",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let bridge = Bridge::start_with(2, &["--pcap-pipe", path.to_str().unwrap()]).await;
+    bridge.client().await.request(&[0xf1, 0x09], 4).await;
+
+    assert!(bridge.stop().await.success());
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,131 @@
+//! Bus settings GVRET clients make with `SetupCanBus`
+
+#[allow(dead_code)]
+mod common;
+
+use std::time::Duration;
+
+use common::{Bridge, DEADLINE, GvretClient};
+use tokio::time::timeout;
+use usr_canet_gvret::usr_canet::Message;
+
+/// A `SetupCanBus` word with explicit flags
+fn setup_word(enabled: bool, listen_only: bool, speed: u32) -> [u8; 4] {
+    (1 << 31 | u32::from(enabled) << 30 | u32::from(listen_only) << 29 | speed).to_le_bytes()
+}
+
+/// Configure both buses and wait until the bridge took the settings
+async fn setup(client: &mut GvretClient, buses: [(bool, bool, u32); 2]) {
+    let mut command = vec![0xf1, 0x05];
+    for (enabled, listen_only, speed) in buses {
+        command.extend(setup_word(enabled, listen_only, speed));
+    }
+    client.send(&command).await;
+    client.request(&[0xf1, 0x09], 4).await;
+}
+
+/// `GetCanBusParams` reply for the flags and bitrate of both buses
+fn params(buses: [(u8, u32); 2]) -> Vec<u8> {
+    let mut reply = vec![0xf1, 0x06];
+    for (flags, speed) in buses {
+        reply.push(flags);
+        reply.extend(speed.to_le_bytes());
+    }
+    reply
+}
+
+#[tokio::test]
+async fn reports_the_settings_of_clients() {
+    let bridge = Bridge::start(2).await;
+    let mut client = bridge.client().await;
+    assert_eq!(
+        client.request(&[0xf1, 0x06], 12).await,
+        params([(0x01, 500_000), (0x01, 500_000)])
+    );
+
+    setup(
+        &mut client,
+        [(true, true, 500_000), (false, false, 500_000)],
+    )
+    .await;
+    assert_eq!(
+        client.request(&[0xf1, 0x06], 12).await,
+        params([(0x11, 500_000), (0x00, 500_000)])
+    );
+    // Another client sees them too
+    let mut other = bridge.client().await;
+    assert_eq!(
+        other.request(&[0xf1, 0x06], 12).await,
+        params([(0x11, 500_000), (0x00, 500_000)])
+    );
+}
+
+#[tokio::test]
+async fn keeps_the_configured_bitrate() {
+    let bridge = Bridge::start(2).await;
+    let mut client = bridge.client().await;
+
+    setup(
+        &mut client,
+        [(true, false, 250_000), (true, false, 1_000_000)],
+    )
+    .await;
+    assert_eq!(
+        client.request(&[0xf1, 0x06], 12).await,
+        params([(0x01, 500_000), (0x01, 500_000)])
+    );
+}
+
+#[tokio::test]
+async fn gates_traffic_on_the_settings() {
+    let bridge = Bridge::start(2).await;
+    let mut client = bridge.client().await;
+    setup(
+        &mut client,
+        [(true, true, 500_000), (false, false, 500_000)],
+    )
+    .await;
+
+    // Nothing from the disabled bus, a listen-only bus still receives
+    bridge
+        .canet
+        .emit(1, &Message::new_data(1, 0x101, false, &[1]).unwrap());
+    bridge
+        .canet
+        .emit(0, &Message::new_data(0, 0x100, false, &[0]).unwrap());
+    let frame = client.frame().await;
+    assert_eq!((frame.bus, frame.id), (0, 0x100));
+
+    // Neither transmits
+    for bus in [0, 1] {
+        client
+            .transmit(&Message::new_data(bus, 0x200, false, &[bus]).unwrap())
+            .await;
+    }
+    client.request(&[0xf1, 0x09], 4).await;
+    for bus in [0, 1] {
+        let sent = timeout(Duration::from_millis(200), bridge.canet.recv(bus as usize)).await;
+        assert!(sent.is_err(), "bus {bus} transmitted {sent:?}");
+    }
+
+    setup(
+        &mut client,
+        [(true, false, 500_000), (true, false, 500_000)],
+    )
+    .await;
+    for bus in [0, 1] {
+        let message = Message::new_data(bus, 0x300, false, &[bus]).unwrap();
+        client.transmit(&message).await;
+        assert_eq!(
+            timeout(DEADLINE, bridge.canet.recv(bus as usize))
+                .await
+                .unwrap(),
+            Some(message)
+        );
+    }
+    bridge
+        .canet
+        .emit(1, &Message::new_data(1, 0x101, false, &[1]).unwrap());
+    let frame = client.frame().await;
+    assert_eq!((frame.bus, frame.id), (1, 0x101));
+}
@@ -0,0 +1,109 @@
+use std::time::Duration;
+
+use usr_canet_gvret::{
+    candump::{self, format_line},
+    trace::Direction,
+    usr_canet::Message,
+};
+
+const TIME: Duration = Duration::new(1_760_644_036, 249_713_000);
+
+#[test]
+fn formats_data_frames() {
+    let standard = Message::new_data(0, 0x44, false, &[0x2a, 0x36, 0x6c]).unwrap();
+    assert_eq!(
+        format_line(TIME, "can0", &standard, None),
+        "(1760644036.249713) can0 044#2A366C"
+    );
+    let extended = Message::new_data(1, 0x18FF50E5, true, &[0xde, 0xad, 0xbe, 0xef]).unwrap();
+    assert_eq!(
+        format_line(TIME, "can1", &extended, Some(Direction::Rx)),
+        "(1760644036.249713) can1 18FF50E5#DEADBEEF R"
+    );
+    let empty = Message::new_data(0, 0x7ff, false, &[]).unwrap();
+    assert_eq!(
+        format_line(
+            Duration::from_micros(5),
+            "body",
+            &empty,
+            Some(Direction::Tx)
+        ),
+        "(0.000005) body 7FF# T"
+    );
+}
+
+#[test]
+fn formats_remote_frames() {
+    let with_length = Message::new_remote(0, 0x123, false, 3).unwrap();
+    assert_eq!(
+        format_line(TIME, "can0", &with_length, None),
+        "(1760644036.249713) can0 123#R3"
+    );
+    let extended = Message::new_remote(0, 0x1, true, 0).unwrap();
+    assert_eq!(
+        format_line(TIME, "can0", &extended, Some(Direction::Tx)),
+        "(1760644036.249713) can0 00000001#R T"
+    );
+}
+
+#[test]
+fn reads_lines_back() {
+    let log = "(1760644036.249713) can1 18FF50E5#DEADBEEF R\n\
+               \n\
+               (1760644036.250102) can0 123#R3 T\n\
+               (1760644036.250200) can1 12#XY\n\
+               (1760644036.3) can1 7FF#\n";
+    let mut reader = candump::Reader::new(log.as_bytes());
+    let records: Vec<_> = reader.by_ref().collect();
+
+    let first = records[0].as_ref().unwrap();
+    assert_eq!(first.time, TIME);
+    assert_eq!(
+        first.message,
+        Message::new_data(1, 0x18FF50E5, true, &[0xde, 0xad, 0xbe, 0xef]).unwrap()
+    );
+    assert_eq!(first.direction, Some(Direction::Rx));
+    let second = records[1].as_ref().unwrap();
+    assert_eq!(
+        second.message,
+        Message::new_remote(0, 0x123, false, 3).unwrap()
+    );
+    assert_eq!(second.direction, Some(Direction::Tx));
+    let bad = records[2].as_ref().unwrap_err();
+    assert_eq!(bad.kind(), std::io::ErrorKind::InvalidData);
+    assert!(bad.to_string().starts_with("line 4:"), "{bad}");
+    let last = records[3].as_ref().unwrap();
+    assert_eq!(last.time, Duration::new(1_760_644_036, 300_000_000));
+    assert_eq!(last.direction, None);
+    assert_eq!(records.len(), 4);
+    assert_eq!(reader.interfaces(), ["can0", "can1"]);
+}
+
+#[test]
+fn numbers_buses_after_interface_names() {
+    assert_eq!(candump::interface_bus("can0"), Some(0));
+    assert_eq!(candump::interface_bus("vcan12"), Some(12));
+    assert_eq!(candump::interface_bus("CAN1"), Some(0));
+    assert_eq!(candump::interface_bus("CAN0"), None);
+    assert_eq!(candump::interface_bus("body"), None);
+    assert_eq!(candump::interface_bus("42"), None);
+    assert_eq!(candump::interface_bus("can256"), None);
+
+    // The second bus first, as in a capture of a quiet first bus
+    let log = "(1.000000) CAN2 123#11
+               (2.000000) CAN1 456#22
+               (3.000000) body 789#
+               (4.000000) vcan4 7FF#
+               (5.000000) CAN2 123#33
+";
+    let mut reader = candump::Reader::new(log.as_bytes());
+    let buses: Vec<_> = reader
+        .by_ref()
+        .map(|record| record.unwrap().message.bus())
+        .collect();
+    assert_eq!(buses, [1, 0, 2, 4, 1]);
+    assert_eq!(
+        reader.interfaces(),
+        ["CAN1", "CAN2", "body", "can3", "vcan4"]
+    );
+}
@@ -0,0 +1,153 @@
+//! Sessions of GVRET clients, one after the other and side by side
+
+#[allow(dead_code)]
+mod common;
+
+use std::time::Duration;
+
+use common::{Bridge, GvretClient};
+use tokio::time::timeout;
+use usr_canet_gvret::usr_canet::Message;
+
+/// Frames emitted per round, few enough for the queues of the mock CANET
+const ROUND: u32 = 500;
+/// Frames enough to fill a client queue and socket buffers, which grow to 4 MiB by default
+const FLOOD: u32 = 300_000;
+/// Bytes of a GVRET frame with 8 data bytes
+const FRAME_LEN: usize = 20;
+
+/// Frame on bus 0 carrying `n`
+fn numbered(n: u32) -> Message {
+    let mut data = [0; 8];
+    data[..4].copy_from_slice(&n.to_le_bytes());
+    Message::new_data(0, 0x100, false, &data).unwrap()
+}
+
+/// Emit numbered frames `from` on, checking after each round that every reader got all of them
+async fn flood(bridge: &Bridge, readers: &mut [&mut GvretClient], from: u32, count: u32) {
+    for first in (from..from + count).step_by(ROUND as usize) {
+        for n in first..first + ROUND {
+            bridge.canet.emit(0, &numbered(n));
+        }
+        for reader in readers.iter_mut() {
+            let frames = reader.read(ROUND as usize * FRAME_LEN).await;
+            for (frame, n) in frames.chunks(FRAME_LEN).zip(first..) {
+                assert_eq!(frame[11..15], n.to_le_bytes());
+            }
+        }
+    }
+}
+
+/// Numbers of the frames a client receives until the bridge falls silent
+async fn numbers(client: &mut GvretClient) -> Vec<u32> {
+    let mut numbers = Vec::new();
+    while let Ok(frame) = timeout(Duration::from_millis(500), client.frame()).await {
+        numbers.push(u32::from_le_bytes(frame.data[..4].try_into().unwrap()));
+    }
+    numbers
+}
+
+#[tokio::test]
+async fn serves_new_sessions_after_clients_leave() {
+    let bridge = Bridge::start_with(2, &["--max-client-errors", "1"]).await;
+    let message = Message::new_data(1, 0x123, false, &[1]).unwrap();
+
+    let mut closed = bridge.client().await;
+    assert_eq!(closed.request(&[0xf1, 0x0c], 3).await, [0xf1, 0x0c, 2]);
+    bridge.canet.emit(1, &message);
+    closed.frame().await;
+    drop(closed);
+
+    let mut reset = bridge.client().await;
+    assert_eq!(reset.request(&[0xf1, 0x0c], 3).await, [0xf1, 0x0c, 2]);
+    reset.reset();
+
+    // A new session starts in text mode, where stray bytes are no error
+    let mut client = bridge.connect().await;
+    client.send(b"hello\r\n").await;
+    client.send(&[0xe7, 0xe7]).await;
+    assert_eq!(client.request(&[0xf1, 0x0c], 3).await, [0xf1, 0x0c, 2]);
+    bridge.canet.emit(1, &message);
+    let frame = client.frame().await;
+    assert_eq!((frame.bus, frame.id, &frame.data[..]), (1, 0x123, &[1][..]));
+
+    // The CANET links outlived the sessions
+    assert_eq!(bridge.canet.accepted(0), 1);
+    assert_eq!(bridge.canet.accepted(1), 1);
+
+    // Only after the handshake are stray bytes errors
+    client.send(b"hello\r\n").await;
+    client.send(&[0xf1, 0x09]).await;
+    client.closed().await;
+}
+
+#[tokio::test]
+async fn fans_frames_out_to_every_client() {
+    let bridge = Bridge::start(2).await;
+    let mut reading = bridge.client().await;
+    let mut waiting = bridge.client().await;
+    assert_eq!(reading.request(&[0xf1, 0x0c], 3).await, [0xf1, 0x0c, 2]);
+    assert_eq!(waiting.request(&[0xf1, 0x0c], 3).await, [0xf1, 0x0c, 2]);
+
+    for id in 0..100 {
+        bridge.canet.emit(
+            id as usize % 2,
+            &Message::new_data(0, id, false, &[id as u8]).unwrap(),
+        );
+    }
+    // Each bus keeps its order, the two buses interleave as they come
+    let mut expected = [
+        (0..100).step_by(2).collect::<Vec<_>>(),
+        (1..100).step_by(2).collect(),
+    ];
+    expected.iter_mut().for_each(|ids| ids.reverse());
+    let mut received = |frame: common::GvretFrame| {
+        assert_eq!(expected[frame.bus as usize].pop(), Some(frame.id));
+        assert_eq!(frame.data, [frame.id as u8]);
+    };
+    for _ in 0..100 {
+        received(reading.frame().await);
+    }
+
+    // The client that did not read meanwhile gets the same frames
+    let mut expected = [
+        (0..100).step_by(2).collect::<Vec<_>>(),
+        (1..100).step_by(2).collect(),
+    ];
+    expected.iter_mut().for_each(|ids| ids.reverse());
+    for _ in 0..100 {
+        let frame = waiting.frame().await;
+        assert_eq!(expected[frame.bus as usize].pop(), Some(frame.id));
+    }
+}
+
+#[tokio::test]
+async fn drops_frames_of_clients_that_do_not_read() {
+    let bridge = Bridge::start_with(1, &["--client-overflow", "drop"]).await;
+    let mut reading = bridge.client().await;
+    let mut stalled = bridge.slow_client().await;
+    assert_eq!(stalled.request(&[0xf1, 0x0c], 3).await, [0xf1, 0x0c, 1]);
+
+    flood(&bridge, &mut [&mut reading], 0, FLOOD).await;
+
+    // The stalled client got the frames up to when its queue filled, and none after
+    let received = numbers(&mut stalled).await;
+    assert!(received.len() < FLOOD as usize, "no frame dropped");
+    assert!(received.iter().copied().eq(0..received.len() as u32));
+
+    // Yet it stays connected and gets what comes once it reads again
+    flood(&bridge, &mut [&mut reading, &mut stalled], FLOOD, ROUND).await;
+}
+
+#[tokio::test]
+async fn disconnects_clients_that_do_not_read() {
+    let bridge = Bridge::start_with(1, &["--client-overflow", "disconnect"]).await;
+    let mut reading = bridge.client().await;
+    let mut stalled = bridge.slow_client().await;
+    assert_eq!(stalled.request(&[0xf1, 0x0c], 3).await, [0xf1, 0x0c, 1]);
+
+    flood(&bridge, &mut [&mut reading], 0, FLOOD).await;
+
+    stalled.closed().await;
+    flood(&bridge, &mut [&mut reading], FLOOD, ROUND).await;
+}
@@ -0,0 +1,44 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use usr_canet_gvret::clock::{Clock, TimestampMode};
+
+/// Period of the 32 bit microsecond counter
+const WRAP: Duration = Duration::from_micros(1 << 32);
+
+#[test]
+fn monotonic_starts_at_zero() {
+    let clock = Clock::new(TimestampMode::Monotonic);
+    assert!(clock.now() < 1_000_000);
+}
+
+#[test]
+fn timestamps_wrap_every_2_pow_32_us() {
+    for mode in [TimestampMode::Monotonic, TimestampMode::Wallclock] {
+        let clock = Clock::new(mode);
+        let at = Instant::now();
+        let t = clock.timestamp(at);
+        let later = at + WRAP + Duration::from_micros(10);
+        assert_eq!(clock.timestamp(later), t.wrapping_add(10), "{mode:?}");
+    }
+}
+
+#[test]
+fn differences_survive_the_wrap() {
+    let clock = Clock::new(TimestampMode::Monotonic);
+    let before = Instant::now() + WRAP - Duration::from_millis(1);
+    let after = before + Duration::from_millis(2);
+    let (t0, t1) = (clock.timestamp(before), clock.timestamp(after));
+    assert!(t1 < t0);
+    assert_eq!(t1.wrapping_sub(t0), 2_000);
+}
+
+#[test]
+fn wallclock_matches_unix_time() {
+    let clock = Clock::new(TimestampMode::Wallclock);
+    let unix = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_micros() as u32;
+    let skew = unix.wrapping_sub(clock.now()) as i32;
+    assert!(skew.unsigned_abs() < 100_000, "{skew}");
+}
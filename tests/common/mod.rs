@@ -0,0 +1,243 @@
+//! End-to-end harness: a [`MockCanet`], the bridge binary connected to it and a fake GVRET client
+
+use std::{
+    net::SocketAddr,
+    process::{ExitStatus, Stdio},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpSocket, TcpStream},
+    process::{Child, Command},
+    time::{sleep, timeout},
+};
+use usr_canet_gvret::{mock_canet::MockCanet, usr_canet::Message};
+
+/// Upper bound for anything the bridge is expected to do
+pub const DEADLINE: Duration = Duration::from_secs(5);
+
+/// A running bridge and the simulated CANET behind it, killed on drop
+pub struct Bridge {
+    pub canet: MockCanet,
+    pub listen: SocketAddr,
+    process: Child,
+}
+
+impl Bridge {
+    /// Start a CANET with `ports` ports and a bridge connected to all of them
+    pub async fn start(ports: usize) -> Self {
+        Self::start_with(ports, &[]).await
+    }
+
+    /// Like [`Bridge::start`], passing extra command line flags to the bridge
+    pub async fn start_with(ports: usize, args: &[&str]) -> Self {
+        let canet = MockCanet::start(ports).await.unwrap();
+        let listen = free_port().await;
+        let mut command = Command::new(env!("CARGO_BIN_EXE_usr-canet-gvret"));
+        command
+            .args(["--listen", &listen.to_string(), "--debug", "error"])
+            .args(args)
+            .arg("127.0.0.1")
+            .arg(canet.addr(0).port().to_string());
+        if ports > 1 {
+            command.args(["--port2", &canet.addr(1).port().to_string()]);
+        }
+        let process = command
+            .env("RUST_BACKTRACE", "0")
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+        for port in 0..ports {
+            timeout(DEADLINE, canet.connected(port))
+                .await
+                .expect("bridge did not connect to the CANET");
+        }
+        Self {
+            canet,
+            listen,
+            process,
+        }
+    }
+
+    /// Ask the bridge to stop with SIGTERM and wait for it to exit
+    #[cfg(unix)]
+    pub async fn stop(mut self) -> ExitStatus {
+        let pid = self.process.id().expect("bridge already exited");
+        // SAFETY: plain syscall on the pid of a child not yet waited for
+        assert_eq!(unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) }, 0);
+        timeout(DEADLINE, self.process.wait())
+            .await
+            .expect("bridge did not stop")
+            .unwrap()
+    }
+
+    /// Connect a GVRET client, retrying until the bridge listens, and switch it to binary mode
+    pub async fn client(&self) -> GvretClient {
+        let mut client = self.connect().await;
+        client.send(&[0xe7, 0xe7]).await;
+        client
+    }
+
+    /// Like [`Bridge::client`] with a small receive buffer, so what the client does not read soon
+    /// backs up into the bridge
+    pub async fn slow_client(&self) -> GvretClient {
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.set_recv_buffer_size(4096).unwrap();
+        let stream = timeout(DEADLINE, socket.connect(self.listen))
+            .await
+            .expect("bridge not listening")
+            .unwrap();
+        let mut client = GvretClient { stream };
+        client.send(&[0xe7, 0xe7]).await;
+        client
+    }
+
+    /// Connect a GVRET client still in text mode, retrying until the bridge listens
+    pub async fn connect(&self) -> GvretClient {
+        let connect = async {
+            loop {
+                match TcpStream::connect(self.listen).await {
+                    Ok(stream) => return stream,
+                    Err(_) => sleep(Duration::from_millis(20)).await,
+                }
+            }
+        };
+        let stream = timeout(DEADLINE, connect)
+            .await
+            .expect("bridge not listening");
+        GvretClient { stream }
+    }
+}
+
+/// A bridge over two CANET devices: the ports of [`Bridge::canet`] as buses 0 and 1, the port of
+/// `second` as bus 2
+pub struct TwoDevices {
+    pub bridge: Bridge,
+    pub second: MockCanet,
+}
+
+impl TwoDevices {
+    /// Start both devices and the bridge, with `config` ahead of the devices in its config file
+    pub async fn start(config: &str) -> Self {
+        let second = MockCanet::start(1).await.unwrap();
+        let port = second.addr(0).port();
+        let path = std::env::temp_dir().join(format!(
+            "usr-canet-gvret-{}-devices-{port}.toml",
+            std::process::id()
+        ));
+        // The first device gets its ports from the command line
+        std::fs::write(
+            &path,
+            format!(
+                "{config}\n[[canet]]\nhost = \"127.0.0.1\"\nports = [1, 1]\n\n\
+                 [[canet]]\nhost = \"127.0.0.1\"\nports = [{port}]\n"
+            ),
+        )
+        .unwrap();
+        let bridge = Bridge::start_with(2, &["--config", path.to_str().unwrap()]).await;
+        std::fs::remove_file(path).unwrap();
+        timeout(DEADLINE, second.connected(0))
+            .await
+            .expect("bridge did not connect to the second CANET");
+        Self { bridge, second }
+    }
+}
+
+/// A localhost address nobody listens on right now
+pub async fn free_port() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// A frame as reported to GVRET clients
+#[derive(Debug)]
+pub struct GvretFrame {
+    pub timestamp: u32,
+    pub id: u32,
+    pub bus: u8,
+    pub data: Vec<u8>,
+}
+
+pub struct GvretClient {
+    stream: TcpStream,
+}
+
+impl GvretClient {
+    pub async fn send(&mut self, bytes: &[u8]) {
+        self.stream.write_all(bytes).await.unwrap();
+    }
+
+    /// Abort the connection with a TCP reset instead of closing it
+    pub fn reset(self) {
+        self.stream.set_zero_linger().unwrap();
+    }
+
+    /// Wait for the bridge to close the connection, discarding anything sent before
+    pub async fn closed(&mut self) {
+        let mut buf = [0; 64];
+        let drain = async { while self.stream.read(&mut buf).await.is_ok_and(|n| n > 0) {} };
+        timeout(DEADLINE, drain)
+            .await
+            .expect("bridge kept the connection open");
+    }
+
+    /// Read exactly `len` bytes
+    pub async fn read(&mut self, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        timeout(DEADLINE, self.stream.read_exact(&mut buf))
+            .await
+            .expect("no reply from the bridge")
+            .unwrap();
+        buf
+    }
+
+    /// Send a command and read a reply of `len` bytes
+    pub async fn request(&mut self, command: &[u8], len: usize) -> Vec<u8> {
+        self.send(command).await;
+        self.read(len).await
+    }
+
+    /// Ask the bridge to transmit `message` on its bus
+    pub async fn transmit(&mut self, message: &Message) {
+        self.send_frame(0x00, message).await;
+    }
+
+    /// Ask the bridge to report `message` to its clients without transmitting it
+    pub async fn echo(&mut self, message: &Message) {
+        self.send_frame(0x0b, message).await;
+    }
+
+    async fn send_frame(&mut self, command: u8, message: &Message) {
+        let mut id = message.id();
+        if message.ext_id() {
+            id |= 1 << 31;
+        }
+        let mut command = vec![0xf1, command];
+        command.extend(id.to_le_bytes());
+        command.push(message.bus());
+        command.push(message.dlc());
+        command.extend(message.data().unwrap_or_default());
+        // Checksum, sent as 0 by SavvyCAN
+        command.push(0);
+        self.send(&command).await;
+    }
+
+    /// Read the next received frame
+    pub async fn frame(&mut self) -> GvretFrame {
+        let header = self.read(11).await;
+        assert_eq!(header[..2], [0xf1, 0x00], "not a frame: {header:02x?}");
+        let len = (header[10] & 0xf) as usize;
+        let data = self.read(len + 1).await;
+        GvretFrame {
+            timestamp: u32::from_le_bytes(header[2..6].try_into().unwrap()),
+            id: u32::from_le_bytes(header[6..10].try_into().unwrap()),
+            bus: header[10] >> 4,
+            data: data[..len].to_vec(),
+        }
+    }
+}
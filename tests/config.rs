@@ -0,0 +1,268 @@
+//! Config files and command line flags of the bridge binary
+
+#[allow(dead_code)]
+mod common;
+
+use std::{
+    path::PathBuf,
+    process::Stdio,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use common::{Bridge, DEADLINE, GvretClient};
+use tokio::{
+    net::TcpStream,
+    process::Command,
+    time::{sleep, timeout},
+};
+use usr_canet_gvret::{mock_canet::MockCanet, usr_canet::Message};
+
+/// Numbers the config files of tests running at the same time
+static FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// A config file holding `config`, removed on drop
+struct ConfigFile(PathBuf);
+
+impl ConfigFile {
+    fn new(config: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "usr-canet-gvret-{}-config-{}.toml",
+            std::process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&path, config).unwrap();
+        Self(path)
+    }
+
+    fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+}
+
+impl Drop for ConfigFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Run the bridge with `config` as its config file and `args`, expecting it to fail
+async fn rejected(config: &str, args: &[&str]) -> String {
+    let file = ConfigFile::new(config);
+    let output = timeout(
+        DEADLINE,
+        Command::new(env!("CARGO_BIN_EXE_usr-canet-gvret"))
+            .args(["--config", file.path()])
+            .args(args)
+            .kill_on_drop(true)
+            .output(),
+    )
+    .await
+    .unwrap_or_else(|_| panic!("bridge accepted {config:?} {args:?}"))
+    .unwrap();
+    assert!(!output.status.success());
+    String::from_utf8(output.stderr).unwrap()
+}
+
+/// A device section for the bridge to have a bus
+const DEVICE: &str = "[[canet]]\nhost = \"127.0.0.1\"\nports = [1]\n";
+
+#[tokio::test]
+async fn rejects_invalid_values_naming_the_key() {
+    let many_devices = "[[canet]]\nhost = \"127.0.0.1\"\nports = [1, 2]\n".repeat(9);
+    let cases = [
+        ("", "No bus configured"),
+        (
+            "[[socketcan]]\ninterface = \"\"\n",
+            "socketcan[0].interface: must not be empty",
+        ),
+        (
+            "[[canet]]\nhost = \"\"\nports = [1]\n",
+            "canet[0].host: must not be empty",
+        ),
+        (
+            "[[canet]]\nhost = \"127.0.0.1\"\nports = []\n",
+            "canet[0].ports: expected 1 to 2 ports, found 0",
+        ),
+        (
+            "[[canet]]\nhost = \"127.0.0.1\"\nports = [1, 2, 3]\n",
+            "canet[0].ports: expected 1 to 2 ports, found 3",
+        ),
+        (
+            &format!("{DEVICE}buses = [\"a\", \"b\"]\n"),
+            "canet[0].buses: 2 names given for 1 ports",
+        ),
+        (
+            &format!("{DEVICE}transport = \"udp\"\nlocal_ports = [1, 2]\n"),
+            "canet[0].local_ports: 2 local ports given for 1 ports",
+        ),
+        (
+            &format!("{DEVICE}local_ports = [1]\n"),
+            "canet[0].local_ports: only used with transport = \"udp\"",
+        ),
+        (
+            &format!("{DEVICE}bitrate = 0\n"),
+            "canet[0].bitrate: 0 is not a classic CAN bitrate",
+        ),
+        (
+            &format!("{DEVICE}bitrate = 2000000\n"),
+            "canet[0].bitrate: 2000000 is not a classic CAN bitrate",
+        ),
+        (
+            &many_devices,
+            "18 buses configured, GVRET supports at most 16",
+        ),
+        (
+            &format!(
+                "{DEVICE}\n[[canet]]\nhost = \"127.0.0.1\"\nports = [2]\nbuses = [\"CAN1\"]\n"
+            ),
+            "canet[1].buses: bus 1 is named \"CAN1\" like bus 0",
+        ),
+        (
+            &format!("{DEVICE}\n[[vcan]]\nbus = 1\ninterface = \"vcan0\"\n"),
+            "vcan[0].bus: bus 1 does not exist, 1 buses configured",
+        ),
+        (
+            &format!("{DEVICE}\n[[vcan]]\nbus = 0\ninterface = \"\"\n"),
+            "vcan[0].interface: must not be empty",
+        ),
+        (
+            &format!("{DEVICE}\n[[filter]]\nid = 0x20000000\n"),
+            "filter[0].id: 0x20000000 is not a valid CAN id",
+        ),
+        (
+            &format!("max_client_errors = 0\n{DEVICE}"),
+            "max_client_errors: must be at least 1",
+        ),
+        (
+            &format!("{DEVICE}\n[capture]\nfile = \"c.log\"\nrotate_bytes = 0\n"),
+            "capture.rotate_bytes: must be at least 1",
+        ),
+        (
+            &format!("{DEVICE}\n[capture]\nfile = \"c.log\"\nrotate_secs = 0\n"),
+            "capture.rotate_secs: must be at least 1",
+        ),
+        (
+            &format!("{DEVICE}\n[capture]\nfile = \"c.blf\"\ngzip = true\n"),
+            "capture.gzip: BLF files are already compressed",
+        ),
+        (
+            &format!("{DEVICE}\n[batching]\nmax_bytes = 0\n"),
+            "batching.max_bytes: must be at least 1",
+        ),
+        (
+            &format!("{DEVICE}\n[logging]\nlevel = \"loud\"\n"),
+            "logging.level: unknown level \"loud\"",
+        ),
+    ];
+    for (config, error) in cases {
+        let stderr = rejected(config, &[]).await;
+        assert!(stderr.contains(error), "{config:?}: {stderr}");
+    }
+}
+
+#[tokio::test]
+async fn rejects_settings_the_command_line_makes_invalid() {
+    let stderr = rejected(DEVICE, &["--vcan", "3=vcan0"]).await;
+    assert!(
+        stderr.contains("vcan[0].bus: bus 3 does not exist"),
+        "{stderr}"
+    );
+}
+
+#[tokio::test]
+async fn command_line_listen_replaces_the_config_file() {
+    let configured = common::free_port().await;
+    let file = ConfigFile::new(&format!("listen = [\"{configured}\"]\n"));
+    // The harness gives --listen
+    let bridge = Bridge::start_with(1, &["--config", file.path()]).await;
+    let mut client = bridge.client().await;
+    assert_eq!(client.request(&[0xf1, 0x0c], 3).await, [0xf1, 0x0c, 1]);
+    assert!(TcpStream::connect(configured).await.is_err());
+}
+
+#[tokio::test]
+async fn interface_flag_replaces_configured_listen_addresses() {
+    let canet = MockCanet::start(1).await.unwrap();
+    let configured = common::free_port().await;
+    let file = ConfigFile::new(&format!("listen = [\"{configured}\"]\n"));
+    let mut process = Command::new(env!("CARGO_BIN_EXE_usr-canet-gvret"))
+        .args(["--config", file.path(), "--interface", "local"])
+        .arg("127.0.0.1")
+        .arg(canet.addr(0).port().to_string())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+
+    // Port 23 may well be taken or privileged, the bridge gives up then
+    tokio::select! {
+        () = canet.connected(0) => {
+            assert!(TcpStream::connect(configured).await.is_err());
+        }
+        status = process.wait() => {
+            assert!(!status.unwrap().success());
+            let mut stderr = String::new();
+            tokio::io::AsyncReadExt::read_to_string(process.stderr.as_mut().unwrap(), &mut stderr)
+                .await
+                .unwrap();
+            assert!(stderr.contains("Cannot listen on 127.0.0.1:23"), "{stderr}");
+        }
+        () = sleep(DEADLINE) => panic!("bridge neither started nor failed"),
+    }
+}
+
+/// The timestamp of a `TimeSync` reply
+async fn time_sync(client: &mut GvretClient) -> u32 {
+    let reply = client.request(&[0xf1, 0x01], 6).await;
+    u32::from_le_bytes(reply[2..].try_into().unwrap())
+}
+
+#[tokio::test]
+async fn command_line_timestamps_override_the_config_file() {
+    let file = ConfigFile::new("timestamps = \"wallclock\"\n");
+    let bridge = Bridge::start_with(1, &["--config", file.path()]).await;
+    let wallclock = time_sync(&mut bridge.client().await).await;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let expected = (now.as_micros() % (1 << 32)) as u32;
+    assert!(
+        expected.wrapping_sub(wallclock) < 1_000_000,
+        "{wallclock} is not about {expected}"
+    );
+
+    let bridge =
+        Bridge::start_with(1, &["--config", file.path(), "--timestamps", "monotonic"]).await;
+    assert!(time_sync(&mut bridge.client().await).await < DEADLINE.as_micros() as u32);
+}
+
+/// Whether a frame transmitted while the CANET is down reaches it once it is back
+async fn queued_while_down(args: &[&str]) -> bool {
+    let bridge = Bridge::start_with(1, args).await;
+    let mut client = bridge.client().await;
+    bridge.canet.stop(0);
+    timeout(DEADLINE, bridge.canet.disconnected(0))
+        .await
+        .unwrap();
+    let down = async {
+        while client.request(&[0xf1, 0x06], 12).await[2] & 1 != 0 {
+            sleep(Duration::from_millis(20)).await;
+        }
+    };
+    timeout(DEADLINE, down).await.unwrap();
+
+    let message = Message::new_data(0, 0x321, false, &[7]).unwrap();
+    client.transmit(&message).await;
+    client.request(&[0xf1, 0x09], 4).await;
+    bridge.canet.restart(0).await.unwrap();
+    timeout(DEADLINE, bridge.canet.connected(0)).await.unwrap();
+    timeout(Duration::from_millis(300), bridge.canet.recv(0))
+        .await
+        .is_ok()
+}
+
+#[tokio::test]
+async fn command_line_tx_policy_overrides_the_config_file() {
+    let file = ConfigFile::new("tx_policy = \"queue\"\n");
+    assert!(queued_while_down(&["--config", file.path()]).await);
+    assert!(!queued_while_down(&["--config", file.path(), "--tx-policy", "drop"]).await);
+}
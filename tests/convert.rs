@@ -0,0 +1,87 @@
+//! The `convert` subcommand of the bridge binary
+
+use std::{fs, path::PathBuf, process::Command};
+
+fn temp(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("usr-canet-gvret-{}-{name}", std::process::id()))
+}
+
+fn convert(input: &PathBuf, output: &PathBuf) {
+    let status = Command::new(env!("CARGO_BIN_EXE_usr-canet-gvret"))
+        .arg("convert")
+        .args([input, output])
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+#[test]
+fn converts_between_formats() {
+    let log = "(1760644036.249713) body 18FF50E5#DEADBEEF R\n\
+               (1760644036.250102) chassis 123#R3 T\n\
+               (1760644036.250200) body 12#XY\n\
+               (1760644037.000000) chassis 7FF# R\n";
+    let [candump, blf, asc, back] = ["in.log", "out.blf", "out.asc", "back.log.gz"].map(temp);
+    fs::write(&candump, log).unwrap();
+
+    convert(&candump, &blf);
+    convert(&blf, &asc);
+    convert(&asc, &back);
+    let asc_lines = fs::read_to_string(&asc).unwrap();
+    let back_lines = Command::new("gzip")
+        .arg("-dc")
+        .arg(&back)
+        .output()
+        .map(|output| String::from_utf8(output.stdout).unwrap());
+    for path in [candump, blf, asc, back] {
+        fs::remove_file(path).unwrap();
+    }
+
+    assert!(
+        asc_lines.contains("   0.001102 2  123             Tx   r 3\n"),
+        "{asc_lines}"
+    );
+    // Skipped the malformed frame, lost the interface names
+    if let Ok(back_lines) = back_lines {
+        assert_eq!(
+            back_lines,
+            "(1760644036.249713) can0 18FF50E5#DEADBEEF R\n\
+             (1760644036.250102) can1 123#R3 T\n\
+             (1760644037.000000) can1 7FF# R\n"
+        );
+    }
+}
+
+#[test]
+fn keeps_buses_of_numbered_interfaces() {
+    let log = "(1.000000) CAN2 123#11
+(2.000000) CAN1 456#22 T
+";
+    let [input, asc] = ["numbered.log", "numbered.asc"].map(temp);
+    fs::write(&input, log).unwrap();
+    convert(&input, &asc);
+    let asc_lines = fs::read_to_string(&asc).unwrap();
+    fs::remove_file(input).unwrap();
+    fs::remove_file(asc).unwrap();
+    // ASC numbers channels from 1, like the bridge names its buses
+    assert!(
+        asc_lines.contains("   0.000000 2  123             Rx   d 1 11\n"),
+        "{asc_lines}"
+    );
+    assert!(
+        asc_lines.contains("   1.000000 1  456             Tx   d 1 22\n"),
+        "{asc_lines}"
+    );
+}
+
+#[test]
+fn keeps_candump_interface_names() {
+    let log = "(1.000000) body 100#01\n(2.000000) chassis 200#02 T\n(3.000000) body 300#\n";
+    let [input, output] = ["names.log", "names-out.log"].map(temp);
+    fs::write(&input, log).unwrap();
+    convert(&input, &output);
+    let converted = fs::read_to_string(&output).unwrap();
+    fs::remove_file(input).unwrap();
+    fs::remove_file(output).unwrap();
+    assert_eq!(converted, log);
+}
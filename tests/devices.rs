@@ -0,0 +1,68 @@
+//! Several CANET devices bridged into one GVRET bus space
+
+#[allow(dead_code)]
+mod common;
+
+use std::time::Duration;
+
+use common::{DEADLINE, TwoDevices};
+use tokio::time::timeout;
+use usr_canet_gvret::usr_canet::Message;
+
+/// `GetExtBuses` reply with `flags` for bus 2 at the default bitrate and nothing beyond
+fn ext_buses(flags: u8) -> Vec<u8> {
+    let mut reply = vec![0xf1, 0x0d, flags];
+    reply.extend(500_000_u32.to_le_bytes());
+    reply.extend([0; 10]);
+    reply
+}
+
+#[tokio::test]
+async fn numbers_the_ports_of_all_devices() {
+    let devices = TwoDevices::start("").await;
+    let mut client = devices.bridge.client().await;
+
+    assert_eq!(client.request(&[0xf1, 0x0c], 3).await, [0xf1, 0x0c, 3]);
+    assert_eq!(client.request(&[0xf1, 0x0d], 17).await, ext_buses(0x01));
+
+    devices
+        .second
+        .emit(0, &Message::new_data(0, 0x222, false, &[2]).unwrap());
+    let frame = client.frame().await;
+    assert_eq!((frame.bus, frame.id, &frame.data[..]), (2, 0x222, &[2][..]));
+
+    // Bus 2 is the first port of the second device
+    let message = Message::new_data(2, 0x18FF50E5, true, &[1, 2, 3]).unwrap();
+    client.transmit(&message).await;
+    assert_eq!(
+        timeout(DEADLINE, devices.second.recv(0)).await.unwrap(),
+        Some(Message::new_data(0, 0x18FF50E5, true, &[1, 2, 3]).unwrap())
+    );
+}
+
+#[tokio::test]
+async fn configures_the_second_device_through_ext_buses() {
+    let devices = TwoDevices::start("").await;
+    let mut client = devices.bridge.client().await;
+
+    // Disabled, then listen-only
+    let mut disable = vec![0xf1, 0x0e];
+    disable.extend((1_u32 << 31 | 500_000).to_le_bytes());
+    disable.extend([0; 8]);
+    client.send(&disable).await;
+    assert_eq!(client.request(&[0xf1, 0x0d], 17).await, ext_buses(0x00));
+    // The first device is left alone
+    let params = client.request(&[0xf1, 0x06], 12).await;
+    assert_eq!((params[2], params[7]), (0x01, 0x01));
+    client
+        .transmit(&Message::new_data(2, 0x100, false, &[]).unwrap())
+        .await;
+    let sent = timeout(Duration::from_millis(200), devices.second.recv(0)).await;
+    assert!(sent.is_err(), "disabled bus transmitted {sent:?}");
+
+    let mut listen_only = vec![0xf1, 0x0e];
+    listen_only.extend((1_u32 << 31 | 1 << 30 | 1 << 29 | 500_000).to_le_bytes());
+    listen_only.extend([0; 8]);
+    client.send(&listen_only).await;
+    assert_eq!(client.request(&[0xf1, 0x0d], 17).await, ext_buses(0x11));
+}
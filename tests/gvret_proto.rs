@@ -0,0 +1,423 @@
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+use usr_canet_gvret::{
+    gvret_proto::{BusParams, Command, GvretCodec, GvretError, Reply},
+    usr_canet::Message,
+};
+
+/// Handshake, a data frame on bus 1, a zero padded remote frame, a bus setup and a keepalive, as
+/// sent by SavvyCAN with a zero checksum byte after each frame
+const SESSION: &[u8] = &[
+    0xe7, 0xe7, //
+    0xf1, 0x00, 0x23, 0x01, 0x00, 0x00, 0x01, 0x02, 0x11, 0x22, 0x00, //
+    0xf1, 0x00, 0x78, 0x56, 0x34, 0xd2, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, //
+    0xf1, 0x05, 0x20, 0xa1, 0x07, 0xc0, 0x00, 0x00, 0x00, 0x00, //
+    0xf1, 0x09,
+];
+
+fn expected() -> Vec<Result<Command, GvretError>> {
+    [
+        Command::BinaryMode,
+        Command::BuildCanFrame(Message::new_data(1, 0x123, false, &[0x11, 0x22]).unwrap()),
+        Command::BuildCanFrame(Message::new_remote(0, 0x12345678, true, 4).unwrap()),
+        Command::SetupCanBus([
+            BusParams {
+                enabled: true,
+                listen_only: false,
+                speed: 500_000,
+            },
+            BusParams::default(),
+        ]),
+        Command::KeepAlive,
+    ]
+    .into_iter()
+    .map(Ok)
+    .collect()
+}
+
+fn decode_in_chunks(bytes: &[u8], chunk: usize) -> Vec<Result<Command, GvretError>> {
+    let mut codec = GvretCodec::new();
+    let mut buf = BytesMut::new();
+    let mut commands = Vec::new();
+    for part in bytes.chunks(chunk) {
+        buf.extend_from_slice(part);
+        while let Some(command) = codec.decode(&mut buf).unwrap() {
+            commands.push(command);
+        }
+    }
+    commands
+}
+
+#[test]
+fn decodes_session_in_one_read() {
+    assert_eq!(decode_in_chunks(SESSION, SESSION.len()), expected());
+}
+
+#[test]
+fn decodes_session_split_at_every_byte() {
+    for chunk in 1..SESSION.len() {
+        assert_eq!(
+            decode_in_chunks(SESSION, chunk),
+            expected(),
+            "chunk {chunk}"
+        );
+    }
+}
+
+#[test]
+fn ignores_text_before_the_handshake() {
+    let bytes = [0x0d, 0x0a, 0x3f, 0xe7, 0xf1, 0x0c];
+    assert_eq!(
+        decode_in_chunks(&bytes, 1),
+        [Ok(Command::BinaryMode), Ok(Command::GetNumBuses)]
+    );
+}
+
+#[test]
+fn reports_stray_bytes_and_unknown_commands() {
+    let bytes = [0xe7, 0xe7, 0x00, 0x42, 0xf1, 0x7f, 0x13, 0xf1, 0x0c];
+    assert_eq!(
+        decode_in_chunks(&bytes, 1),
+        [
+            Ok(Command::BinaryMode),
+            Err(GvretError::Unsynchronised(2)),
+            Err(GvretError::UnknownCommand(0x7f)),
+            Ok(Command::GetNumBuses),
+        ]
+    );
+}
+
+#[test]
+fn resyncs_after_a_bad_frame() {
+    let bytes = [
+        0xe7, 0xe7, //
+        // Standard id above 0x7ff
+        0xf1, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x01, 0x55, 0x00, //
+        0xf1, 0x09,
+    ];
+    for chunk in 1..bytes.len() {
+        assert_eq!(
+            decode_in_chunks(&bytes, chunk),
+            [
+                Ok(Command::BinaryMode),
+                Err(GvretError::InvalidId(0x800)),
+                // The rest of the rejected frame is skipped without another error
+                Ok(Command::KeepAlive),
+            ],
+            "chunk {chunk}"
+        );
+    }
+}
+
+#[test]
+fn clamps_frame_lengths_to_eight() {
+    let bytes = [
+        0xe7, 0xe7, //
+        0xf1, 0x00, 0x23, 0x01, 0x00, 0x00, 0x00, 0x0f, 1, 2, 3, 4, 5, 6, 7, 8, 0x00, //
+        0xf1, 0x09,
+    ];
+    assert_eq!(
+        decode_in_chunks(&bytes, 1),
+        [
+            Ok(Command::BinaryMode),
+            Ok(Command::BuildCanFrame(
+                Message::new_data(0, 0x123, false, &[1, 2, 3, 4, 5, 6, 7, 8]).unwrap()
+            )),
+            Ok(Command::KeepAlive),
+        ]
+    );
+}
+
+#[test]
+fn keeps_partial_commands_until_complete() {
+    let mut codec = GvretCodec::new();
+    let mut buf = BytesMut::from(&[0xf1, 0x00, 0x23, 0x01, 0x00][..]);
+    assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    assert_eq!(buf.len(), 5);
+    assert_eq!(codec.decode_eof(&mut buf).unwrap(), None);
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn encodes_replies() {
+    let mut codec = GvretCodec::new();
+    let mut buf = BytesMut::new();
+    let message = Message::new_remote(1, 0x7ff, false, 2).unwrap();
+    codec
+        .encode(
+            Reply::Frame {
+                timestamp: 0x01020304,
+                message,
+            },
+            &mut buf,
+        )
+        .unwrap();
+    codec.encode(Reply::NumBuses(2), &mut buf).unwrap();
+    assert_eq!(
+        &buf[..],
+        &[
+            0xf1, 0x00, 0x04, 0x03, 0x02, 0x01, 0xff, 0x07, 0x00, 0x40, 0x12, 0x00, 0x00, 0x00,
+            0xf1, 0x0c, 0x02,
+        ]
+    );
+}
+
+/// Decode `bytes` sent after the handshake as a single command
+fn decode_command(bytes: &[u8]) -> Result<Command, GvretError> {
+    let mut codec = GvretCodec::new();
+    let mut buf = BytesMut::from(&[0xe7, 0xe7][..]);
+    buf.extend_from_slice(bytes);
+    assert_eq!(
+        codec.decode(&mut buf).unwrap(),
+        Some(Ok(Command::BinaryMode))
+    );
+    let command = codec.decode(&mut buf).unwrap().expect("incomplete command");
+    assert!(buf.is_empty(), "{} bytes left over", buf.len());
+    command
+}
+
+fn encode(reply: Reply) -> Vec<u8> {
+    let mut buf = BytesMut::new();
+    GvretCodec::new().encode(reply, &mut buf).unwrap();
+    buf.to_vec()
+}
+
+#[test]
+fn decodes_input_queries() {
+    assert_eq!(decode_command(&[0xf1, 0x02]), Ok(Command::DigInputs));
+    assert_eq!(decode_command(&[0xf1, 0x03]), Ok(Command::AnaInputs));
+    // Input state and checksum
+    assert_eq!(encode(Reply::DigInputs), [0xf1, 0x02, 0x00, 0x00]);
+    // Four little endian 16 bit readings and the checksum
+    assert_eq!(
+        encode(Reply::AnaInputs),
+        [0xf1, 0x03, 0, 0, 0, 0, 0, 0, 0, 0, 0x00]
+    );
+}
+
+#[test]
+fn decodes_single_byte_settings() {
+    assert_eq!(
+        decode_command(&[0xf1, 0x04, 0x81]),
+        Ok(Command::SetDigOut(0x81))
+    );
+    assert_eq!(
+        decode_command(&[0xf1, 0x08, 0x01]),
+        Ok(Command::SetSwMode(1))
+    );
+    assert_eq!(
+        decode_command(&[0xf1, 0x0a, 0x02]),
+        Ok(Command::SetSysType(2))
+    );
+}
+
+#[test]
+fn decodes_echo_frames() {
+    assert_eq!(
+        decode_command(&[
+            0xf1, 0x0b, 0xe5, 0x50, 0xff, 0x98, 0x01, 0x03, 0xaa, 0xbb, 0xcc, 0x00
+        ]),
+        Ok(Command::EchoCanFrame(
+            Message::new_data(1, 0x18ff50e5, true, &[0xaa, 0xbb, 0xcc]).unwrap()
+        ))
+    );
+}
+
+#[test]
+fn decodes_extended_bus_settings() {
+    // Single wire CAN enabled at 33.3k, LIN1 disabled, LIN2 listen only at 19.2k
+    assert_eq!(
+        decode_command(&[
+            0xf1, 0x0e, 0x24, 0x82, 0x00, 0xc0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x4b, 0x00, 0xe0
+        ]),
+        Ok(Command::SetExtBuses([
+            BusParams {
+                enabled: true,
+                listen_only: false,
+                speed: 33_316,
+            },
+            BusParams::default(),
+            BusParams {
+                enabled: true,
+                listen_only: true,
+                speed: 19_200,
+            },
+        ]))
+    );
+    assert_eq!(decode_command(&[0xf1, 0x0d]), Ok(Command::GetExtBuses));
+    // Flags (enabled in bit 0, listen only in bit 4) and little endian speed per bus
+    assert_eq!(
+        encode(Reply::ExtBuses([
+            BusParams {
+                enabled: true,
+                listen_only: false,
+                speed: 33_333,
+            },
+            BusParams::default(),
+            BusParams {
+                enabled: true,
+                listen_only: true,
+                speed: 19_200,
+            },
+        ])),
+        [
+            0xf1, 0x0d, //
+            0x01, 0x35, 0x82, 0x00, 0x00, //
+            0x00, 0x00, 0x00, 0x00, 0x00, //
+            0x11, 0x00, 0x4b, 0x00, 0x00,
+        ]
+    );
+}
+
+#[test]
+fn decodes_fd_commands() {
+    let setup: [u8; 16] = std::array::from_fn(|i| i as u8);
+    let mut bytes = vec![0xf1, 0x15];
+    bytes.extend(setup);
+    assert_eq!(decode_command(&bytes), Ok(Command::SetupFd(setup)));
+    assert_eq!(decode_command(&[0xf1, 0x16]), Ok(Command::GetFd));
+    // Nominal and data bitrates of both buses and the checksum, all zero with FD disabled
+    let mut fd = vec![0xf1, 0x16];
+    fd.extend([0; 18]);
+    assert_eq!(encode(Reply::Fd), fd);
+
+    // Id word, bus, length, 12 data bytes and the checksum
+    let mut frame = vec![0xf1, 0x14, 0x23, 0x01, 0x00, 0x00, 0x00, 0x0c];
+    frame.extend([0x55; 12]);
+    frame.push(0x00);
+    assert_eq!(
+        decode_command(&frame),
+        Ok(Command::BuildFdFrame { len: 12 })
+    );
+}
+
+#[test]
+fn encodes_device_replies() {
+    assert_eq!(decode_command(&[0xf1, 0x06]), Ok(Command::GetCanBusParams));
+    assert_eq!(
+        encode(Reply::CanBusParams([
+            BusParams {
+                enabled: true,
+                listen_only: false,
+                speed: 500_000,
+            },
+            BusParams {
+                enabled: true,
+                listen_only: true,
+                speed: 250_000,
+            },
+        ])),
+        [
+            0xf1, 0x06, //
+            0x01, 0x20, 0xa1, 0x07, 0x00, //
+            0x11, 0x90, 0xd0, 0x03, 0x00,
+        ]
+    );
+    assert_eq!(decode_command(&[0xf1, 0x07]), Ok(Command::GetDevInfo));
+    // Build 618, EEPROM version 32, file output type, auto logging and single wire mode
+    assert_eq!(
+        encode(Reply::DevInfo),
+        [0xf1, 0x07, 0x6a, 0x02, 0x20, 0x00, 0x00, 0x00]
+    );
+}
+
+#[test]
+fn skips_unknown_commands() {
+    // The unknown command's payload, if any, is skipped up to the next marker
+    let bytes = [0xe7, 0xe7, 0xf1, 0x42, 0x01, 0x02, 0xf1, 0x0c];
+    for chunk in 1..bytes.len() {
+        assert_eq!(
+            decode_in_chunks(&bytes, chunk),
+            [
+                Ok(Command::BinaryMode),
+                Err(GvretError::UnknownCommand(0x42)),
+                Ok(Command::GetNumBuses),
+            ],
+            "chunk {chunk}"
+        );
+    }
+}
+
+/// Bytes SavvyCAN sent and replies ESP32RET sent back, in the fixture's `>` and `<` lines
+struct Transcript {
+    client: Vec<u8>,
+    device: Vec<Vec<u8>>,
+}
+
+fn transcript() -> Transcript {
+    let mut transcript = Transcript {
+        client: Vec::new(),
+        device: Vec::new(),
+    };
+    for line in include_str!("fixtures/savvycan_esp32ret.txt").lines() {
+        let Some((direction, hex)) = line.split_once(' ') else {
+            continue;
+        };
+        let bytes = hex
+            .split_whitespace()
+            .map(|b| u8::from_str_radix(b, 16).unwrap());
+        match direction {
+            ">" => transcript.client.extend(bytes),
+            "<" => transcript.device.push(bytes.collect()),
+            _ => {}
+        }
+    }
+    transcript
+}
+
+#[test]
+fn decodes_a_savvycan_session() {
+    let expected = [
+        Command::BinaryMode,
+        Command::GetNumBuses,
+        Command::GetCanBusParams,
+        Command::GetDevInfo,
+        Command::TimeSync,
+        Command::SetupCanBus([
+            BusParams {
+                enabled: true,
+                listen_only: false,
+                speed: 500_000,
+            },
+            BusParams {
+                enabled: false,
+                listen_only: false,
+                speed: 250_000,
+            },
+        ]),
+        Command::GetCanBusParams,
+        Command::BuildCanFrame(Message::new_data(1, 0x321, false, &[0xde, 0xad]).unwrap()),
+        Command::KeepAlive,
+    ]
+    .map(Ok);
+    let client = transcript().client;
+    for chunk in 1..=client.len() {
+        assert_eq!(decode_in_chunks(&client, chunk), expected, "chunk {chunk}");
+    }
+}
+
+#[test]
+fn encodes_the_replies_of_an_esp32ret() {
+    let bus = |enabled, speed| BusParams {
+        enabled,
+        listen_only: false,
+        speed,
+    };
+    let replies = [
+        Reply::NumBuses(2),
+        Reply::CanBusParams([bus(true, 500_000), bus(false, 500_000)]),
+        Reply::DevInfo,
+        Reply::TimeSync(1_000_000),
+        Reply::CanBusParams([bus(true, 500_000), bus(false, 250_000)]),
+        Reply::Frame {
+            timestamp: 0x123456,
+            message: Message::new_data(0, 0x18ff50e5, true, &[0x11, 0x22, 0x33, 0x44]).unwrap(),
+        },
+        Reply::KeepAlive,
+    ];
+    let device = transcript().device;
+    assert_eq!(device.len(), replies.len());
+    for (reply, recorded) in replies.into_iter().zip(device) {
+        assert_eq!(encode(reply.clone()), recorded, "{reply:?}");
+    }
+}
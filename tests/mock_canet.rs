@@ -0,0 +1,51 @@
+//! The simulated CANET of the integration tests and `--simulate`
+
+use std::time::Duration;
+
+use tokio::{
+    io::AsyncWriteExt,
+    net::TcpStream,
+    time::{sleep, timeout},
+};
+use usr_canet_gvret::{
+    mock_canet::{MockCanet, RECV_QUEUE_DEPTH},
+    usr_canet::{Message, convert_to_canet},
+};
+
+const DEADLINE: Duration = Duration::from_secs(5);
+
+fn frame(n: usize) -> Message {
+    Message::new_data(0, 0x100, false, &(n as u32).to_le_bytes()).unwrap()
+}
+
+#[tokio::test]
+async fn drops_frames_nobody_takes() {
+    let canet = MockCanet::start(1).await.unwrap();
+    let mut bridge = TcpStream::connect(canet.addr(0)).await.unwrap();
+    let records: Vec<u8> = (0..RECV_QUEUE_DEPTH + 10)
+        .flat_map(|n| convert_to_canet(&frame(n)))
+        .collect();
+    bridge.write_all(&records).await.unwrap();
+
+    let dropped = async {
+        while canet.dropped(0) < 10 {
+            sleep(Duration::from_millis(10)).await;
+        }
+    };
+    timeout(DEADLINE, dropped).await.unwrap();
+    assert_eq!(canet.dropped(0), 10);
+    // The oldest are kept, and room is made by taking them
+    assert_eq!(canet.recv(0).await, Some(frame(0)));
+    bridge
+        .write_all(&convert_to_canet(&frame(9999)))
+        .await
+        .unwrap();
+    for n in 1..RECV_QUEUE_DEPTH {
+        assert_eq!(canet.recv(0).await, Some(frame(n)));
+    }
+    assert_eq!(
+        timeout(DEADLINE, canet.recv(0)).await.unwrap(),
+        Some(frame(9999))
+    );
+    assert_eq!(canet.dropped(0), 10);
+}
@@ -0,0 +1,128 @@
+//! Reconnecting to a CANET that went away, and what clients see meanwhile
+
+#[allow(dead_code)]
+mod common;
+
+use std::time::Duration;
+
+use common::{Bridge, DEADLINE, GvretClient};
+use tokio::time::{Instant, sleep, timeout};
+use usr_canet_gvret::usr_canet::Message;
+
+/// Whether `GetCanBusParams` reports `bus` as enabled
+async fn enabled(client: &mut GvretClient, bus: usize) -> bool {
+    let reply = client.request(&[0xf1, 0x06], 12).await;
+    reply[2 + 5 * bus] & 1 != 0
+}
+
+/// Wait until `GetCanBusParams` reports `bus` as `up`
+async fn reported(client: &mut GvretClient, bus: usize, up: bool) {
+    let poll = async {
+        while enabled(client, bus).await != up {
+            sleep(Duration::from_millis(20)).await;
+        }
+    };
+    timeout(DEADLINE, poll)
+        .await
+        .unwrap_or_else(|_| panic!("bus {bus} not reported {}", if up { "up" } else { "down" }));
+}
+
+/// Stop a port of the CANET and wait until the bridge reports it down
+async fn take_down(bridge: &Bridge, client: &mut GvretClient, port: usize) {
+    bridge.canet.stop(port);
+    timeout(DEADLINE, bridge.canet.disconnected(port))
+        .await
+        .unwrap();
+    reported(client, port, false).await;
+}
+
+#[tokio::test]
+async fn reconnects_after_the_canet_drops() {
+    let bridge = Bridge::start(2).await;
+    let mut client = bridge.client().await;
+    assert!(enabled(&mut client, 0).await);
+
+    take_down(&bridge, &mut client, 0).await;
+    assert!(enabled(&mut client, 1).await);
+    bridge.canet.restart(0).await.unwrap();
+    timeout(DEADLINE, bridge.canet.connected(0)).await.unwrap();
+    reported(&mut client, 0, true).await;
+
+    let message = Message::new_data(0, 0x123, false, &[1]).unwrap();
+    bridge.canet.emit(0, &message);
+    let frame = client.frame().await;
+    assert_eq!((frame.bus, frame.id, &frame.data[..]), (0, 0x123, &[1][..]));
+    assert_eq!(bridge.canet.accepted(0), 2);
+    assert_eq!(bridge.canet.accepted(1), 1);
+}
+
+#[tokio::test]
+async fn backs_off_while_the_canet_is_down_and_resets_once_back() {
+    let bridge = Bridge::start(1).await;
+    let mut client = bridge.client().await;
+
+    // Attempts 0.5s and 1.5s after the drop fail, the next one comes 2s later
+    take_down(&bridge, &mut client, 0).await;
+    sleep(Duration::from_millis(1700)).await;
+    bridge.canet.restart(0).await.unwrap();
+    let restarted = Instant::now();
+    timeout(DEADLINE, bridge.canet.connected(0)).await.unwrap();
+    assert!(
+        restarted.elapsed() >= Duration::from_secs(1),
+        "reconnected {:?} after the restart",
+        restarted.elapsed()
+    );
+
+    // Connecting again resets the delay
+    bridge.canet.stop(0);
+    timeout(DEADLINE, bridge.canet.disconnected(0))
+        .await
+        .unwrap();
+    bridge.canet.restart(0).await.unwrap();
+    let dropped = Instant::now();
+    timeout(DEADLINE, bridge.canet.connected(0)).await.unwrap();
+    assert!(
+        dropped.elapsed() < Duration::from_secs(1),
+        "reconnected {:?} after the drop",
+        dropped.elapsed()
+    );
+}
+
+#[tokio::test]
+async fn queues_frames_while_disconnected() {
+    let bridge = Bridge::start_with(1, &["--tx-policy", "queue"]).await;
+    let mut client = bridge.client().await;
+    take_down(&bridge, &mut client, 0).await;
+
+    let queued = Message::new_data(0, 0x321, false, &[7]).unwrap();
+    client.transmit(&queued).await;
+    // Give the bridge the time to take the frame before the CANET is back
+    client.request(&[0xf1, 0x09], 4).await;
+    bridge.canet.restart(0).await.unwrap();
+
+    assert_eq!(
+        timeout(DEADLINE, bridge.canet.recv(0)).await.unwrap(),
+        Some(queued)
+    );
+}
+
+#[tokio::test]
+async fn drops_frames_while_disconnected() {
+    let bridge = Bridge::start(1).await;
+    let mut client = bridge.client().await;
+    take_down(&bridge, &mut client, 0).await;
+
+    let dropped = Message::new_data(0, 0x321, false, &[7]).unwrap();
+    client.transmit(&dropped).await;
+    client.request(&[0xf1, 0x09], 4).await;
+    bridge.canet.restart(0).await.unwrap();
+    timeout(DEADLINE, bridge.canet.connected(0)).await.unwrap();
+    reported(&mut client, 0, true).await;
+
+    let sent = Message::new_data(0, 0x322, false, &[8]).unwrap();
+    client.transmit(&sent).await;
+    assert_eq!(
+        timeout(DEADLINE, bridge.canet.recv(0)).await.unwrap(),
+        Some(sent)
+    );
+}
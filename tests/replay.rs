@@ -0,0 +1,123 @@
+//! The `replay` subcommand of the bridge binary
+
+use std::{fs, path::PathBuf, time::Duration};
+
+use tokio::{
+    process::Command,
+    time::{Instant, timeout},
+};
+use usr_canet_gvret::{mock_canet::MockCanet, usr_canet::Message};
+
+const DEADLINE: Duration = Duration::from_secs(5);
+
+fn temp(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("usr-canet-gvret-{}-{name}", std::process::id()))
+}
+
+#[tokio::test]
+async fn dry_run_prints_canet_records() {
+    let path = temp("dry-run.csv");
+    fs::write(
+        &path,
+        "Time Stamp,ID,Extended,Bus,LEN,D1,D2,D3,D4,D5,D6,D7,D8\n\
+         1000000,00000123,false,0,2,01,02,\n\
+         1250000,18FF50E5,true,1,3,\n\
+         2000000,00000456,false,1,1,AA,\n",
+    )
+    .unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_usr-canet-gvret"))
+        .arg("replay")
+        .arg(&path)
+        .args([
+            "--dry-run",
+            "--speed",
+            "0.5",
+            "--id",
+            "18FF0000/1FFF0000",
+            "--id",
+            "123",
+        ])
+        .args(["--map", "1=3"])
+        .output()
+        .await
+        .unwrap();
+    fs::remove_file(path).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "    0.000000 0 02 00 00 01 23 01 02 00 00 00 00 00 00\n\
+         \x20   0.500000 3 C3 18 FF 50 E5 00 00 00 00 00 00 00 00\n"
+    );
+}
+
+#[tokio::test]
+async fn maps_interfaces_by_name() {
+    let path = temp("names.log");
+    // The second bus first, as in a capture of a quiet first bus
+    fs::write(
+        &path,
+        "(1.000000) CAN2 123#11\n(1.500000) CAN1 456#22\n(2.000000) body 789#33\n",
+    )
+    .unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_usr-canet-gvret"))
+        .arg("replay")
+        .arg(&path)
+        .arg("--dry-run")
+        .args(["--map", "CAN2=0", "--map", "CAN1=1", "--map", "body=3"])
+        .output()
+        .await
+        .unwrap();
+    fs::remove_file(path).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "    0.000000 0 01 00 00 01 23 11 00 00 00 00 00 00 00\n\
+         \x20   0.500000 1 01 00 00 04 56 22 00 00 00 00 00 00 00\n\
+         \x20   1.000000 3 01 00 00 07 89 33 00 00 00 00 00 00 00\n"
+    );
+}
+
+#[tokio::test]
+async fn replays_onto_the_canet_with_timing() {
+    let canet = MockCanet::start(2).await.unwrap();
+    let path = temp("replay.log");
+    fs::write(
+        &path,
+        "(1.000000) can0 100#01\n(1.400000) can1 200#02\n(1.500000) can2 300#03\n",
+    )
+    .unwrap();
+    let mut process = Command::new(env!("CARGO_BIN_EXE_usr-canet-gvret"))
+        .arg("replay")
+        .arg(&path)
+        .arg("127.0.0.1")
+        .arg(canet.addr(0).port().to_string())
+        .args(["--port2", &canet.addr(1).port().to_string()])
+        .args(["--map", "1=0", "--map", "0=1", "--speed", "2"])
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+
+    let recv = |port| timeout(DEADLINE, canet.recv(port));
+    assert_eq!(
+        recv(1).await.unwrap(),
+        Some(Message::new_data(1, 0x100, false, &[1]).unwrap())
+    );
+    let first = Instant::now();
+    assert_eq!(
+        recv(0).await.unwrap(),
+        Some(Message::new_data(0, 0x200, false, &[2]).unwrap())
+    );
+    // 400ms in the log at twice the speed
+    assert!(first.elapsed() >= Duration::from_millis(150));
+    // The frame of bus 2 has no port and is skipped
+    assert!(
+        timeout(DEADLINE, process.wait())
+            .await
+            .unwrap()
+            .unwrap()
+            .success()
+    );
+    fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,30 @@
+use usr_canet_gvret::{router::Router, usr_canet::Message};
+
+fn frame(bus: u8) -> Message {
+    Message::new_data(bus, 0x123, false, &[bus]).unwrap()
+}
+
+#[test]
+fn routes_every_bus_index() {
+    for ports in 0..=16 {
+        let router = Router::new((0..ports).collect::<Vec<usize>>());
+        for bus in 0..=u8::MAX {
+            let expected = (usize::from(bus) < ports).then_some(usize::from(bus));
+            assert_eq!(
+                router.route(&frame(bus)).copied(),
+                expected,
+                "bus {bus} with {ports} ports"
+            );
+        }
+        assert_eq!(router.unroutable(), 256 - ports as u64);
+    }
+}
+
+#[test]
+fn bus_zero_is_the_first_port() {
+    let router = Router::new(vec!["CAN1", "CAN2"]);
+    assert_eq!(router.route(&frame(0)), Some(&"CAN1"));
+    assert_eq!(router.route(&frame(1)), Some(&"CAN2"));
+    assert_eq!(router.route(&frame(2)), None);
+    assert_eq!(router.len(), 2);
+}
@@ -0,0 +1,190 @@
+//! SocketCAN backend and bus mirror of the bridge binary. These need a `vcan0` interface:
+//!
+//! ```sh
+//! sudo ip link add dev vcan0 type vcan && sudo ip link set up vcan0
+//! cargo test --test socketcan -- --ignored
+//! ```
+//!
+//! Tests running at the same time share the interface, so each uses ids of its own and skips
+//! frames of the others.
+
+#![cfg(target_os = "linux")]
+
+#[allow(dead_code)]
+mod common;
+
+use std::{
+    ffi::CString,
+    mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    time::Duration,
+};
+
+use common::{Bridge, DEADLINE};
+use tokio::{task, time::sleep};
+use usr_canet_gvret::usr_canet::Message;
+
+const INTERFACE: &str = "vcan0";
+
+/// Time for the bridge to open the interface after it accepted a client
+const OPEN_DELAY: Duration = Duration::from_millis(200);
+
+/// A blocking raw CAN socket on [`INTERFACE`]
+struct Vcan(OwnedFd);
+
+impl Vcan {
+    fn open() -> Self {
+        let name = CString::new(INTERFACE).unwrap();
+        // SAFETY: plain libc calls on a socket owned by `OwnedFd` once created
+        unsafe {
+            let ifindex = libc::if_nametoindex(name.as_ptr());
+            assert_ne!(ifindex, 0, "no {INTERFACE} interface");
+            let fd = libc::socket(libc::PF_CAN, libc::SOCK_RAW, libc::CAN_RAW);
+            assert!(fd >= 0, "cannot open a CAN socket");
+            let fd = OwnedFd::from_raw_fd(fd);
+            let mut addr: libc::sockaddr_can = mem::zeroed();
+            addr.can_family = libc::AF_CAN as libc::sa_family_t;
+            addr.can_ifindex = ifindex as libc::c_int;
+            let bound = libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const _ as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_can>() as libc::socklen_t,
+            );
+            assert_eq!(bound, 0, "cannot bind to {INTERFACE}");
+            let timeout = libc::timeval {
+                tv_sec: DEADLINE.as_secs() as libc::time_t,
+                tv_usec: 0,
+            };
+            libc::setsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &timeout as *const _ as *const libc::c_void,
+                mem::size_of::<libc::timeval>() as libc::socklen_t,
+            );
+            Self(fd)
+        }
+    }
+
+    fn send(&self, id: u32, data: &[u8]) {
+        // SAFETY: an all zero can_frame is valid, exactly one is written
+        unsafe {
+            let mut frame: libc::can_frame = mem::zeroed();
+            frame.can_id = id;
+            frame.can_dlc = data.len() as u8;
+            frame.data[..data.len()].copy_from_slice(data);
+            let n = libc::write(
+                self.0.as_raw_fd(),
+                &frame as *const _ as *const libc::c_void,
+                mem::size_of::<libc::can_frame>(),
+            );
+            assert_eq!(n, mem::size_of::<libc::can_frame>() as isize);
+        }
+    }
+
+    /// Data of the next frame with standard id `id`
+    fn recv(&self, id: u32) -> Vec<u8> {
+        loop {
+            // SAFETY: an all zero can_frame is valid, at most one is read
+            let frame = unsafe {
+                let mut frame: libc::can_frame = mem::zeroed();
+                let n = libc::read(
+                    self.0.as_raw_fd(),
+                    &mut frame as *mut _ as *mut libc::c_void,
+                    mem::size_of::<libc::can_frame>(),
+                );
+                assert!(n > 0, "no frame {id:#x} on {INTERFACE}");
+                frame
+            };
+            if frame.can_id == id {
+                return frame.data[..frame.can_dlc as usize].to_vec();
+            }
+        }
+    }
+}
+
+/// Wait for a frame with `id` on the interface without holding up the runtime
+async fn recv_on_vcan(vcan: Vcan, id: u32) -> (Vcan, Vec<u8>) {
+    task::spawn_blocking(move || {
+        let data = vcan.recv(id);
+        (vcan, data)
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs a vcan0 interface"]
+async fn receives_frames_from_socketcan() {
+    let bridge = Bridge::start_with(1, &["--socketcan", INTERFACE]).await;
+    let mut client = bridge.client().await;
+    sleep(OPEN_DELAY).await;
+
+    Vcan::open().send(0x311, &[1, 2, 3]);
+
+    // Bus 1, after the CANET port
+    loop {
+        let frame = client.frame().await;
+        if frame.id == 0x311 {
+            assert_eq!((frame.bus, &frame.data[..]), (1, &[1, 2, 3][..]));
+            break;
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs a vcan0 interface"]
+async fn transmits_frames_on_socketcan() {
+    let bridge = Bridge::start_with(1, &["--socketcan", INTERFACE]).await;
+    let mut client = bridge.client().await;
+    let vcan = Vcan::open();
+    sleep(OPEN_DELAY).await;
+
+    client
+        .transmit(&Message::new_data(1, 0x322, false, &[4, 5]).unwrap())
+        .await;
+
+    let (_, data) = recv_on_vcan(vcan, 0x322).await;
+    assert_eq!(data, [4, 5]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs a vcan0 interface"]
+async fn mirrors_a_bus_onto_vcan() {
+    let bridge = Bridge::start_with(1, &["--vcan", &format!("0={INTERFACE}")]).await;
+    let mut client = bridge.client().await;
+    let vcan = Vcan::open();
+    sleep(OPEN_DELAY).await;
+
+    // Received on the bus
+    bridge
+        .canet
+        .emit(0, &Message::new_data(0, 0x333, false, &[6]).unwrap());
+    let (vcan, data) = recv_on_vcan(vcan, 0x333).await;
+    assert_eq!(data, [6]);
+
+    // Transmitted by a GVRET client
+    let from_client = Message::new_data(0, 0x334, false, &[7]).unwrap();
+    client.transmit(&from_client).await;
+    let (vcan, data) = recv_on_vcan(vcan, 0x334).await;
+    assert_eq!(data, [7]);
+
+    // Sent on the interface
+    vcan.send(0x335, &[8]);
+    let expected = [
+        from_client,
+        Message::new_data(0, 0x335, false, &[8]).unwrap(),
+    ];
+    for expected in expected {
+        loop {
+            let message = tokio::time::timeout(DEADLINE, bridge.canet.recv(0))
+                .await
+                .expect("no frame on the CANET")
+                .unwrap();
+            if message.id() == expected.id() {
+                assert_eq!(message, expected);
+                break;
+            }
+        }
+    }
+}
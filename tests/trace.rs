@@ -0,0 +1,248 @@
+use std::{
+    io::{self, Cursor},
+    time::Duration,
+};
+
+use usr_canet_gvret::{
+    asc, blf, gvret_csv, pcapng,
+    trace::{Direction, Record, TraceFormat, TraceWriter, UtcTime},
+    usr_canet::Message,
+};
+
+/// Fri Oct 16 2026 20:14:49.031 UTC
+const START: Duration = Duration::new(1_792_181_689, 31_000_000);
+
+fn records() -> Vec<Record> {
+    let at = |micros| START + Duration::from_micros(micros);
+    vec![
+        Record {
+            time: at(1_234),
+            message: Message::new_data(0, 0x123, false, &[1, 2, 3]).unwrap(),
+            direction: Some(Direction::Rx),
+        },
+        Record {
+            time: at(2_345),
+            message: Message::new_remote(1, 0x18FF50E5, true, 8).unwrap(),
+            direction: Some(Direction::Tx),
+        },
+        Record {
+            time: at(1_500_000),
+            message: Message::new_data(3, 0x7ff, false, &[]).unwrap(),
+            direction: Some(Direction::Rx),
+        },
+    ]
+}
+
+#[test]
+fn converts_dates() {
+    let date = UtcTime::from_unix(START.as_secs());
+    assert_eq!(
+        date,
+        UtcTime {
+            year: 2026,
+            month: 10,
+            day: 16,
+            hour: 20,
+            minute: 14,
+            second: 49
+        }
+    );
+    assert_eq!(date.weekday(), 5);
+    assert_eq!(date.to_unix(), START.as_secs());
+    // Leap day
+    assert_eq!(UtcTime::from_unix(951_782_400).day, 29);
+    assert_eq!(asc::format_date(START), "Fri Oct 16 20:14:49.031 2026");
+    assert_eq!(asc::parse_date("Fri Oct 16 20:14:49.031 2026"), Some(START));
+    assert_eq!(
+        asc::parse_date("Fri Oct 16 08:14:49.031 pm 2026"),
+        Some(START)
+    );
+    assert_eq!(
+        asc::parse_date("Fri Oct 16 12:00:00 am 2026"),
+        Some(Duration::from_secs(
+            START.as_secs() - 20 * 3600 - 14 * 60 - 49
+        ))
+    );
+}
+
+#[test]
+fn finds_formats_by_extension() {
+    let format = |name: &str| TraceFormat::from_path(name.as_ref());
+    assert_eq!(format("bridge.log"), Some(TraceFormat::Candump));
+    assert_eq!(format("bridge.log.gz"), Some(TraceFormat::Candump));
+    assert_eq!(format("Bridge.ASC"), Some(TraceFormat::Asc));
+    assert_eq!(format("bridge.blf"), Some(TraceFormat::Blf));
+    assert_eq!(format("savvycan.csv"), Some(TraceFormat::GvretCsv));
+    assert_eq!(format("bridge.gz"), None);
+    assert_eq!(format("bridge"), None);
+}
+
+#[test]
+fn writes_asc_files() {
+    let mut out = vec![];
+    let mut writer = asc::Writer::new(&mut out, START).unwrap();
+    for record in &records() {
+        writer.write(record).unwrap();
+    }
+    writer.finish().unwrap();
+    drop(writer);
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "date Fri Oct 16 20:14:49.031 2026\n\
+         base hex  timestamps absolute\n\
+         internal events logged\n\
+         // version 9.0.0\n\
+         Begin Triggerblock Fri Oct 16 20:14:49.031 2026\n   \
+         0.000000 Start of measurement\n   \
+         0.001234 1  123             Rx   d 3 01 02 03\n   \
+         0.002345 2  18FF50E5x       Tx   r 8\n   \
+         1.500000 4  7FF             Rx   d 0\n\
+         End TriggerBlock\n"
+    );
+}
+
+#[test]
+fn reads_asc_files() {
+    let mut out = vec![];
+    let mut writer = asc::Writer::new(&mut out, START).unwrap();
+    for record in &records() {
+        writer.write(record).unwrap();
+    }
+    drop(writer);
+    let read: Vec<_> = asc::Reader::new(&out[..]).map(Result::unwrap).collect();
+    assert_eq!(read, records());
+
+    let relative = "date Fri Oct 16 08:14:49.031 pm 2026\n\
+                    base dec  timestamps relative\n\
+                    0.5 1 291 Rx d 1 FF\n\
+                    0.25 2 100x Rx r\n\
+                    0.25 1 ErrorFrame\n\
+                    0.25 1 100 Rx d 2 01\n";
+    let read: Vec<_> = asc::Reader::new(relative.as_bytes()).collect();
+    let first = read[0].as_ref().unwrap();
+    assert_eq!(first.time, START + Duration::from_millis(500));
+    assert_eq!(
+        first.message,
+        Message::new_data(0, 291, false, &[0xff]).unwrap()
+    );
+    let second = read[1].as_ref().unwrap();
+    assert_eq!(second.time, START + Duration::from_millis(750));
+    assert_eq!(
+        second.message,
+        Message::new_remote(1, 100, true, 0).unwrap()
+    );
+    let short = read[2].as_ref().unwrap_err();
+    assert!(short.to_string().starts_with("line 6:"), "{short}");
+    assert_eq!(read.len(), 3);
+}
+
+#[test]
+fn round_trips_blf_files() {
+    let mut out = Cursor::new(vec![]);
+    let mut writer = blf::Writer::new(&mut out, START).unwrap();
+    for record in &records()[..2] {
+        writer.write(record).unwrap();
+    }
+    // A container of its own
+    writer.flush().unwrap();
+    writer.write(&records()[2]).unwrap();
+    writer.finish().unwrap();
+    drop(writer);
+    let file = out.into_inner();
+    assert_eq!(&file[..4], b"LOGG");
+    assert_eq!(u32::from_le_bytes(file[32..36].try_into().unwrap()), 3);
+    assert_eq!(
+        u64::from_le_bytes(file[16..24].try_into().unwrap()),
+        file.len() as u64
+    );
+
+    let reader = blf::Reader::new(&file[..]).unwrap();
+    assert_eq!(reader.start(), START);
+    let read: Vec<_> = reader.map(Result::unwrap).collect();
+    assert_eq!(read, records());
+
+    // Cut short within the last container
+    let mut reader = blf::Reader::new(&file[..file.len() - 10]).unwrap();
+    assert_eq!(reader.next().unwrap().unwrap(), records()[0]);
+    assert_eq!(reader.next().unwrap().unwrap(), records()[1]);
+    assert!(reader.next().unwrap().is_err());
+    assert!(reader.next().is_none());
+}
+
+#[test]
+fn round_trips_pcapng_files() {
+    let mut file = vec![];
+    let mut writer = pcapng::Writer::new(&mut file, vec!["body".into()]).unwrap();
+    for record in &records() {
+        writer.write(record).unwrap();
+    }
+    writer.finish().unwrap();
+    drop(writer);
+    // Remote frames carry their length and no data
+    let rtr = [0xd8, 0xff, 0x50, 0xe5, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    assert!(file.windows(rtr.len()).any(|packet| packet == rtr));
+
+    let mut reader = pcapng::Reader::new(&file[..]);
+    let read: Vec<_> = reader.by_ref().map(Result::unwrap).collect();
+    assert_eq!(read, records());
+    assert_eq!(reader.interfaces(), ["body", "can1", "can2", "can3"]);
+
+    // Appending starts a new section with its own interfaces
+    let mut appended = file.clone();
+    let mut writer = pcapng::Writer::new(&mut appended, vec!["chassis".into()]).unwrap();
+    writer.write(&records()[0]).unwrap();
+    drop(writer);
+    let mut reader = pcapng::Reader::new(&appended[..]);
+    assert_eq!(reader.by_ref().count(), 4);
+    assert_eq!(reader.interfaces(), ["chassis"]);
+
+    let error = pcapng::Reader::new(&[0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0][..])
+        .next()
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(error.to_string(), "not a pcapng file");
+}
+
+#[test]
+fn round_trips_gvret_csv_files() {
+    let mut file = vec![];
+    let mut writer = gvret_csv::Writer::new(&mut file).unwrap();
+    for record in &records() {
+        writer.write(record).unwrap();
+    }
+    writer.finish().unwrap();
+    let text = String::from_utf8(file).unwrap();
+    assert!(
+        text.starts_with(
+            "Time Stamp,ID,Extended,Dir,Bus,LEN,D1,D2,D3,D4,D5,D6,D7,D8\n\
+             1792181689032234,00000123,false,Rx,0,3,01,02,03,\n\
+             1792181689033345,18FF50E5,true,Tx,1,8,\n"
+        ),
+        "{text}"
+    );
+
+    let read: Vec<_> = gvret_csv::Reader::new(text.as_bytes())
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(read, records());
+
+    // Files without a direction column, with a malformed line in between
+    let old = "Time Stamp,ID,Extended,Bus,LEN,D1,D2,D3,D4,D5,D6,D7,D8\n\
+               1000,7FF,false,1,2,0A,0B\n\
+               2000,800,false,1,0,\n\
+               3000,1FFFFFFF,true,0,0,\n";
+    let read: Vec<_> = gvret_csv::Reader::new(old.as_bytes()).collect();
+    assert_eq!(
+        read[0].as_ref().unwrap(),
+        &Record {
+            time: Duration::from_micros(1000),
+            message: Message::new_data(1, 0x7ff, false, &[10, 11]).unwrap(),
+            direction: None,
+        }
+    );
+    assert_eq!(
+        read[1].as_ref().unwrap_err().kind(),
+        io::ErrorKind::InvalidData
+    );
+    assert_eq!(read[2].as_ref().unwrap().message.id(), 0x1FFFFFFF);
+}
@@ -0,0 +1,151 @@
+//! CANET ports in UDP mode, with a UDP socket standing in for the device
+
+#[allow(dead_code)]
+mod common;
+
+use std::{fs, net::SocketAddr, time::Duration};
+
+use common::{Bridge, DEADLINE, GvretClient, GvretFrame};
+use tokio::{
+    net::UdpSocket,
+    time::{interval, timeout},
+};
+use usr_canet_gvret::usr_canet::{Message, convert_to_canet};
+
+/// Sent until the bridge reports it, to know the bridge's socket is bound
+const PROBE_ID: u32 = 0x7ff;
+
+/// A UDP CANET on localhost and a bridge with it as bus 1, receiving on a local port other than
+/// the device's port
+struct UdpCanet {
+    socket: UdpSocket,
+    /// Where the bridge receives records
+    local: SocketAddr,
+    bridge: Bridge,
+}
+
+impl UdpCanet {
+    async fn start() -> Self {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let local = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let config = std::env::temp_dir().join(format!(
+            "usr-canet-gvret-{}-udp-{}.toml",
+            std::process::id(),
+            local.port()
+        ));
+        fs::write(
+            &config,
+            format!(
+                "[[canet]]\nhost = \"127.0.0.1\"\nports = [1]\n\n\
+                 [[canet]]\nhost = \"127.0.0.1\"\ntransport = \"udp\"\n\
+                 ports = [{}]\nlocal_ports = [{}]\n",
+                socket.local_addr().unwrap().port(),
+                local.port()
+            ),
+        )
+        .unwrap();
+        let bridge = Bridge::start_with(1, &["--config", config.to_str().unwrap()]).await;
+        fs::remove_file(config).unwrap();
+        Self {
+            socket,
+            local,
+            bridge,
+        }
+    }
+
+    /// Connect a client once the bridge receives records
+    async fn client(&self) -> GvretClient {
+        let mut client = self.bridge.client().await;
+        let probe = records(&[Message::new_data(1, PROBE_ID, false, &[]).unwrap()]);
+        let mut ticks = interval(Duration::from_millis(50));
+        let synced = async {
+            loop {
+                tokio::select! {
+                    _ = ticks.tick() => {
+                        self.socket.send_to(&probe, self.local).await.unwrap();
+                    }
+                    frame = client.frame() => if frame.id == PROBE_ID {
+                        return;
+                    },
+                }
+            }
+        };
+        timeout(DEADLINE, synced)
+            .await
+            .expect("bridge did not bind its UDP port");
+        client
+    }
+}
+
+fn records(messages: &[Message]) -> Vec<u8> {
+    messages.iter().flat_map(convert_to_canet).collect()
+}
+
+/// Next frame other than a late probe
+async fn frame(client: &mut GvretClient) -> GvretFrame {
+    loop {
+        let frame = client.frame().await;
+        if frame.id != PROBE_ID {
+            return frame;
+        }
+    }
+}
+
+#[tokio::test]
+async fn receives_several_records_per_datagram() {
+    let canet = UdpCanet::start().await;
+    let mut client = canet.client().await;
+
+    let datagram = records(&[
+        Message::new_data(1, 0x100, false, &[1]).unwrap(),
+        Message::new_data(1, 0x18ff50e5, true, &[2, 2]).unwrap(),
+        Message::new_remote(1, 0x102, false, 3).unwrap(),
+    ]);
+    canet.socket.send_to(&datagram, canet.local).await.unwrap();
+
+    for (id, data) in [
+        (0x100, &[1][..]),
+        (0x18ff50e5 | 1 << 31, &[2, 2][..]),
+        (0x102 | 1 << 30, &[0, 0, 0][..]),
+    ] {
+        let frame = frame(&mut client).await;
+        assert_eq!((frame.bus, frame.id, &frame.data[..]), (1, id, data));
+    }
+}
+
+#[tokio::test]
+async fn skips_truncated_datagrams() {
+    let canet = UdpCanet::start().await;
+    let mut client = canet.client().await;
+
+    // A whole record and the start of another
+    let mut truncated = records(&[Message::new_data(1, 0x200, false, &[1]).unwrap()]);
+    truncated.extend(&records(&[Message::new_data(1, 0x201, false, &[2]).unwrap()])[..5]);
+    canet.socket.send_to(&truncated, canet.local).await.unwrap();
+    let next = records(&[Message::new_data(1, 0x202, false, &[3]).unwrap()]);
+    canet.socket.send_to(&next, canet.local).await.unwrap();
+
+    assert_eq!(frame(&mut client).await.id, 0x200);
+    assert_eq!(frame(&mut client).await.id, 0x202);
+}
+
+#[tokio::test]
+async fn transmits_from_the_local_port() {
+    let canet = UdpCanet::start().await;
+    let mut client = canet.client().await;
+
+    let message = Message::new_data(1, 0x300, false, &[1, 2, 3]).unwrap();
+    client.transmit(&message).await;
+
+    let mut buf = [0; 1500];
+    let (len, from) = timeout(DEADLINE, canet.socket.recv_from(&mut buf))
+        .await
+        .expect("no datagram from the bridge")
+        .unwrap();
+    assert_eq!(&buf[..len], records(&[message]));
+    assert_eq!(from.port(), canet.local.port());
+}
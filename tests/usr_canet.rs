@@ -0,0 +1,99 @@
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+use usr_canet_gvret::usr_canet::{
+    CANET_RECORD_LEN, CanetCodec, Message, UsrError, decode_canet_frame,
+};
+
+fn messages(bus: u8) -> Vec<Message> {
+    vec![
+        Message::new_data(bus, 0x123, false, &[1, 2, 3]).unwrap(),
+        Message::new_data(bus, 0x18ff50e5, true, &[0xff; 8]).unwrap(),
+        Message::new_remote(bus, 0x7ff, false, 4).unwrap(),
+        Message::new_data(bus, 0, false, &[]).unwrap(),
+    ]
+}
+
+fn encode(messages: &[Message]) -> BytesMut {
+    let mut codec = CanetCodec::new(0);
+    let mut buf = BytesMut::new();
+    for message in messages {
+        codec.encode(message, &mut buf).unwrap();
+    }
+    buf
+}
+
+#[test]
+fn encodes_records() {
+    let buf = encode(&messages(0)[..3]);
+    assert_eq!(
+        &buf[..],
+        &[
+            0x03, 0x00, 0x00, 0x01, 0x23, 1, 2, 3, 0, 0, 0, 0, 0, //
+            0x88, 0x18, 0xff, 0x50, 0xe5, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, //
+            0x44, 0x00, 0x00, 0x07, 0xff, 0, 0, 0, 0, 0, 0, 0, 0,
+        ]
+    );
+}
+
+#[test]
+fn round_trips_records_on_the_codec_bus() {
+    let mut buf = encode(&messages(0));
+    assert_eq!(buf.len(), 4 * CANET_RECORD_LEN);
+
+    let mut codec = CanetCodec::new(1);
+    let mut decoded = vec![];
+    while let Some(message) = codec.decode(&mut buf).unwrap() {
+        decoded.push(message);
+    }
+    assert_eq!(decoded, messages(1));
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn keeps_partial_records_until_complete() {
+    let record = encode(&messages(0)[..1]);
+    let mut codec = CanetCodec::new(0);
+    let mut buf = BytesMut::new();
+    for (i, &byte) in record.iter().enumerate() {
+        assert!(codec.decode(&mut buf).unwrap().is_none(), "byte {i}");
+        buf.extend_from_slice(&[byte]);
+    }
+    assert_eq!(
+        codec.decode(&mut buf).unwrap(),
+        Some(messages(0)[0].clone())
+    );
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn skips_malformed_records() {
+    // A standard id above 0x7ff between two good records
+    let mut buf = encode(&messages(0)[..1]);
+    buf.extend_from_slice(&[0x01, 0x00, 0x00, 0x08, 0x00, 0xaa, 0, 0, 0, 0, 0, 0, 0]);
+    buf.extend_from_slice(&encode(&messages(0)[1..2]));
+
+    let mut codec = CanetCodec::new(0);
+    assert_eq!(
+        codec.decode(&mut buf).unwrap(),
+        Some(messages(0)[0].clone())
+    );
+    assert!(matches!(codec.decode(&mut buf), Err(UsrError::IdTooLong)));
+    assert_eq!(
+        codec.decode(&mut buf).unwrap(),
+        Some(messages(0)[1].clone())
+    );
+    assert!(buf.is_empty());
+}
+
+#[tokio::test]
+async fn reads_records_from_a_stream() {
+    let buf = encode(&messages(0));
+    let mut stream = &buf[..];
+    for expected in messages(2) {
+        assert_eq!(decode_canet_frame(&mut stream, 2).await.unwrap(), expected);
+    }
+    assert!(matches!(
+        decode_canet_frame(&mut stream, 2).await,
+        Err(UsrError::Io(_))
+    ));
+}